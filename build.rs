@@ -0,0 +1,21 @@
+use std::process::Command;
+
+fn main() {
+    // Packagers building from a tarball can pass the commit in explicitly.
+    let commit = std::env::var("PONDER_GIT_COMMIT")
+        .ok()
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| String::from("unknown"));
+
+    println!("cargo:rustc-env=PONDER_GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-env-changed=PONDER_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
@@ -29,23 +29,36 @@
 //! }
 //! ```
 
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::num::{NonZeroU16, NonZeroU32};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::anyhow;
+use ipnet::IpNet;
 use nonzero_ext::nonzero;
 use rmqtt_codec::types::QoS;
-use socket2::{Domain, SockAddr, Socket, Type};
+use socket2::{Domain, SockAddr, SockRef, Socket, TcpKeepalive, Type};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-
-use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
-use tokio_openssl::SslStream as TokioSslStream;
-
-use crate::stream::Dispatcher;
-use crate::{Error, Result};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+use crate::ratelimit::ConnRateLimiter;
+use crate::stats::{ActiveConnection, ListenerCounters, ListenerStats};
+use crate::stream::{BoxedIo, Dispatcher};
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+use crate::tls::CustomSslAcceptor;
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+use crate::tls::TlsStream;
+use crate::tls::{Identity, Pem, PemBytes, Pkcs12Bundle, TlsAcceptor};
+#[cfg(feature = "ws")]
+use crate::ws::WsStream;
+use crate::{ClientRegistry, ListenerError, Result};
 
 /// Configuration builder for MQTT server instances
 #[derive(Clone, Debug)]
@@ -54,6 +67,8 @@ pub struct Builder {
     pub name: String,
     /// Network address to listen on
     pub laddr: SocketAddr,
+    /// Addresses for [`Builder::bind_all`], falls back to `laddr` when empty
+    pub laddrs: Vec<SocketAddr>,
     /// Maximum number of pending connections in the accept queue
     pub backlog: i32,
     /// Enable TCP_NODELAY option for lower latency
@@ -62,12 +77,40 @@ pub struct Builder {
     pub reuseaddr: Option<bool>,
     /// Set SO_REUSEPORT socket option
     pub reuseport: Option<bool>,
+    /// Set IP_TOS (DSCP/ECN bits) on the listening and accepted sockets
+    pub tos: Option<u32>,
+    /// Set IPV6_V6ONLY on IPv6 listening sockets (None = platform default)
+    pub ipv6_only: Option<bool>,
+    /// SO_RCVBUF for the listening socket, inherited by accepted ones (None = OS default)
+    pub recv_buffer_size: Option<usize>,
+    /// SO_SNDBUF for the listening socket, inherited by accepted ones (None = OS default)
+    pub send_buffer_size: Option<usize>,
+    /// Idle time before TCP keepalive probes are sent on accepted sockets (None = OS default)
+    pub tcp_keepalive: Option<Duration>,
+    /// SO_LINGER timeout for the listening socket, inherited by accepted ones (None = not set)
+    pub linger: Option<Duration>,
+    /// Expect a PROXY protocol v1 or v2 header in front of every connection
+    pub proxy_protocol: bool,
     /// Maximum concurrent active connections
     pub max_connections: usize,
+    /// Close connections over `max_connections` right away instead of waiting for a free slot
+    pub reject_on_full: bool,
+    /// Client networks admitted by `accept()` (empty = all)
+    pub allow_cidrs: Vec<IpNet>,
+    /// Client networks closed by `accept()`, taking precedence over `allow_cidrs`
+    pub deny_cidrs: Vec<IpNet>,
+    /// New connections admitted per client IP within any one-second window (None = unlimited)
+    pub max_conns_per_ip_per_sec: Option<NonZeroU32>,
+    /// Callback deciding by peer address whether `accept()` keeps a connection
+    pub on_accept: Option<AcceptFilter>,
+    /// Connected client identifiers, a CONNECT reusing one takes over the older connection
+    pub client_registry: ClientRegistry,
     /// Maximum simultaneous handshakes during connection setup
     pub max_handshaking_limit: usize,
     /// Maximum allowed MQTT packet size in bytes (0 = unlimited)
     pub max_packet_size: u32,
+    /// Longest delay between accept retries after the process ran out of file descriptors
+    pub accept_backoff: Duration,
 
     /// Allow unauthenticated client connections
     pub allow_anonymous: bool,
@@ -85,6 +128,8 @@ pub struct Builder {
     pub handshake_timeout: Duration,
     /// Network I/O timeout for sending operations
     pub send_timeout: Duration,
+    /// Close connections that send nothing for this long, regardless of keepalive (None = never)
+    pub idle_timeout: Option<Duration>,
     /// Maximum messages queued per client
     pub max_mqueue_len: usize,
     /// Rate limiting for message delivery (messages per duration)
@@ -114,13 +159,94 @@ pub struct Builder {
     pub limit_subscription: bool,
     /// Enable future-dated message publishing
     pub delayed_publish: bool,
+    /// Log every inbound and outbound packet at trace level, publishes without their payload
+    pub packet_trace: bool,
 
     /// Enable mutual TLS authentication
     pub tls_cross_certificate: bool,
+    /// Path to the CA certificates client certificates are verified against (None = server chain)
+    pub tls_client_ca: Option<String>,
+    /// Maximum number of intermediate CAs allowed in a client certificate chain
+    pub tls_verify_depth: u32,
     /// Path to TLS certificate chain
     pub tls_cert: Option<String>,
     /// Path to TLS private key
     pub tls_key: Option<String>,
+    /// PEM certificate chain held in memory, used instead of `tls_cert`
+    pub tls_cert_pem: Option<PemBytes>,
+    /// PEM private key held in memory, used instead of `tls_key`
+    pub tls_key_pem: Option<PemBytes>,
+    /// PKCS#12 bundle with the certificate chain and key, takes precedence over the PEM settings
+    pub tls_pkcs12: Option<Pkcs12Bundle>,
+    /// Per-SNI certificates as `(hostname, cert path, key path)`
+    pub tls_sni_certs: Vec<(String, String, String)>,
+    /// Lowest TLS protocol version accepted
+    pub tls_min_version: TlsVersion,
+    /// Highest TLS protocol version accepted
+    pub tls_max_version: TlsVersion,
+    /// OpenSSL cipher list for TLS 1.2 and below (None = backend defaults)
+    pub tls_ciphers: Option<String>,
+    /// OpenSSL ciphersuites for TLS 1.3 (None = backend defaults)
+    pub tls_ciphersuites: Option<String>,
+    /// ALPN protocols advertised during the TLS handshake, in order of preference
+    pub tls_alpn: Vec<String>,
+    /// DER encoded OCSP response stapled for the default certificate
+    pub tls_ocsp_response: Option<PathBuf>,
+    /// Pre-built OpenSSL acceptor used as is, instead of one built from the `tls_*` settings
+    #[cfg(all(feature = "openssl", not(feature = "rustls")))]
+    pub tls_acceptor: Option<CustomSslAcceptor>,
+
+    /// WebSocket subprotocols accepted during the upgrade, the client's order decides
+    pub ws_subprotocols: Vec<String>,
+    /// Request path the WebSocket upgrade is served on
+    pub ws_path: String,
+    /// HTTP path answered with `200 OK` as a liveness check (None = disabled)
+    pub health_path: Option<String>,
+}
+
+/// Connection filter installed with [`Builder::on_accept`]
+#[derive(Clone)]
+pub struct AcceptFilter(Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>);
+
+impl AcceptFilter {
+    fn allows(&self, addr: SocketAddr) -> bool {
+        (self.0)(addr)
+    }
+}
+
+impl fmt::Debug for AcceptFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AcceptFilter(..)")
+    }
+}
+
+/// TLS protocol versions selectable on a listener
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    #[serde(rename = "1.2")]
+    Tls12,
+    /// TLS 1.3
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// Deployment profiles for [`Builder::preset`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    /// Gateway on constrained hardware serving a few hundred local devices
+    ///
+    /// `max_connections` 1000, `max_handshaking_limit` 50, `backlog` 128, `nodelay` on,
+    /// `max_inflight` 8, `max_mqueue_len` 100, `max_packet_size` 256 KiB and 16 KiB socket
+    /// buffers, so a full gateway stays within a small memory budget.
+    EdgeGateway,
+    /// Broker node behind a load balancer handling a large fleet
+    ///
+    /// `max_connections` 1000000, `max_handshaking_limit` 5000, `backlog` 4096, `reuseaddr` and
+    /// `reuseport` on, `max_inflight` 64, `max_mqueue_len` 10000, 256 KiB socket buffers and TCP
+    /// keepalive after 60 seconds idle, for high throughput and quick detection of dead peers.
+    CloudBroker,
 }
 
 impl Default for Builder {
@@ -145,13 +271,28 @@ impl Builder {
         Builder {
             name: Default::default(),
             laddr: SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 1883)),
+            laddrs: Vec::new(),
             max_connections: 1_000_000,
+            reject_on_full: false,
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            max_conns_per_ip_per_sec: None,
+            on_accept: None,
+            client_registry: ClientRegistry::default(),
             max_handshaking_limit: 1_000,
             max_packet_size: 1024 * 1024,
+            accept_backoff: Duration::from_secs(1),
             backlog: 512,
             nodelay: false,
             reuseaddr: None,
             reuseport: None,
+            tos: None,
+            ipv6_only: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            tcp_keepalive: None,
+            linger: Some(Duration::from_secs(10)),
+            proxy_protocol: false,
 
             allow_anonymous: true,
             min_keepalive: 0,
@@ -161,6 +302,7 @@ impl Builder {
             max_inflight: nonzero!(16u16),
             handshake_timeout: Duration::from_secs(30),
             send_timeout: Duration::from_secs(10),
+            idle_timeout: None,
             max_mqueue_len: 1000,
 
             mqueue_rate_limit: (nonzero!(u32::MAX), Duration::from_secs(1)),
@@ -177,13 +319,73 @@ impl Builder {
 
             limit_subscription: false,
             delayed_publish: false,
+            packet_trace: false,
 
             tls_cross_certificate: false,
+            tls_client_ca: None,
+            tls_verify_depth: 4,
             tls_cert: None,
             tls_key: None,
+            tls_cert_pem: None,
+            tls_key_pem: None,
+            tls_pkcs12: None,
+            tls_sni_certs: Vec::new(),
+            tls_min_version: TlsVersion::Tls12,
+            tls_max_version: TlsVersion::Tls13,
+            tls_ciphers: None,
+            tls_ciphersuites: None,
+            tls_alpn: Vec::new(),
+            tls_ocsp_response: None,
+            #[cfg(all(feature = "openssl", not(feature = "rustls")))]
+            tls_acceptor: None,
+
+            ws_subprotocols: vec!["mqtt".into()],
+            ws_path: "/mqtt".into(),
+            health_path: None,
         }
     }
 
+    /// Applies the tuning of a deployment profile, see [`Preset`]
+    ///
+    /// Only overwrites the fields that preset lists, in one go; setters called afterwards still
+    /// override individual values.
+    ///
+    /// ```
+    /// use rmqtt_net::{Builder, Preset};
+    ///
+    /// let builder = Builder::new().preset(Preset::EdgeGateway).max_connections(500);
+    /// assert!(builder.nodelay);
+    /// assert_eq!(builder.max_connections, 500);
+    /// ```
+    pub fn preset(mut self, preset: Preset) -> Self {
+        match preset {
+            Preset::EdgeGateway => {
+                self.max_connections = 1_000;
+                self.max_handshaking_limit = 50;
+                self.backlog = 128;
+                self.nodelay = true;
+                self.max_inflight = nonzero!(8u16);
+                self.max_mqueue_len = 100;
+                self.max_packet_size = 256 * 1024;
+                self.recv_buffer_size = Some(16 * 1024);
+                self.send_buffer_size = Some(16 * 1024);
+            }
+            Preset::CloudBroker => {
+                self.max_connections = 1_000_000;
+                self.max_handshaking_limit = 5_000;
+                self.backlog = 4_096;
+                self.reuseaddr = Some(true);
+                self.reuseport = Some(true);
+                self.max_inflight = nonzero!(64u16);
+                self.max_mqueue_len = 10_000;
+                self.recv_buffer_size = Some(256 * 1024);
+                self.send_buffer_size = Some(256 * 1024);
+                self.tcp_keepalive = Some(Duration::from_secs(60));
+            }
+        }
+        self
+    }
+
     /// Sets the server name identifier
     pub fn name<N: Into<String>>(mut self, name: N) -> Self {
         self.name = name.into();
@@ -196,6 +398,13 @@ impl Builder {
         self
     }
 
+    /// Configures several listen addresses, e.g. `0.0.0.0:1883` and `[::]:1883`, for
+    /// [`Builder::bind_all`]
+    pub fn laddrs(mut self, laddrs: Vec<SocketAddr>) -> Self {
+        self.laddrs = laddrs;
+        self
+    }
+
     /// Sets the TCP backlog size
     pub fn backlog(mut self, backlog: i32) -> Self {
         self.backlog = backlog;
@@ -220,12 +429,140 @@ impl Builder {
         self
     }
 
+    /// Configures IP_TOS socket option, e.g. `Some(0xb8)` for DSCP EF
+    pub fn tos(mut self, tos: Option<u32>) -> Self {
+        self.tos = tos;
+        self
+    }
+
+    /// Controls whether an IPv6 listener also accepts IPv4 clients as `::ffff:a.b.c.d`
+    ///
+    /// Left as `None` the platform decides: Linux and macOS accept IPv4-mapped connections on
+    /// `[::]` unless `net.ipv6.bindv6only` says otherwise, while Windows and OpenBSD only take
+    /// IPv6. `Some(true)` restricts the socket to IPv6, `Some(false)` asks for dual-stack. Has no
+    /// effect, apart from a warning, when `laddr` is an IPv4 address.
+    pub fn ipv6_only(mut self, ipv6_only: Option<bool>) -> Self {
+        self.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// Sets the socket receive buffer size
+    ///
+    /// Applied to the listening socket before `listen()`, so accepted sockets inherit it and TCP
+    /// window scaling can take it into account. The kernel may round or cap it, `bind()` logs
+    /// the size actually granted.
+    pub fn recv_buffer_size(mut self, recv_buffer_size: Option<usize>) -> Self {
+        self.recv_buffer_size = recv_buffer_size;
+        self
+    }
+
+    /// Sets the socket send buffer size, see [`Builder::recv_buffer_size`]
+    pub fn send_buffer_size(mut self, send_buffer_size: Option<usize>) -> Self {
+        self.send_buffer_size = send_buffer_size;
+        self
+    }
+
+    /// Enables SO_KEEPALIVE on accepted sockets, probing after `idle` without traffic
+    ///
+    /// Where the platform allows it the probe interval is set to `idle` as well. This finds peers
+    /// that vanished behind a NAT even when the MQTT keepalive is disabled.
+    pub fn tcp_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.tcp_keepalive = idle;
+        self
+    }
+
+    /// Sets SO_LINGER, how long closing a socket may block to flush unsent data
+    ///
+    /// Defaults to 10 seconds. `None` leaves SO_LINGER alone, so close returns at once and the
+    /// kernel finishes sending in the background, which avoids lingering sockets holding up
+    /// address reuse.
+    pub fn linger(mut self, linger: Option<Duration>) -> Self {
+        self.linger = linger;
+        self
+    }
+
+    /// Requires a PROXY protocol header, v1 or v2, before any TLS, WebSocket or MQTT bytes
+    ///
+    /// The source address from the header replaces the peer address of the socket, so only
+    /// enable this behind a load balancer that always sends it.
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> Self {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
     /// Sets maximum concurrent connections
+    ///
+    /// Once reached, `accept()` waits until a connection is dropped, see also
+    /// [`Builder::reject_on_full`].
     pub fn max_connections(mut self, max_connections: usize) -> Self {
         self.max_connections = max_connections;
         self
     }
 
+    /// Accepts and immediately closes connections while `max_connections` is reached
+    ///
+    /// Without this, pending clients queue up in the kernel backlog until a slot frees up.
+    pub fn reject_on_full(mut self, reject_on_full: bool) -> Self {
+        self.reject_on_full = reject_on_full;
+        self
+    }
+
+    /// Only accepts clients from these networks, an empty list allows all of them
+    ///
+    /// Other clients are closed by `accept()` before any handshake, without returning an error.
+    ///
+    /// ```
+    /// let builder = rmqtt_net::Builder::new()
+    ///     .allow_cidrs(vec!["192.168.0.0/16".parse().unwrap(), "fd00::/8".parse().unwrap()])
+    ///     .deny_cidrs(vec!["192.168.66.0/24".parse().unwrap()]);
+    /// ```
+    pub fn allow_cidrs(mut self, allow_cidrs: Vec<IpNet>) -> Self {
+        self.allow_cidrs = allow_cidrs;
+        self
+    }
+
+    /// Closes clients from these networks in `accept()`, even if `allow_cidrs` matches them
+    pub fn deny_cidrs(mut self, deny_cidrs: Vec<IpNet>) -> Self {
+        self.deny_cidrs = deny_cidrs;
+        self
+    }
+
+    /// Limits how many connections one client IP may open per second
+    ///
+    /// Connections over the limit are closed by `accept()` without returning an error. This
+    /// guards against floods from a single source, `max_connections` caps the total.
+    pub fn max_conns_per_ip_per_sec(mut self, max_conns_per_ip_per_sec: Option<NonZeroU32>) -> Self {
+        self.max_conns_per_ip_per_sec = max_conns_per_ip_per_sec;
+        self
+    }
+
+    /// Installs a callback that vets every connection by its peer address before any handshake
+    ///
+    /// Returning `false` closes the socket and `accept()` moves on to the next connection, which
+    /// allows dynamic blocklists the CIDR filters cannot express. The callback runs on the accept
+    /// path after the IP filter and rate limit, so it must be fast and must not block: look the
+    /// address up in memory rather than asking a remote service. With `proxy_protocol` it sees
+    /// the proxy's address, not the client's.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use rmqtt_net::Builder;
+    ///
+    /// let builder = Builder::new().on_accept(Arc::new(|addr| addr.port() != 0));
+    /// ```
+    pub fn on_accept(mut self, on_accept: Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>) -> Self {
+        self.on_accept = Some(AcceptFilter(on_accept));
+        self
+    }
+
+    /// Shares a client identifier registry, so takeover also works across listeners
+    ///
+    /// Every builder starts with its own registry, which clones of the builder share.
+    pub fn client_registry(mut self, client_registry: ClientRegistry) -> Self {
+        self.client_registry = client_registry;
+        self
+    }
+
     /// Sets maximum concurrent handshakes
     pub fn max_handshaking_limit(mut self, max_handshaking_limit: usize) -> Self {
         self.max_handshaking_limit = max_handshaking_limit;
@@ -238,6 +575,14 @@ impl Builder {
         self
     }
 
+    /// Sets the cap on the accept retry delay used on file descriptor exhaustion
+    ///
+    /// The delay starts at 1ms and doubles on every consecutive failure up to this value.
+    pub fn accept_backoff(mut self, accept_backoff: Duration) -> Self {
+        self.accept_backoff = accept_backoff;
+        self
+    }
+
     /// Enables anonymous client access
     pub fn allow_anonymous(mut self, allow_anonymous: bool) -> Self {
         self.allow_anonymous = allow_anonymous;
@@ -286,6 +631,15 @@ impl Builder {
         self
     }
 
+    /// Sets how long an established connection may go without sending a packet
+    ///
+    /// Unlike keepalive this also covers clients that connected with a keepalive of zero. The
+    /// stream then yields [`MqttError::IdleTimeout`](crate::MqttError::IdleTimeout).
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
     /// Configures maximum message queue length
     pub fn max_mqueue_len(mut self, max_mqueue_len: usize) -> Self {
         self.max_mqueue_len = max_mqueue_len;
@@ -370,12 +724,40 @@ impl Builder {
         self
     }
 
+    /// Logs each packet type with the client ID and remote address at trace level
+    ///
+    /// Publishes show their topic, QoS and payload length, the payload itself is never logged.
+    pub fn packet_trace(mut self, packet_trace: bool) -> Self {
+        self.packet_trace = packet_trace;
+        self
+    }
+
     /// Enables mutual TLS authentication
     pub fn tls_cross_certificate(mut self, cross_certificate: bool) -> Self {
         self.tls_cross_certificate = cross_certificate;
         self
     }
 
+    /// Sets the PEM file of CA certificates that client certificates must chain up to
+    ///
+    /// Only used with `tls_cross_certificate`. Left unset, the server certificate chain doubles
+    /// as the client CA, which only fits setups where clients are signed by the server cert itself.
+    pub fn tls_client_ca<N: Into<String>>(mut self, tls_client_ca: Option<N>) -> Self {
+        self.tls_client_ca = tls_client_ca.map(|c| c.into());
+        self
+    }
+
+    /// Sets how many intermediate CAs may sit between a client certificate and the trusted CA
+    ///
+    /// Only used with `tls_cross_certificate`, defaults to 4. Set it too low and legitimately
+    /// chained client certificates fail verification, e.g. devices signed by an issuing CA under
+    /// a regional CA need at least 2. Only the OpenSSL backend applies it, rustls keeps the
+    /// limit built into webpki.
+    pub fn tls_verify_depth(mut self, depth: u32) -> Self {
+        self.tls_verify_depth = depth;
+        self
+    }
+
     /// Sets path to TLS certificate chain
     pub fn tls_cert<N: Into<String>>(mut self, tls_cert: Option<N>) -> Self {
         self.tls_cert = tls_cert.map(|c| c.into());
@@ -388,15 +770,307 @@ impl Builder {
         self
     }
 
+    /// Sets the TLS certificate chain from PEM bytes, for secrets that never touch the disk
+    ///
+    /// Replaces `tls_cert`, setting both is rejected by [`Builder::validate`].
+    pub fn tls_cert_pem(mut self, pem: Vec<u8>) -> Self {
+        self.tls_cert_pem = Some(pem.into());
+        self
+    }
+
+    /// Sets the TLS private key from PEM bytes, replacing `tls_key`
+    pub fn tls_key_pem(mut self, pem: Vec<u8>) -> Self {
+        self.tls_key_pem = Some(pem.into());
+        self
+    }
+
+    /// Loads the certificate chain and private key from a `.p12`/`.pfx` bundle
+    ///
+    /// Intermediate CA certificates in the bundle are sent along with the leaf. When set,
+    /// `tls_cert`/`tls_key` and their PEM variants are ignored. Only the OpenSSL backend reads
+    /// PKCS#12.
+    pub fn tls_pkcs12<P: Into<PathBuf>, S: Into<String>>(mut self, path: P, password: S) -> Self {
+        self.tls_pkcs12 = Some(Pkcs12Bundle { path: path.into(), password: password.into() });
+        self
+    }
+
+    /// Whether a default certificate and key are configured, from files, memory or a bundle
+    fn has_tls_identity(&self) -> bool {
+        #[cfg(all(feature = "openssl", not(feature = "rustls")))]
+        if self.tls_acceptor.is_some() {
+            return true;
+        }
+        self.tls_pkcs12.is_some()
+            || (self.tls_cert.is_some() || self.tls_cert_pem.is_some())
+                && (self.tls_key.is_some() || self.tls_key_pem.is_some())
+    }
+
+    /// Sets certificates selected by the client's SNI hostname
+    ///
+    /// Each entry is `(hostname, cert path, key path)`. Clients that send no SNI, or a hostname
+    /// without an entry, are served the default `tls_cert`/`tls_key`.
+    pub fn tls_sni_certs(mut self, tls_sni_certs: Vec<(String, String, String)>) -> Self {
+        self.tls_sni_certs = tls_sni_certs;
+        self
+    }
+
+    /// Registers the certificate served to clients asking for `host` through SNI
+    ///
+    /// Can be called once per hostname; registering a hostname again replaces its certificate.
+    /// Hostnames are matched case-insensitively.
+    ///
+    /// ```
+    /// let builder = rmqtt_net::Builder::new()
+    ///     .tls_cert(Some("default.pem"))
+    ///     .tls_key(Some("default.key"))
+    ///     .tls_sni_cert("a.example.com", "a.pem", "a.key")
+    ///     .tls_sni_cert("b.example.com", "b.pem", "b.key");
+    /// assert_eq!(builder.tls_sni_certs.len(), 2);
+    /// ```
+    pub fn tls_sni_cert<H, C, K>(mut self, host: H, cert: C, key: K) -> Self
+    where
+        H: Into<String>,
+        C: Into<String>,
+        K: Into<String>,
+    {
+        let host = host.into().to_ascii_lowercase();
+        self.tls_sni_certs.retain(|(h, _, _)| !h.eq_ignore_ascii_case(&host));
+        self.tls_sni_certs.push((host, cert.into(), key.into()));
+        self
+    }
+
+    /// Sets the lowest accepted TLS protocol version
+    pub fn tls_min_version(mut self, tls_min_version: TlsVersion) -> Self {
+        self.tls_min_version = tls_min_version;
+        self
+    }
+
+    /// Sets the highest accepted TLS protocol version
+    pub fn tls_max_version(mut self, tls_max_version: TlsVersion) -> Self {
+        self.tls_max_version = tls_max_version;
+        self
+    }
+
+    /// Sets the TLS 1.2 cipher list, e.g. `Some("ECDHE-RSA-AES256-SHA")`
+    pub fn tls_ciphers<N: Into<String>>(mut self, tls_ciphers: Option<N>) -> Self {
+        self.tls_ciphers = tls_ciphers.map(|c| c.into());
+        self
+    }
+
+    /// Sets the TLS 1.3 ciphersuites, e.g. `Some("TLS_AES_256_GCM_SHA384")`
+    pub fn tls_ciphersuites<N: Into<String>>(mut self, tls_ciphersuites: Option<N>) -> Self {
+        self.tls_ciphersuites = tls_ciphersuites.map(|c| c.into());
+        self
+    }
+
+    /// Sets the ALPN protocols to advertise, e.g. `vec!["mqtt".into()]`
+    ///
+    /// When non-empty, clients offering ALPN without any of these protocols fail the handshake.
+    pub fn tls_alpn(mut self, tls_alpn: Vec<String>) -> Self {
+        self.tls_alpn = tls_alpn;
+        self
+    }
+
+    /// Sets a pre-fetched, DER encoded OCSP response to staple during the handshake
+    ///
+    /// Only clients requesting certificate status get it, and only for the default `tls_cert`,
+    /// not the per-SNI ones. A missing file means no stapling, a malformed one fails `tls()`.
+    /// After the file was refreshed on disk, [`Listener::reload_ocsp`] swaps it in.
+    pub fn tls_ocsp_response<P: Into<PathBuf>>(mut self, tls_ocsp_response: Option<P>) -> Self {
+        self.tls_ocsp_response = tls_ocsp_response.map(|p| p.into());
+        self
+    }
+
+    /// Hands `tls()` and `wss()` a ready-made OpenSSL acceptor, for settings this builder does
+    /// not expose such as engines or custom verify callbacks
+    ///
+    /// The acceptor is used verbatim: `tls_cert`/`tls_key` and their PEM and PKCS#12 variants,
+    /// `tls_sni_certs`, the TLS version range, ciphers, ALPN, OCSP stapling and the client
+    /// verification settings `tls_cross_certificate`, `tls_client_ca` and `tls_verify_depth`
+    /// are all ignored.
+    #[cfg(all(feature = "openssl", not(feature = "rustls")))]
+    pub fn tls_acceptor(mut self, acceptor: Arc<openssl::ssl::SslAcceptor>) -> Self {
+        self.tls_acceptor = Some(CustomSslAcceptor(acceptor));
+        self
+    }
+
+    /// Sets the `Sec-WebSocket-Protocol` values accepted on WS/WSS listeners
+    ///
+    /// The first value the client offers that is in this list is echoed in the `101` response,
+    /// clients offering none of them get `400 Bad Request`. Defaults to `["mqtt"]`, add
+    /// `"mqttv3.1"` for clients that predate MQTT 3.1.1.
+    pub fn ws_subprotocols(mut self, ws_subprotocols: Vec<String>) -> Self {
+        self.ws_subprotocols = ws_subprotocols;
+        self
+    }
+
+    /// Sets the HTTP path that WebSocket upgrades must target, `/mqtt` by default
+    ///
+    /// The path has to match exactly, query strings are ignored. Requests for any other path are
+    /// answered with `404 Not Found`, so a reverse proxy can route other endpoints elsewhere.
+    pub fn ws_path<P: Into<String>>(mut self, ws_path: P) -> Self {
+        self.ws_path = ws_path.into();
+        self
+    }
+
+    /// Answers `GET` requests for this path with `200 OK` on the MQTT port itself
+    ///
+    /// Lets an orchestrator probe liveness without a separate port. A connection whose first
+    /// bytes are `GET ` cannot be MQTT, binary CONNECTs pass through unaffected. On WebSocket
+    /// listeners the check is answered instead of the upgrade. The answered connection ends
+    /// with [`MqttError::HealthCheck`](crate::MqttError::HealthCheck).
+    pub fn health_path<P: Into<String>>(mut self, health_path: Option<P>) -> Self {
+        self.health_path = health_path.map(|p| p.into());
+        self
+    }
+
+    /// Checks the configuration for settings that contradict each other
+    ///
+    /// Called by [`Builder::bind`], the error names the offending field.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_connections == 0 {
+            return Err(anyhow!("Invalid max_connections: must be greater than zero"));
+        }
+        if self.min_keepalive > self.max_keepalive {
+            return Err(anyhow!(
+                "Invalid min_keepalive: {} is above max_keepalive {}",
+                self.min_keepalive,
+                self.max_keepalive
+            ));
+        }
+        if self.keepalive_backoff.is_nan() || self.keepalive_backoff <= 0.0 {
+            return Err(anyhow!(
+                "Invalid keepalive_backoff: {} must be greater than zero",
+                self.keepalive_backoff
+            ));
+        }
+        if self.idle_timeout.is_some_and(|t| t.is_zero()) {
+            return Err(anyhow!("Invalid idle_timeout: must be greater than zero"));
+        }
+        if self.ws_subprotocols.is_empty() {
+            return Err(anyhow!("Invalid ws_subprotocols: at least one subprotocol is required"));
+        }
+        if let Some(p) = self.ws_subprotocols.iter().find(|p| !is_http_token(p)) {
+            return Err(anyhow!("Invalid ws_subprotocols: {:?} is not a valid HTTP token", p));
+        }
+        if !self.ws_path.starts_with('/') || self.ws_path.contains('?') {
+            return Err(anyhow!(
+                "Invalid ws_path: {:?} must start with '/' and not contain a query",
+                self.ws_path
+            ));
+        }
+        if let Some(health_path) = &self.health_path {
+            if !health_path.starts_with('/') || health_path.contains('?') {
+                return Err(anyhow!(
+                    "Invalid health_path: {:?} must start with '/' and not contain a query",
+                    health_path
+                ));
+            }
+            if *health_path == self.ws_path {
+                return Err(anyhow!("Invalid health_path: must differ from ws_path"));
+            }
+        }
+        if self.accept_backoff.is_zero() {
+            return Err(anyhow!("Invalid accept_backoff: must be greater than zero"));
+        }
+        if self.mqueue_rate_limit.1.is_zero() {
+            return Err(anyhow!("Invalid mqueue_rate_limit: the duration must not be zero"));
+        }
+        if self.tls_cert.is_some() && self.tls_cert_pem.is_some() {
+            return Err(anyhow!("Invalid tls_cert_pem: tls_cert is set as well, configure only one of them"));
+        }
+        if self.tls_key.is_some() && self.tls_key_pem.is_some() {
+            return Err(anyhow!("Invalid tls_key_pem: tls_key is set as well, configure only one of them"));
+        }
+        if self.tls_cross_certificate && !self.has_tls_identity() {
+            return Err(anyhow!(
+                "Invalid tls_cross_certificate: requires a certificate and key, from tls_cert/tls_key, tls_cert_pem/tls_key_pem or tls_pkcs12"
+            ));
+        }
+        if self.tls_client_ca.is_some() && !self.tls_cross_certificate {
+            return Err(anyhow!("Invalid tls_client_ca: only used when tls_cross_certificate is enabled"));
+        }
+        // Unknown SNI hostnames fall back to the default certificate, so there must be one
+        if !self.tls_sni_certs.is_empty() && !self.has_tls_identity() {
+            return Err(anyhow!(
+                "Invalid tls_sni_certs: requires a default certificate and key to fall back to"
+            ));
+        }
+        if self.tls_min_version > self.tls_max_version {
+            return Err(anyhow!(
+                "Invalid tls_min_version: {:?} is above tls_max_version {:?}",
+                self.tls_min_version,
+                self.tls_max_version
+            ));
+        }
+        Ok(())
+    }
+
     /// Binds the server to the configured address
-    #[allow(unused_variables)]
-    pub fn bind(self) -> Result<Listener> {
-        let builder = match self.laddr {
+    ///
+    /// Fails with [`ListenerError::Config`] without touching the network if
+    /// [`Builder::validate`] does, and with [`ListenerError::Bind`] if the socket cannot be set up.
+    pub fn bind(self) -> std::result::Result<Listener, ListenerError> {
+        self.validate().map_err(|e| ListenerError::Config(e.to_string()))?;
+        let laddr = self.laddr;
+        Self::listen(Arc::new(self), laddr)
+    }
+
+    /// Binds one listener per address in `laddrs`, all sharing this configuration
+    ///
+    /// Each listener has its own shutdown handle, counters and `max_connections` budget. If any
+    /// address cannot be bound, the listeners bound so far are closed again and the
+    /// [`ListenerError::Bind`] names the failing address.
+    ///
+    /// ```
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let listeners = rmqtt_net::Builder::new()
+    ///     .laddrs(vec!["127.0.0.1:0".parse()?, "127.0.0.1:0".parse()?])
+    ///     .bind_all()?;
+    /// assert_eq!(listeners.len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bind_all(self) -> std::result::Result<Vec<Listener>, ListenerError> {
+        self.validate().map_err(|e| ListenerError::Config(e.to_string()))?;
+        let laddrs = if self.laddrs.is_empty() { vec![self.laddr] } else { self.laddrs.clone() };
+        let cfg = Arc::new(self);
+        // Stops at the first failure, dropping the listeners collected so far closes their sockets
+        laddrs.into_iter().map(|laddr| Self::listen(cfg.clone(), laddr)).collect()
+    }
+
+    fn listen(cfg: Arc<Builder>, laddr: SocketAddr) -> std::result::Result<Listener, ListenerError> {
+        let bind_error = |source| ListenerError::Bind { addr: laddr, source };
+        let tcp_listener = cfg.bind_socket(laddr).map_err(bind_error)?;
+        // Resolved now so an ephemeral port asked for with `:0` is known before any accept()
+        let bound_addr = BoundAddr::Tcp(tcp_listener.local_addr().map_err(bind_error)?);
+
+        log::info!("MQTT Broker Listening on {} {}", cfg.name, bound_addr);
+        let rate_limiter = cfg.max_conns_per_ip_per_sec.map(ConnRateLimiter::new);
+        let connection_limit = Arc::new(Semaphore::new(cfg.max_connections.min(Semaphore::MAX_PERMITS)));
+        Ok(Listener {
+            typ: ListenerType::TCP,
+            cfg,
+            tcp_listener,
+            tls_acceptor: None,
+            shutdown: CancellationToken::new(),
+            counters: Arc::new(ListenerCounters::default()),
+            connection_limit,
+            rate_limiter,
+            bound_addr,
+        })
+    }
+
+    fn bind_socket(&self, laddr: SocketAddr) -> io::Result<TcpListener> {
+        let builder = match laddr {
             SocketAddr::V4(_) => Socket::new(Domain::IPV4, Type::STREAM, None)?,
             SocketAddr::V6(_) => Socket::new(Domain::IPV6, Type::STREAM, None)?,
         };
 
-        builder.set_linger(Some(Duration::from_secs(10)))?;
+        if let Some(linger) = self.linger {
+            builder.set_linger(Some(linger))?;
+        }
 
         builder.set_nonblocking(true)?;
 
@@ -409,21 +1083,36 @@ impl Builder {
             builder.set_reuse_port(reuseport)?;
         }
 
-        builder.bind(&SockAddr::from(self.laddr))?;
-        builder.listen(self.backlog)?;
-        let tcp_listener = TcpListener::from_std(std::net::TcpListener::from(builder))?;
+        if let Some(tos) = self.tos {
+            set_tos(&builder, tos)?;
+        }
 
-        log::info!(
-            "MQTT Broker Listening on {} {}",
-            self.name,
-            tcp_listener.local_addr().unwrap_or(self.laddr)
-        );
-        Ok(Listener {
-            typ: ListenerType::TCP,
-            cfg: Arc::new(self),
-            tcp_listener,
-            tls_acceptor: None,
-        })
+        match (self.ipv6_only, laddr) {
+            (Some(only_v6), SocketAddr::V6(_)) => builder.set_only_v6(only_v6)?,
+            (Some(_), SocketAddr::V4(_)) => {
+                log::warn!("{} ipv6_only is ignored for IPv4 address {}", self.name, laddr)
+            }
+            (None, _) => {}
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            builder.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            builder.set_send_buffer_size(size)?;
+        }
+
+        builder.bind(&SockAddr::from(laddr))?;
+        builder.listen(self.backlog)?;
+        if self.recv_buffer_size.is_some() || self.send_buffer_size.is_some() {
+            log::info!(
+                "{} socket buffers: recv {} bytes, send {} bytes",
+                self.name,
+                builder.recv_buffer_size()?,
+                builder.send_buffer_size()?
+            );
+        }
+        TcpListener::from_std(std::net::TcpListener::from(builder))
     }
 }
 
@@ -434,6 +1123,12 @@ pub enum ListenerType {
     TCP,
     /// TLS-secured TCP listener
     TLS,
+    #[cfg(feature = "ws")]
+    /// MQTT over WebSocket
+    WS,
+    #[cfg(all(feature = "tls", feature = "ws"))]
+    /// MQTT over TLS-secured WebSocket
+    WSS,
 }
 
 /// Network listener for accepting client connections
@@ -443,7 +1138,40 @@ pub struct Listener {
     /// Shared server configuration
     pub cfg: Arc<Builder>,
     tcp_listener: TcpListener,
-    tls_acceptor: Option<Arc<SslAcceptor>>,
+    tls_acceptor: Option<TlsAcceptor>,
+    shutdown: CancellationToken,
+    counters: Arc<ListenerCounters>,
+    connection_limit: Arc<Semaphore>,
+    rate_limiter: Option<ConnRateLimiter>,
+    bound_addr: BoundAddr,
+}
+
+/// Address a listener ended up bound to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BoundAddr {
+    /// TCP socket address, with the port the OS picked when binding to port 0
+    Tcp(SocketAddr),
+    /// Filesystem path of a Unix domain socket listener
+    Unix(PathBuf),
+}
+
+impl BoundAddr {
+    /// Returns the socket address of a TCP listener
+    pub fn as_tcp(&self) -> Option<SocketAddr> {
+        match self {
+            BoundAddr::Tcp(addr) => Some(*addr),
+            BoundAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for BoundAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BoundAddr::Tcp(addr) => addr.fmt(f),
+            BoundAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
 /// # Examples
@@ -457,62 +1185,274 @@ pub struct Listener {
 /// ```
 impl Listener {
     /// Converts listener to plain TCP mode
-    pub fn tcp(mut self) -> Result<Self> {
-        let _err = anyhow!("Protocol downgrade from TLS/WS/WSS to TCP is not permitted");
+    pub fn tcp(mut self) -> std::result::Result<Self, ListenerError> {
+        let _err =
+            ListenerError::ProtocolMismatch("Protocol downgrade from TLS/WS/WSS to TCP is not permitted");
         if matches!(self.typ, ListenerType::TLS) {
             return Err(_err);
         }
+        #[cfg(feature = "ws")]
+        if matches!(self.typ, ListenerType::WS) {
+            return Err(_err);
+        }
+        #[cfg(all(feature = "tls", feature = "ws"))]
+        if matches!(self.typ, ListenerType::WSS) {
+            return Err(_err);
+        }
         self.typ = ListenerType::TCP;
         Ok(self)
     }
 
+    #[cfg(feature = "ws")]
+    /// Converts listener to WebSocket mode
+    pub fn ws(mut self) -> std::result::Result<Self, ListenerError> {
+        if matches!(self.typ, ListenerType::TCP | ListenerType::WS) {
+            self.typ = ListenerType::WS;
+        } else {
+            return Err(ListenerError::ProtocolMismatch(
+                "Protocol upgrade from TLS/WSS to WS is not permitted",
+            ));
+        }
+        Ok(self)
+    }
+
+    #[cfg(all(feature = "tls", feature = "ws"))]
+    /// Upgrades listener to TLS-secured WebSocket
+    pub fn wss(mut self) -> std::result::Result<Listener, ListenerError> {
+        match self.typ {
+            ListenerType::WSS => return Ok(self),
+            ListenerType::TCP | ListenerType::WS => {}
+            ListenerType::TLS => {
+                return Err(ListenerError::ProtocolMismatch(
+                    "Protocol upgrade from TLS to WSS is not permitted",
+                ));
+            }
+        }
+
+        self.tls_acceptor = Some(self.tls_acceptor().map_err(ListenerError::TlsConfig)?);
+        self.typ = ListenerType::WSS;
+        Ok(self)
+    }
+
     /// Upgrades listener to TLS-secured TCP
-    pub fn tls(mut self) -> Result<Listener> {
+    pub fn tls(mut self) -> std::result::Result<Listener, ListenerError> {
         match self.typ {
             ListenerType::TLS => return Ok(self),
             ListenerType::TCP => {}
+            #[cfg(feature = "ws")]
+            ListenerType::WS => {
+                return Err(ListenerError::ProtocolMismatch(
+                    "Protocol downgrade from WS to TLS is not permitted",
+                ));
+            }
+            #[cfg(all(feature = "tls", feature = "ws"))]
+            ListenerType::WSS => {
+                return Err(ListenerError::ProtocolMismatch(
+                    "Protocol downgrade from WSS to TLS is not permitted",
+                ));
+            }
         }
 
-        let cert_file = self
-            .cfg
-            .tls_cert
-            .as_ref()
-            .ok_or(anyhow!("TLS certificate path not set"))?;
-        let key_file = self
-            .cfg
-            .tls_key
-            .as_ref()
-            .ok_or(anyhow!("TLS key path not set"))?;
+        self.tls_acceptor = Some(self.tls_acceptor().map_err(ListenerError::TlsConfig)?);
+        self.typ = ListenerType::TLS;
+        Ok(self)
+    }
 
-        // Create OpenSSL acceptor
-        let mut acceptor_builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    /// Builds the acceptor of the compiled-in TLS backend, shared by the TLS and WSS modes
+    fn tls_acceptor(&self) -> Result<TlsAcceptor> {
+        #[cfg(all(feature = "openssl", not(feature = "rustls")))]
+        if let Some(custom) = &self.cfg.tls_acceptor {
+            return Ok(TlsAcceptor::from(custom));
+        }
+        if let Some(bundle) = &self.cfg.tls_pkcs12 {
+            return crate::tls::build_acceptor(&self.cfg, Identity::Pkcs12(bundle));
+        }
 
-        // Configure certificates
-        acceptor_builder.set_private_key_file(key_file, SslFiletype::PEM)?;
-        acceptor_builder.set_certificate_chain_file(cert_file)?;
+        let cert = match (&self.cfg.tls_cert_pem, &self.cfg.tls_cert) {
+            (Some(pem), _) => Pem::Bytes(pem.as_ref()),
+            (None, Some(path)) => Pem::File(path),
+            (None, None) => return Err(anyhow!("TLS certificate not set")),
+        };
+        let key = match (&self.cfg.tls_key_pem, &self.cfg.tls_key) {
+            (Some(pem), _) => Pem::Bytes(pem.as_ref()),
+            (None, Some(path)) => Pem::File(path),
+            (None, None) => return Err(anyhow!("TLS key not set")),
+        };
 
-        // Enable legacy cipher suite
-        acceptor_builder.set_cipher_list("ECDHE-RSA-AES256-SHA")?;
+        crate::tls::build_acceptor(&self.cfg, Identity::Pem { cert, key })
+    }
 
-        // Configure client verification
-        if self.cfg.tls_cross_certificate {
-            acceptor_builder.set_ca_file(cert_file)?; // Use server cert as CA
-            acceptor_builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
-        } else {
-            acceptor_builder.set_verify(SslVerifyMode::NONE);
-        }
+    /// Re-reads the `tls_ocsp_response` file and staples it to subsequent handshakes
+    ///
+    /// On error the previously loaded response stays in use.
+    pub fn reload_ocsp(&self) -> Result<()> {
+        let acceptor =
+            self.tls_acceptor.as_ref().ok_or_else(|| anyhow!("OCSP reload requires a TLS listener"))?;
+        crate::tls::reload_ocsp(acceptor)
+    }
 
-        let acceptor = Arc::new(acceptor_builder.build());
-        self.tls_acceptor = Some(acceptor);
-        self.typ = ListenerType::TLS;
-        Ok(self)
+    /// Returns a token that stops this listener's accept loop when cancelled
+    ///
+    /// Connections that were already accepted, including ones still in their TLS/WebSocket
+    /// handshake, are left running so the caller can drain them.
+    ///
+    /// ```rust,no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let listener = rmqtt_net::Builder::new().bind()?;
+    /// let shutdown = listener.shutdown_handle();
+    /// tokio::spawn(async move {
+    ///     let _ = tokio::signal::ctrl_c().await;
+    ///     shutdown.cancel();
+    /// });
+    ///
+    /// loop {
+    ///     match listener.accept().await {
+    ///         Ok(acceptor) => { /* handle connection */ }
+    ///         Err(e) if e.is_shutdown() => break,
+    ///         Err(e) => return Err(e.into()),
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shutdown_handle(&self) -> CancellationToken {
+        self.shutdown.clone()
     }
 
     /// Accepts incoming client connections
-    pub async fn accept(&self) -> Result<Acceptor<TcpStream>> {
-        let (socket, remote_addr) = self.tcp_listener.accept().await?;
-        if let Err(e) = socket.set_nodelay(self.cfg.nodelay) {
-            return Err(Error::from(e));
+    ///
+    /// Resolves to [`ListenerError::Shutdown`] once the shutdown handle fires.
+    /// At most `max_connections` accepted connections are alive at a time; the slot is held by
+    /// the `Acceptor` and whatever it turns into, and freed when that is dropped.
+    ///
+    /// Cancellation safe: a socket is only taken off the listen queue right before the
+    /// `Acceptor` is returned, with no await in between, so dropping the future never loses a
+    /// connection that was already accepted.
+    pub async fn accept(&self) -> std::result::Result<Acceptor<TcpStream>, ListenerError> {
+        let mut permit = if self.cfg.reject_on_full {
+            None
+        } else {
+            tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => {
+                    return Err(ListenerError::Shutdown);
+                }
+                permit = self.connection_limit.clone().acquire_owned() => {
+                    Some(permit.map_err(|_| ListenerError::ConnectionLimit)?)
+                }
+            }
+        };
+
+        let mut backoff: Option<Duration> = None;
+        let (socket, remote_addr) = loop {
+            let accepted = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => {
+                    return Err(ListenerError::Shutdown);
+                }
+                accepted = self.tcp_listener.accept() => accepted,
+            };
+            let (socket, remote_addr) = match accepted {
+                Ok(accepted) => accepted,
+                // Retrying straight away would spin on EMFILE/ENFILE until a descriptor frees up
+                Err(e) if is_fd_exhausted(&e) => {
+                    self.counters.accept_error();
+                    let delay = match backoff {
+                        None => {
+                            let delay = ACCEPT_BACKOFF_MIN.min(self.cfg.accept_backoff);
+                            log::warn!(
+                                "{} accept failed, out of file descriptors, backing off up to {:?}: {}",
+                                self.cfg.name,
+                                self.cfg.accept_backoff,
+                                e
+                            );
+                            delay
+                        }
+                        Some(prev) => {
+                            let delay = (prev * 2).min(self.cfg.accept_backoff);
+                            log::debug!(
+                                "{} accept still failing, retrying in {:?}: {}",
+                                self.cfg.name,
+                                delay,
+                                e
+                            );
+                            delay
+                        }
+                    };
+                    backoff = Some(delay);
+                    tokio::select! {
+                        _ = self.shutdown.cancelled() => {
+                            return Err(ListenerError::Shutdown);
+                        }
+                        _ = tokio::time::sleep(delay) => {}
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    self.counters.accept_error();
+                    return Err(ListenerError::Accept(e));
+                }
+            };
+
+            // Connections turned away below are closed by dropping the socket
+            if !ip_allowed(&self.cfg, remote_addr.ip()) {
+                self.counters.denied();
+                log::debug!(
+                    "{} closing connection from {}, denied by the IP filter",
+                    self.cfg.name,
+                    remote_addr
+                );
+                continue;
+            }
+            if let Some(limiter) = &self.rate_limiter {
+                if !limiter.check(remote_addr.ip()) {
+                    self.counters.rate_limited();
+                    log::debug!(
+                        "{} closing connection from {}, over max_conns_per_ip_per_sec",
+                        self.cfg.name,
+                        remote_addr
+                    );
+                    continue;
+                }
+            }
+            if let Some(filter) = &self.cfg.on_accept {
+                if !filter.allows(remote_addr) {
+                    self.counters.filtered();
+                    log::debug!(
+                        "{} closing connection from {}, refused by on_accept",
+                        self.cfg.name,
+                        remote_addr
+                    );
+                    continue;
+                }
+            }
+            if permit.is_none() {
+                match self.connection_limit.clone().try_acquire_owned() {
+                    Ok(p) => permit = Some(p),
+                    Err(_) => {
+                        self.counters.rejected();
+                        log::debug!(
+                            "{} at max_connections {}, closing connection from {}",
+                            self.cfg.name,
+                            self.cfg.max_connections,
+                            remote_addr
+                        );
+                        continue;
+                    }
+                }
+            }
+            break (socket, remote_addr);
+        };
+        // Counted from here on, so dropping the socket below releases it again
+        let active = self.counters.accepted(permit);
+        socket.set_nodelay(self.cfg.nodelay).map_err(ListenerError::Accept)?;
+        if let Some(tos) = self.cfg.tos {
+            set_tos(&SockRef::from(&socket), tos).map_err(ListenerError::Accept)?;
+        }
+        if let Some(idle) = self.cfg.tcp_keepalive {
+            SockRef::from(&socket)
+                .set_tcp_keepalive(&keepalive_params(idle))
+                .map_err(ListenerError::Accept)?;
         }
         Ok(Acceptor {
             socket,
@@ -520,26 +1460,243 @@ impl Listener {
             acceptor: self.tls_acceptor.clone(),
             cfg: self.cfg.clone(),
             typ: self.typ,
+            active,
         })
     }
 
+    /// Like [`Listener::accept`], but resolves to `Ok(None)` if no connection arrives in time
+    ///
+    /// Lets an event loop wake up regularly to do other work without a separate task. Pending
+    /// connections stay queued in the backlog for the next call.
+    ///
+    /// ```rust,no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    ///
+    /// let listener = rmqtt_net::Builder::new().bind()?;
+    /// loop {
+    ///     match listener.accept_timeout(Duration::from_secs(1)).await {
+    ///         Ok(Some(acceptor)) => { /* handle connection */ }
+    ///         Ok(None) => { /* periodic housekeeping */ }
+    ///         Err(e) if e.is_shutdown() => break,
+    ///         Err(e) => return Err(e.into()),
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn accept_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<Option<Acceptor<TcpStream>>, ListenerError> {
+        match tokio::time::timeout(timeout, self.accept()).await {
+            Ok(res) => res.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Runs the accept loop, handing every connection to `handler` on a task of its own
+    ///
+    /// The handshake matching the listener type, `tcp()`, `tls()`, `ws()` or `wss()`, runs on
+    /// that task, with at most `max_handshaking_limit` of them in flight; beyond that new
+    /// connections wait in the backlog. Connection limits, filters and accept backoff apply as
+    /// with [`Listener::accept`]. Failed handshakes and health checks are logged and never reach
+    /// `handler`.
+    ///
+    /// Returns once the shutdown handle fires. Connections already handed to `handler` keep
+    /// running, [`Listener::wait_idle`] waits for them.
+    ///
+    /// ```rust,no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let listener = rmqtt_net::Builder::new().bind()?;
+    /// listener
+    ///     .serve(|dispatcher| async move {
+    ///         if let Ok(stream) = dispatcher.mqtt().await {
+    ///             // Handle the MQTT session...
+    ///         }
+    ///     })
+    ///     .await?;
+    /// listener.wait_idle().await;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn serve<F, Fut>(&self, handler: F) -> std::result::Result<(), ListenerError>
+    where
+        F: Fn(Dispatcher<BoxedIo>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let handshakes =
+            Arc::new(Semaphore::new(self.cfg.max_handshaking_limit.clamp(1, Semaphore::MAX_PERMITS)));
+        loop {
+            let permit = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => return Ok(()),
+                permit = handshakes.clone().acquire_owned() => {
+                    permit.map_err(|_| ListenerError::ConnectionLimit)?
+                }
+            };
+            let acceptor = match self.accept().await {
+                Ok(acceptor) => acceptor,
+                Err(ListenerError::Shutdown) => return Ok(()),
+                // Usually a client that reset the connection while it was queued
+                Err(ListenerError::Accept(e)) => {
+                    log::warn!("{} accept failed: {}", self.cfg.name, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let name = acceptor.cfg.name.clone();
+                let remote_addr = acceptor.remote_addr;
+                let dispatcher = acceptor.handshake().await;
+                drop(permit);
+                match dispatcher {
+                    Ok(dispatcher) => handler(dispatcher).await,
+                    Err(e) if e.is_health_check() => {}
+                    Err(e) => log::debug!("{} handshake with {} failed: {}", name, remote_addr, e),
+                }
+            });
+        }
+    }
+
+    /// Returns a snapshot of the connection counters
+    pub fn stats(&self) -> ListenerStats {
+        self.counters.snapshot()
+    }
+
+    /// Number of accepted connections that are still alive
+    ///
+    /// A connection counts from `accept()` until its `Acceptor`, `Dispatcher` or MQTT stream is
+    /// dropped.
+    pub fn active_connections(&self) -> usize {
+        self.counters.active() as usize
+    }
+
+    /// Waits until [`Listener::active_connections`] reaches zero
+    ///
+    /// For draining: stop calling `accept()`, e.g. through the
+    /// [`shutdown_handle`](Listener::shutdown_handle), then wait for the remaining sessions.
+    /// Resolves immediately when nothing is connected.
+    pub async fn wait_idle(&self) {
+        self.counters.wait_idle().await
+    }
+
+    /// Returns the address resolved when the listener was bound
+    ///
+    /// ```
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let listener = rmqtt_net::Builder::new().laddr("127.0.0.1:0".parse()?).bind()?;
+    /// let port = listener.bound_addr().as_tcp().unwrap().port();
+    /// assert_ne!(port, 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bound_addr(&self) -> &BoundAddr {
+        &self.bound_addr
+    }
+
+    /// Queries the local socket address of the TCP listener
     pub fn local_addr(&self) -> Result<SocketAddr> {
         Ok(self.tcp_listener.local_addr()?)
     }
 }
 
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+)))]
+fn set_tos(socket: &Socket, tos: u32) -> io::Result<()> {
+    socket
+        .set_tos(tos)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to set IP_TOS to {:#x}: {}", tos, e)))
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+))]
+fn set_tos(_socket: &Socket, _tos: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "IP_TOS is not supported on this platform"))
+}
+
+fn keepalive_params(idle: Duration) -> TcpKeepalive {
+    let params = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "illumos",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "tvos",
+        target_os = "watchos",
+        target_os = "windows",
+    ))]
+    let params = params.with_interval(idle);
+    params
+}
+
+/// Deny entries win over allow entries, an empty allow list admits everyone else
+fn ip_allowed(cfg: &Builder, ip: IpAddr) -> bool {
+    // IPv4 clients of a dual-stack socket show up as ::ffff:a.b.c.d
+    let ip = ip.to_canonical();
+    if cfg.deny_cidrs.iter().any(|net| net.contains(&ip)) {
+        return false;
+    }
+    cfg.allow_cidrs.is_empty() || cfg.allow_cidrs.iter().any(|net| net.contains(&ip))
+}
+
+/// Whether `s` is a token as defined by RFC 7230, the syntax of a WebSocket subprotocol
+fn is_http_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// First retry delay after running out of file descriptors, doubled up to `accept_backoff`
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(1);
+
+#[cfg(unix)]
+fn is_fd_exhausted(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+#[cfg(windows)]
+fn is_fd_exhausted(e: &std::io::Error) -> bool {
+    // WSAEMFILE
+    e.raw_os_error() == Some(10024)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_fd_exhausted(_e: &std::io::Error) -> bool {
+    false
+}
+
 /// Connection handler for processing client streams
 pub struct Acceptor<S> {
     /// Underlying network transport
     pub(crate) socket: S,
 
-    acceptor: Option<Arc<SslAcceptor>>,
+    #[cfg_attr(not(any(feature = "openssl", feature = "rustls")), allow(dead_code))]
+    acceptor: Option<TlsAcceptor>,
     /// Remote client address
     pub remote_addr: SocketAddr,
     /// Shared server configuration
     pub cfg: Arc<Builder>,
     /// Active protocol type
     pub typ: ListenerType,
+    active: ActiveConnection,
 }
 
 impl<S> Acceptor<S>
@@ -547,39 +1704,323 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     /// Creates TCP protocol dispatcher
+    ///
+    /// With `proxy_protocol` enabled the header is read by [`Dispatcher::mqtt`].
     #[inline]
-    pub fn tcp(self) -> Result<Dispatcher<S>> {
+    pub fn tcp(self) -> std::result::Result<Dispatcher<S>, ListenerError> {
         if matches!(self.typ, ListenerType::TCP) {
-            Ok(Dispatcher::new(self.socket, self.remote_addr, self.cfg))
+            let proxy_pending = self.cfg.proxy_protocol;
+            Ok(Dispatcher::new(self.socket, self.remote_addr, self.cfg, self.active)
+                .proxy_pending(proxy_pending)
+                .health_check(true))
         } else {
-            Err(anyhow!("Protocol mismatch: Expected TCP listener"))
+            Err(ListenerError::ProtocolMismatch("Expected TCP listener"))
         }
     }
 
+    #[cfg(any(feature = "openssl", feature = "rustls"))]
     /// Performs TLS handshake and creates secure dispatcher
+    ///
+    /// The stream is a `tokio_openssl::SslStream` with the default backend and a
+    /// `tokio_rustls::server::TlsStream` with the `rustls` feature.
     #[inline]
-    pub async fn tls(self) -> Result<Dispatcher<TokioSslStream<S>>> {
+    pub async fn tls(mut self) -> std::result::Result<Dispatcher<TlsStream<S>>, ListenerError> {
         if !matches!(self.typ, ListenerType::TLS) {
-            return Err(anyhow!("Protocol mismatch: Expected TLS listener"));
+            return Err(ListenerError::ProtocolMismatch("Expected TLS listener"));
         }
+        self.proxy_header().await?;
+
+        let acceptor = self.acceptor.ok_or_else(no_tls_acceptor)?;
+        let stream = match crate::tls::accept(&acceptor, self.socket, self.cfg.handshake_timeout).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.active.handshake_failed();
+                return Err(ListenerError::handshake(e));
+            }
+        };
+        let alpn = crate::tls::negotiated_alpn(&stream);
+        let peer_cert = crate::tls::peer_cert_info(&stream);
+        Ok(Dispatcher::new(stream, self.remote_addr, self.cfg, self.active)
+            .alpn(alpn)
+            .peer_cert(peer_cert)
+            .health_check(true))
+    }
 
-        let acceptor = self
-            .acceptor
-            .ok_or_else(|| crate::MqttError::ServiceUnavailable)?;
-        let ssl = Ssl::new(acceptor.context())?;
-        let tls_stream = TokioSslStream::new(ssl, self.socket)?;
+    #[cfg(feature = "ws")]
+    /// Performs WebSocket upgrade and creates WS dispatcher
+    ///
+    /// Requests that are not a WebSocket upgrade offering one of `ws_subprotocols` are answered
+    /// with `400 Bad Request`, requests for a path other than `ws_path` with `404 Not Found`.
+    #[inline]
+    pub async fn ws(mut self) -> std::result::Result<Dispatcher<WsStream<S>>, ListenerError> {
+        if !matches!(self.typ, ListenerType::WS) {
+            return Err(ListenerError::ProtocolMismatch("Expected WS listener"));
+        }
+        self.proxy_header().await?;
 
-        // Perform TLS handshake
-        match tokio::time::timeout(self.cfg.handshake_timeout, async {
-            let mut stream = tls_stream;
-            std::pin::Pin::new(&mut stream).accept().await?;
-            Ok::<tokio_openssl::SslStream<S>, openssl::ssl::Error>(stream)
-        })
-        .await
+        match tokio::time::timeout(self.cfg.handshake_timeout, crate::ws::accept(self.socket, &self.cfg))
+            .await
+        {
+            Ok(Ok(ws_stream)) => Ok(Dispatcher::new(ws_stream, self.remote_addr, self.cfg, self.active)),
+            Ok(Err(e)) => {
+                self.active.handshake_failed();
+                Err(ListenerError::handshake(e))
+            }
+            Err(_) => {
+                self.active.handshake_failed();
+                Err(ListenerError::Timeout)
+            }
+        }
+    }
+
+    #[cfg(all(feature = "tls", feature = "ws", any(feature = "openssl", feature = "rustls")))]
+    /// Performs TLS handshake, then the WebSocket upgrade over the encrypted stream
+    ///
+    /// Both steps are bounded by `handshake_timeout` separately.
+    #[inline]
+    pub async fn wss(mut self) -> std::result::Result<Dispatcher<WsStream<TlsStream<S>>>, ListenerError> {
+        if !matches!(self.typ, ListenerType::WSS) {
+            return Err(ListenerError::ProtocolMismatch("Expected WSS listener"));
+        }
+        self.proxy_header().await?;
+
+        let acceptor = self.acceptor.ok_or_else(no_tls_acceptor)?;
+        let tls_stream = match crate::tls::accept(&acceptor, self.socket, self.cfg.handshake_timeout).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.active.handshake_failed();
+                return Err(ListenerError::handshake(e));
+            }
+        };
+        let alpn = crate::tls::negotiated_alpn(&tls_stream);
+        let peer_cert = crate::tls::peer_cert_info(&tls_stream);
+
+        match tokio::time::timeout(self.cfg.handshake_timeout, crate::ws::accept(tls_stream, &self.cfg)).await
         {
-            Ok(Ok(stream)) => Ok(Dispatcher::new(stream, self.remote_addr, self.cfg)),
-            Ok(Err(e)) => Err(e.into()),
-            Err(_) => Err(crate::MqttError::ReadTimeout.into()),
+            Ok(Ok(ws_stream)) => Ok(Dispatcher::new(ws_stream, self.remote_addr, self.cfg, self.active)
+                .alpn(alpn)
+                .peer_cert(peer_cert)),
+            Ok(Err(e)) => {
+                self.active.handshake_failed();
+                Err(ListenerError::handshake(e))
+            }
+            Err(_) => {
+                self.active.handshake_failed();
+                Err(ListenerError::Timeout)
+            }
+        }
+    }
+
+    /// Runs the handshake of the listener type and erases the resulting transport type
+    async fn handshake(self) -> std::result::Result<Dispatcher<BoxedIo>, ListenerError>
+    where
+        S: Send + 'static,
+    {
+        match self.typ {
+            ListenerType::TCP => self.tcp().map(Dispatcher::boxed),
+            #[cfg(any(feature = "openssl", feature = "rustls"))]
+            ListenerType::TLS => self.tls().await.map(Dispatcher::boxed),
+            #[cfg(feature = "ws")]
+            ListenerType::WS => self.ws().await.map(Dispatcher::boxed),
+            #[cfg(all(feature = "tls", feature = "ws", any(feature = "openssl", feature = "rustls")))]
+            ListenerType::WSS => self.wss().await.map(Dispatcher::boxed),
+            // A listener only reaches these types through a TLS backend
+            #[allow(unreachable_patterns)]
+            _ => Err(ListenerError::ProtocolMismatch("TLS support is not compiled in")),
+        }
+    }
+
+    /// Consumes the PROXY protocol header, if configured, and records the real client address
+    #[cfg_attr(not(any(feature = "openssl", feature = "rustls", feature = "ws")), allow(dead_code))]
+    async fn proxy_header(&mut self) -> std::result::Result<(), ListenerError> {
+        if !self.cfg.proxy_protocol {
+            return Ok(());
         }
+        match crate::proxy::read_header(&mut self.socket, self.cfg.handshake_timeout).await {
+            Ok(addr) => {
+                if let Some(addr) = addr {
+                    self.remote_addr = addr;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.active.handshake_failed();
+                Err(ListenerError::handshake(e))
+            }
+        }
+    }
+}
+
+/// TLS modes always build their acceptor, so this only guards against a broken invariant
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+fn no_tls_acceptor() -> ListenerError {
+    ListenerError::TlsConfig(anyhow!("TLS acceptor not initialized"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::MqttStream;
+
+    fn loopback() -> Builder {
+        Builder::new().laddr(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    async fn connect(listener: &Listener) -> TcpStream {
+        TcpStream::connect(listener.local_addr().unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn connection_slot_is_freed_when_the_acceptor_drops() {
+        let listener = loopback().max_connections(1).bind().unwrap();
+        let _first_client = connect(&listener).await;
+        let first = listener.accept().await.unwrap();
+        assert_eq!(listener.active_connections(), 1);
+
+        // The second connection waits in the backlog while the first holds the only slot
+        let second_client = connect(&listener).await;
+        let second_local = second_client.local_addr().unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+
+        drop(first);
+        let second = tokio::time::timeout(Duration::from_secs(1), listener.accept()).await.unwrap().unwrap();
+        assert_eq!(second.remote_addr, second_local);
+        assert_eq!(listener.active_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn reject_on_full_closes_connections_over_the_limit() {
+        let listener = loopback().max_connections(1).reject_on_full(true).bind().unwrap();
+        let _first_client = connect(&listener).await;
+        let first = listener.accept().await.unwrap();
+
+        let mut second_client = connect(&listener).await;
+        assert!(tokio::time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+        let mut buf = [0u8; 1];
+        // Closed without a byte sent
+        assert!(matches!(second_client.read(&mut buf).await, Ok(0) | Err(_)));
+        assert_eq!(listener.stats().rejected, 1);
+
+        drop(first);
+        let _third_client = connect(&listener).await;
+        assert!(tokio::time::timeout(Duration::from_secs(1), listener.accept()).await.unwrap().is_ok());
+    }
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|n| n.parse().unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_cidrs_win_over_allow_cidrs() {
+        let cfg = Builder::new()
+            .allow_cidrs(nets(&["192.168.0.0/16", "fd00::/8"]))
+            .deny_cidrs(nets(&["192.168.66.0/24", "fd00:bad::/32"]));
+        assert!(ip_allowed(&cfg, ip("192.168.1.10")));
+        assert!(!ip_allowed(&cfg, ip("192.168.66.10")));
+        assert!(!ip_allowed(&cfg, ip("10.0.0.1")));
+        assert!(ip_allowed(&cfg, ip("fd00::1")));
+        assert!(!ip_allowed(&cfg, ip("fd00:bad::1")));
+        assert!(!ip_allowed(&cfg, ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn empty_allow_cidrs_admit_everyone_not_denied() {
+        let cfg = Builder::new();
+        assert!(ip_allowed(&cfg, ip("203.0.113.7")));
+        assert!(ip_allowed(&cfg, ip("2001:db8::1")));
+        let cfg = cfg.deny_cidrs(nets(&["203.0.113.0/24"]));
+        assert!(!ip_allowed(&cfg, ip("203.0.113.7")));
+        assert!(ip_allowed(&cfg, ip("198.51.100.7")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_match_ipv4_cidrs() {
+        let cfg = Builder::new().allow_cidrs(nets(&["192.168.0.0/16"])).deny_cidrs(nets(&["192.168.66.0/24"]));
+        assert!(ip_allowed(&cfg, ip("::ffff:192.168.1.10")));
+        assert!(!ip_allowed(&cfg, ip("::ffff:192.168.66.10")));
+        assert!(!ip_allowed(&cfg, ip("::ffff:10.0.0.1")));
+    }
+
+    #[tokio::test]
+    async fn accept_closes_denied_peers() {
+        let listener = loopback().deny_cidrs(nets(&["127.0.0.0/8"])).bind().unwrap();
+        let mut client = connect(&listener).await;
+        assert!(tokio::time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+        let mut buf = [0u8; 1];
+        assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)));
+        assert_eq!(listener.stats().denied, 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_a_pending_accept() {
+        let listener = Arc::new(loopback().bind().unwrap());
+        let pending = tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.accept().await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!pending.is_finished());
+
+        listener.shutdown_handle().cancel();
+        let res = tokio::time::timeout(Duration::from_secs(1), pending).await.unwrap().unwrap();
+        assert!(matches!(res, Err(ListenerError::Shutdown)));
+        // Later calls end right away, even with a client waiting in the backlog
+        let _client = connect(&listener).await;
+        assert!(matches!(listener.accept().await, Err(ListenerError::Shutdown)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_an_accept_waiting_for_a_slot() {
+        let listener = Arc::new(loopback().max_connections(1).bind().unwrap());
+        let _first_client = connect(&listener).await;
+        let _first = listener.accept().await.unwrap();
+        let pending = tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.accept().await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        listener.shutdown_handle().cancel();
+        let res = tokio::time::timeout(Duration::from_secs(1), pending).await.unwrap().unwrap();
+        assert!(matches!(res, Err(ListenerError::Shutdown)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_leaves_a_handshake_in_progress_alone() {
+        let listener = Arc::new(loopback().proxy_protocol(true).bind().unwrap());
+        let mut client = connect(&listener).await;
+        let acceptor = listener.accept().await.unwrap();
+        // Stalled halfway through the PROXY header
+        client.write_all(b"PROXY TCP4 192.0.2.1 ").await.unwrap();
+        let handshake = tokio::spawn(async move {
+            match acceptor.tcp().unwrap().mqtt().await.unwrap() {
+                MqttStream::V3(stream) => stream.remote_addr,
+                MqttStream::V5(stream) => stream.remote_addr,
+            }
+        });
+        let pending = tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.accept().await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        listener.shutdown_handle().cancel();
+        let res = tokio::time::timeout(Duration::from_secs(1), pending).await.unwrap().unwrap();
+        assert!(matches!(res, Err(ListenerError::Shutdown)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!handshake.is_finished());
+
+        // The rest of the header, then an MQTT 3.1.1 CONNECT
+        client.write_all(b"192.0.2.2 1111 1883\r\n").await.unwrap();
+        client.write_all(&[0x10, 13, 0, 4, b'M', b'Q', b'T', b'T', 4, 2, 0, 60, 0, 1, b'c']).await.unwrap();
+        let remote_addr = tokio::time::timeout(Duration::from_secs(1), handshake).await.unwrap().unwrap();
+        assert_eq!(remote_addr, SocketAddr::from(([192, 0, 2, 1], 1111)));
     }
 }
@@ -0,0 +1,305 @@
+//! Declarative listener configuration
+//!
+//! [`BuilderConfig`] mirrors the [`Builder`] knobs so a listener can be described in a
+//! configuration file instead of a chain of setters. Every field is optional and falls back to
+//! the [`Builder::new`] default when left out.
+//!
+//! ```
+//! use std::time::Duration;
+//! use rmqtt_net::{Builder, BuilderConfig};
+//!
+//! let cfg: BuilderConfig = serde_json::from_str(
+//!     r#"{ "laddr": "127.0.0.1:1883", "handshake_timeout": "1m30s", "max_qos_allowed": 1 }"#,
+//! )
+//! .unwrap();
+//! let builder = Builder::from_config(cfg);
+//! assert_eq!(builder.handshake_timeout, Duration::from_secs(90));
+//! ```
+
+use std::net::SocketAddr;
+use std::num::{NonZeroU16, NonZeroU32};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use rmqtt_codec::types::QoS;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use crate::{Builder, Pkcs12Bundle, Preset, TlsVersion};
+
+/// Serde representation of a [`Builder`]
+///
+/// Durations accept either a number of seconds or a human readable string made of
+/// `ms`, `s`, `m`, `h` and `d` components such as `"30s"` or `"1h 30m"`. `max_qos_allowed` is
+/// given as `0`, `1` or `2`, `mqueue_rate_limit` as `[messages, duration]` and `preset` as
+/// `"edge_gateway"` or `"cloud_broker"`, applied before the other fields. `linger: null` leaves
+/// SO_LINGER unset, while leaving `linger` out keeps the default. In-memory certificates and
+/// keys, the `on_accept` callback, the `client_registry` and a custom `tls_acceptor` have no
+/// counterpart here, set them on the builder afterwards.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuilderConfig {
+    pub preset: Option<Preset>,
+    pub name: Option<String>,
+    pub laddr: Option<SocketAddr>,
+    pub laddrs: Option<Vec<SocketAddr>>,
+    pub backlog: Option<i32>,
+    pub nodelay: Option<bool>,
+    pub reuseaddr: Option<bool>,
+    pub reuseport: Option<bool>,
+    pub tos: Option<u32>,
+    pub ipv6_only: Option<bool>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub tcp_keepalive: Option<Duration>,
+    #[serde(deserialize_with = "nullable_duration")]
+    pub linger: Option<Option<Duration>>,
+    pub proxy_protocol: Option<bool>,
+    pub max_connections: Option<usize>,
+    pub reject_on_full: Option<bool>,
+    pub allow_cidrs: Option<Vec<IpNet>>,
+    pub deny_cidrs: Option<Vec<IpNet>>,
+    pub max_conns_per_ip_per_sec: Option<NonZeroU32>,
+    pub max_handshaking_limit: Option<usize>,
+    pub max_packet_size: Option<u32>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub accept_backoff: Option<Duration>,
+
+    pub allow_anonymous: Option<bool>,
+    pub min_keepalive: Option<u16>,
+    pub max_keepalive: Option<u16>,
+    pub allow_zero_keepalive: Option<bool>,
+    pub keepalive_backoff: Option<f32>,
+    pub max_inflight: Option<NonZeroU16>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub handshake_timeout: Option<Duration>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub send_timeout: Option<Duration>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub idle_timeout: Option<Duration>,
+    pub max_mqueue_len: Option<usize>,
+    #[serde(deserialize_with = "opt_rate_limit")]
+    pub mqueue_rate_limit: Option<(NonZeroU32, Duration)>,
+    pub max_clientid_len: Option<usize>,
+    #[serde(deserialize_with = "opt_qos")]
+    pub max_qos_allowed: Option<QoS>,
+    pub max_topic_levels: Option<usize>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub session_expiry_interval: Option<Duration>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub max_session_expiry_interval: Option<Duration>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub message_retry_interval: Option<Duration>,
+    #[serde(deserialize_with = "opt_duration")]
+    pub message_expiry_interval: Option<Duration>,
+    pub max_subscriptions: Option<usize>,
+    pub shared_subscription: Option<bool>,
+    pub max_topic_aliases: Option<u16>,
+    pub limit_subscription: Option<bool>,
+    pub delayed_publish: Option<bool>,
+    pub packet_trace: Option<bool>,
+
+    pub tls_cross_certificate: Option<bool>,
+    pub tls_client_ca: Option<String>,
+    pub tls_verify_depth: Option<u32>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_pkcs12: Option<Pkcs12Bundle>,
+    pub tls_sni_certs: Option<Vec<(String, String, String)>>,
+    pub tls_min_version: Option<TlsVersion>,
+    pub tls_max_version: Option<TlsVersion>,
+    pub tls_ciphers: Option<String>,
+    pub tls_ciphersuites: Option<String>,
+    pub tls_alpn: Option<Vec<String>>,
+    pub tls_ocsp_response: Option<PathBuf>,
+
+    pub ws_subprotocols: Option<Vec<String>>,
+    pub ws_path: Option<String>,
+    pub health_path: Option<String>,
+}
+
+impl Builder {
+    /// Creates a builder from a deserialized configuration, keeping defaults for unset fields
+    pub fn from_config(cfg: BuilderConfig) -> Builder {
+        // The preset goes first, so fields set next to it override its values
+        let mut b = match cfg.preset {
+            Some(preset) => Builder::new().preset(preset),
+            None => Builder::new(),
+        };
+
+        macro_rules! apply {
+            ($($field:ident),* $(,)?) => {
+                $(if let Some(v) = cfg.$field {
+                    b.$field = v;
+                })*
+            };
+        }
+        macro_rules! apply_opt {
+            ($($field:ident),* $(,)?) => {
+                $(if cfg.$field.is_some() {
+                    b.$field = cfg.$field;
+                })*
+            };
+        }
+
+        apply!(
+            name,
+            laddr,
+            laddrs,
+            backlog,
+            nodelay,
+            linger,
+            proxy_protocol,
+            max_connections,
+            reject_on_full,
+            allow_cidrs,
+            deny_cidrs,
+            max_handshaking_limit,
+            max_packet_size,
+            accept_backoff,
+            allow_anonymous,
+            min_keepalive,
+            max_keepalive,
+            allow_zero_keepalive,
+            keepalive_backoff,
+            max_inflight,
+            handshake_timeout,
+            send_timeout,
+            max_mqueue_len,
+            mqueue_rate_limit,
+            max_clientid_len,
+            max_qos_allowed,
+            max_topic_levels,
+            session_expiry_interval,
+            max_session_expiry_interval,
+            message_retry_interval,
+            message_expiry_interval,
+            max_subscriptions,
+            shared_subscription,
+            max_topic_aliases,
+            limit_subscription,
+            delayed_publish,
+            packet_trace,
+            tls_cross_certificate,
+            tls_verify_depth,
+            tls_sni_certs,
+            tls_min_version,
+            tls_max_version,
+            tls_alpn,
+            ws_subprotocols,
+            ws_path,
+        );
+        apply_opt!(
+            max_conns_per_ip_per_sec,
+            ipv6_only,
+            recv_buffer_size,
+            send_buffer_size,
+            tcp_keepalive,
+            idle_timeout,
+            reuseaddr,
+            reuseport,
+            tos,
+            tls_client_ca,
+            tls_cert,
+            tls_key,
+            tls_pkcs12,
+            tls_ciphers,
+            tls_ciphersuites,
+            tls_ocsp_response,
+            health_path
+        );
+
+        b
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Secs(u64),
+    Text(String),
+}
+
+impl RawDuration {
+    fn into_duration<E: de::Error>(self) -> Result<Duration, E> {
+        match self {
+            RawDuration::Secs(secs) => Ok(Duration::from_secs(secs)),
+            RawDuration::Text(text) => parse_duration(&text).map_err(E::custom),
+        }
+    }
+}
+
+/// Parses `"500ms"`, `"30s"`, `"1h 30m"` and similar, a bare number is taken as seconds
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    if let Ok(secs) = text.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if digits == 0 {
+            return Err(format!("invalid duration {text:?}, expected a number before the unit"));
+        }
+        let value: u64 = rest[..digits].parse().map_err(|_| format!("invalid duration {text:?}"))?;
+        rest = &rest[digits..];
+
+        let unit_len = rest.find(|c: char| c.is_ascii_digit() || c.is_whitespace()).unwrap_or(rest.len());
+        let unit = match &rest[..unit_len] {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            unit => return Err(format!("invalid duration {text:?}, unknown unit {unit:?}")),
+        };
+        rest = rest[unit_len..].trim_start();
+
+        let value = u32::try_from(value).ok().and_then(|v| unit.checked_mul(v));
+        total = value
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(|| format!("duration {text:?} is too large"))?;
+    }
+    Ok(total)
+}
+
+fn opt_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<RawDuration>::deserialize(deserializer)?.map(RawDuration::into_duration).transpose()
+}
+
+/// Like [`opt_duration`], but an explicit `null` is kept apart from a missing field
+fn nullable_duration<'de, D>(deserializer: D) -> Result<Option<Option<Duration>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    opt_duration(deserializer).map(Some)
+}
+
+fn opt_rate_limit<'de, D>(deserializer: D) -> Result<Option<(NonZeroU32, Duration)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some((limit, period)) = Option::<(NonZeroU32, RawDuration)>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    Ok(Some((limit, period.into_duration()?)))
+}
+
+fn opt_qos<'de, D>(deserializer: D) -> Result<Option<QoS>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<u8>::deserialize(deserializer)?
+        .map(|qos| {
+            QoS::try_from(qos)
+                .map_err(|_| de::Error::custom(format!("invalid QoS {qos}, expected 0, 1 or 2")))
+        })
+        .transpose()
+}
@@ -1,4 +1,6 @@
 use bytestring::ByteString;
+use std::io;
+use std::net::SocketAddr;
 use std::num::NonZeroU16;
 
 use serde::{Deserialize, Serialize};
@@ -34,6 +36,15 @@ pub enum MqttError {
     /// Close timeout
     #[error("Close timeout")]
     CloseTimeout,
+    /// Nothing was received within `Builder::idle_timeout`
+    #[error("Idle timeout")]
+    IdleTimeout,
+    /// A newer connection registered the same client identifier
+    #[error("session taken over by a new connection")]
+    SessionTakenOver,
+    /// The client sent nothing within its keepalive window
+    #[error("Keep alive timeout")]
+    KeepAliveTimeout,
     #[error("{1}")]
     PublishAckReason(PublishAckReason, ByteString),
     #[error("service unavailable")]
@@ -48,10 +59,101 @@ pub enum MqttError {
     SubscribeLimited(String),
     #[error("identifier rejected")]
     IdentifierRejected,
+    /// The `on_auth` hook refused the client during enhanced authentication
+    #[error("enhanced authentication failed")]
+    AuthFailed,
+    /// A PUBLISH used a topic alias above the maximum advertised in CONNACK
+    #[error("topic alias {0} exceeds the advertised maximum")]
+    TopicAliasInvalid(u16),
+    /// A PUBLISH carried an alias without a topic, and the alias was never mapped
+    #[error("topic alias {0} is not mapped to a topic")]
+    UnknownTopicAlias(u16),
+    /// The client's Receive Maximum is reached and `max_mqueue_len` publishes already wait for it
+    #[error("send queue is full, {0} publishes are waiting for the client's receive maximum")]
+    SendQueueFull(usize),
     #[error("Provided packet id is in use")]
     PacketIdInUse(NonZeroU16),
     #[error("Is None")]
     None,
+    /// The listener's shutdown handle was cancelled, `Listener::accept()` now reports it as
+    /// [`ListenerError::Shutdown`]
+    #[error("listener shutdown requested")]
+    ShutdownRequested,
+    /// The connection was an HTTP health check, it has been answered and closed
+    #[error("HTTP health check answered")]
+    HealthCheck,
+    /// The PROXY protocol header was missing or malformed
+    #[error("invalid PROXY protocol header, {0}")]
+    InvalidProxyHeader(String),
+}
+
+impl MqttError {
+    /// Whether `e` is the error `Listener::accept()` returns after shutdown was requested
+    pub fn is_shutdown(e: &anyhow::Error) -> bool {
+        matches!(e.downcast_ref::<MqttError>(), Some(MqttError::ShutdownRequested))
+            || e.downcast_ref::<ListenerError>().is_some_and(ListenerError::is_shutdown)
+    }
+
+    /// Whether `e` only means the connection was an HTTP health check, not an actual failure
+    pub fn is_health_check(e: &anyhow::Error) -> bool {
+        matches!(e.downcast_ref::<MqttError>(), Some(MqttError::HealthCheck))
+            || e.downcast_ref::<ListenerError>().is_some_and(ListenerError::is_health_check)
+    }
+}
+
+/// Errors of the listener API: binding, accepting and connection handshakes
+///
+/// Converts into [`crate::Error`] with `?`, so callers that do not care about the variant are
+/// unaffected.
+#[derive(Debug, thiserror::Error)]
+pub enum ListenerError {
+    /// The builder settings contradict each other, see `Builder::validate`
+    #[error("{0}")]
+    Config(String),
+    /// Creating, configuring or binding the listening socket failed
+    #[error("failed to bind listener on {addr}: {source}")]
+    Bind { addr: SocketAddr, source: io::Error },
+    /// The TLS certificate, key or settings could not be loaded
+    #[error("invalid TLS configuration: {0}")]
+    TlsConfig(#[source] anyhow::Error),
+    /// Accepting or setting up an incoming socket failed
+    #[error("accept failed: {0}")]
+    Accept(#[source] io::Error),
+    /// The shutdown handle was cancelled
+    #[error("listener shutdown requested")]
+    Shutdown,
+    /// No `max_connections` slot could be obtained
+    #[error("connection limit unavailable")]
+    ConnectionLimit,
+    /// The PROXY header, TLS handshake or WebSocket upgrade failed
+    #[error("handshake failed: {0}")]
+    Handshake(#[source] anyhow::Error),
+    /// The PROXY header, TLS handshake or WebSocket upgrade exceeded `handshake_timeout`
+    #[error("handshake timed out")]
+    Timeout,
+    /// The call does not fit the listener type, e.g. `tls()` on a TCP acceptor
+    #[error("protocol mismatch: {0}")]
+    ProtocolMismatch(&'static str),
+}
+
+impl ListenerError {
+    /// Whether this is the error `Listener::accept()` returns after shutdown was requested
+    pub fn is_shutdown(&self) -> bool {
+        matches!(self, ListenerError::Shutdown)
+    }
+
+    /// Whether the WebSocket upgrade was an HTTP health check that has been answered
+    pub fn is_health_check(&self) -> bool {
+        matches!(self, ListenerError::Handshake(e) if MqttError::is_health_check(e))
+    }
+
+    /// Sorts a handshake failure into [`ListenerError::Timeout`] or [`ListenerError::Handshake`]
+    pub(crate) fn handshake(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<MqttError>() {
+            Some(MqttError::ReadTimeout) => ListenerError::Timeout,
+            _ => ListenerError::Handshake(e),
+        }
+    }
 }
 
 impl ToReasonCode for MqttError {
@@ -65,16 +167,25 @@ impl ToReasonCode for MqttError {
             MqttError::ReadTimeout
             | MqttError::WriteTimeout
             | MqttError::FlushTimeout
-            | MqttError::CloseTimeout => DisconnectReasonCode::KeepAliveTimeout,
+            | MqttError::CloseTimeout
+            | MqttError::IdleTimeout
+            | MqttError::KeepAliveTimeout => DisconnectReasonCode::KeepAliveTimeout,
             MqttError::PublishAckReason(_, _) => DisconnectReasonCode::ImplementationSpecificError,
+            MqttError::SessionTakenOver => DisconnectReasonCode::SessionTakenOver,
             MqttError::ServiceUnavailable => DisconnectReasonCode::ServerBusy,
             MqttError::InvalidProtocol => DisconnectReasonCode::ProtocolError,
             MqttError::TooManySubscriptions => DisconnectReasonCode::QuotaExceeded,
             MqttError::TooManyTopicLevels => DisconnectReasonCode::TopicNameInvalid,
             MqttError::SubscribeLimited(_) => DisconnectReasonCode::QuotaExceeded,
-            MqttError::IdentifierRejected => DisconnectReasonCode::NotAuthorized,
+            MqttError::IdentifierRejected | MqttError::AuthFailed => DisconnectReasonCode::NotAuthorized,
+            MqttError::TopicAliasInvalid(_) => DisconnectReasonCode::TopicAliasInvalid,
+            MqttError::UnknownTopicAlias(_) => DisconnectReasonCode::ProtocolError,
+            MqttError::SendQueueFull(_) => DisconnectReasonCode::QuotaExceeded,
             MqttError::PacketIdInUse(_) => DisconnectReasonCode::UnspecifiedError,
             MqttError::None => DisconnectReasonCode::UnspecifiedError,
+            MqttError::ShutdownRequested => DisconnectReasonCode::ServerShuttingDown,
+            MqttError::HealthCheck => DisconnectReasonCode::NormalDisconnection,
+            MqttError::InvalidProxyHeader(_) => DisconnectReasonCode::ProtocolError,
         }
     }
 }
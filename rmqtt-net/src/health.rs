@@ -0,0 +1,74 @@
+//! HTTP liveness checks served on the MQTT port
+//!
+//! An MQTT stream starts with a CONNECT fixed header (`0x10`), so a connection whose first bytes
+//! are `GET ` can only be HTTP. Those requests are answered with `200 OK` when they target
+//! `health_path` and `404 Not Found` otherwise, everything else is handed on untouched.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::Result;
+
+const GET: &[u8] = b"GET ";
+/// Upper bound for a health check request, orchestrators send a handful of headers at most
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+pub(crate) const OK_RESPONSE: &str =
+    "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nOK";
+const NOT_FOUND_RESPONSE: &str = "HTTP/1.1 404 Not Found\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// Outcome of [`probe`]
+pub(crate) enum Probe {
+    /// The request was a health check, it has been answered
+    Answered,
+    /// An HTTP request for some other path, answered with `404 Not Found`
+    NotFound,
+    /// Not a health check, the bytes read so far belong to the next layer
+    Passthrough(Vec<u8>),
+}
+
+/// Reads the start of `io` and answers it if it is a GET request
+pub(crate) async fn probe<S>(io: &mut S, path: &str) -> Result<Probe>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(256);
+    loop {
+        // Stops at the first byte that rules out a GET, so an MQTT client is never kept waiting
+        let prefix = &buf[..buf.len().min(GET.len())];
+        if !GET.starts_with(prefix) || has_header_end(&buf) || buf.len() >= MAX_REQUEST_LEN {
+            break;
+        }
+        if io.read_buf(&mut buf).await? == 0 {
+            break;
+        }
+    }
+
+    if !buf.starts_with(GET) || !has_header_end(&buf) {
+        return Ok(Probe::Passthrough(buf));
+    }
+    let target = buf[GET.len()..].split(|b| *b == b' ').next().and_then(|t| std::str::from_utf8(t).ok());
+    if !path_matches(target, path) {
+        respond(io, NOT_FOUND_RESPONSE).await;
+        return Ok(Probe::NotFound);
+    }
+
+    respond(io, OK_RESPONSE).await;
+    Ok(Probe::Answered)
+}
+
+/// Writes `response` and closes the stream, the connection is dropped either way
+pub(crate) async fn respond<S>(io: &mut S, response: &str)
+where
+    S: AsyncWrite + Unpin,
+{
+    let _ = io.write_all(response.as_bytes()).await;
+    let _ = io.shutdown().await;
+}
+
+/// Compares a request target to `path`, ignoring any query string
+pub(crate) fn path_matches(target: Option<&str>, path: &str) -> bool {
+    target.is_some_and(|target| target.split_once('?').map_or(target, |(p, _)| p) == path)
+}
+
+fn has_header_end(buf: &[u8]) -> bool {
+    buf.windows(4).any(|w| w == b"\r\n\r\n")
+}
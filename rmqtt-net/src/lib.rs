@@ -27,17 +27,65 @@
 //! ```
 
 mod builder;
+mod config;
 mod error;
+mod health;
+mod proxy;
+mod ratelimit;
+mod session;
+mod stats;
 mod stream;
+mod tls;
+#[cfg(feature = "ws")]
+mod ws;
 
 /// Server configuration and listener management
-pub use builder::{Builder, Listener, ListenerType};
+pub use builder::{AcceptFilter, BoundAddr, Builder, Listener, ListenerType, Preset, TlsVersion};
+
+/// Serde mirror of `Builder` for configuration files
+pub use config::BuilderConfig;
+
+/// Network type used by `Builder::allow_cidrs`/`Builder::deny_cidrs`
+pub use ipnet::IpNet;
+
+/// Client identifier registry behind session takeover
+pub use session::{ClientRegistry, Registration};
+
+/// Connection counters exposed by `Listener::stats()`
+pub use stats::{ActiveConnection, ListenerStats};
 
 /// Error types for MQTT operations
-pub use error::MqttError;
+pub use error::{ListenerError, MqttError};
 
 /// MQTT protocol implementations and stream handling
-pub use stream::{v3, v5, MqttStream};
+pub use stream::{
+    v3, v5, AnyIo, AuthFn, AuthHook, AuthOutcome, BoxedIo, DisconnectReason, IdleTimer, MqttStream,
+    PacketSink, PacketStream, SendWindow, SubscriptionIds, SubscriptionLimit, TopicAliases, TopicLevels,
+};
+
+/// Client certificate details returned by `Dispatcher::peer_cert_info()`
+pub use tls::PeerCertInfo;
+
+/// In-memory PEM data for `Builder::tls_cert_pem`/`Builder::tls_key_pem`
+pub use tls::PemBytes;
+
+/// PKCS#12 bundle for `Builder::tls_pkcs12`
+pub use tls::Pkcs12Bundle;
+
+/// Pre-built OpenSSL acceptor for `Builder::tls_acceptor`
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub use tls::CustomSslAcceptor;
+
+/// Name of the compiled-in TLS backend, for build information
+pub use tls::BACKEND as TLS_BACKEND;
+
+/// Stream type of the compiled-in TLS backend
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+pub use tls::TlsStream;
+
+/// Byte stream adapter over a WebSocket connection
+#[cfg(feature = "ws")]
+pub use ws::WsStream;
 
 /// Convenience type alias for generic errors
 pub type Error = anyhow::Error;
@@ -0,0 +1,295 @@
+//! PROXY protocol v1 and v2 support
+//!
+//! Load balancers that terminate the client TCP connection prepend a header announcing the
+//! original peer, either the v1 text line such as `PROXY TCP4 192.0.2.1 198.51.100.1 56324 1883\r\n`
+//! or the v2 binary form. Both are told apart by their first bytes and read without consuming
+//! anything past the header, which leaves the TLS, WebSocket or MQTT bytes that follow untouched
+//! for the next layer.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{MqttError, Result};
+
+/// Longest v1 header allowed by the specification, CRLF included
+const V1_MAX_LEN: usize = 107;
+/// Shortest possible v1 header, `PROXY UNKNOWN\r\n`
+///
+/// Reading this many bytes up front is always safe and is enough to tell both versions apart.
+const PREFIX_LEN: usize = 15;
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads the PROXY header at the start of `io`
+///
+/// Returns the announced source address, or `None` for `PROXY UNKNOWN` and v2 `LOCAL`
+/// headers in which case the transport peer address should be kept.
+pub(crate) async fn read_header<S>(io: &mut S, timeout: Duration) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    match tokio::time::timeout(timeout, detect(io)).await {
+        Ok(res) => res,
+        Err(_) => Err(MqttError::ReadTimeout.into()),
+    }
+}
+
+async fn detect<S>(io: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; PREFIX_LEN];
+    read_exact(io, &mut prefix).await?;
+
+    if prefix.starts_with(V2_SIGNATURE) {
+        let mut rest = [0u8; 1];
+        read_exact(io, &mut rest).await?;
+        let len = u16::from_be_bytes([prefix[14], rest[0]]) as usize;
+        let mut block = vec![0u8; len];
+        read_exact(io, &mut block).await?;
+        parse_v2(prefix[12], prefix[13], &block)
+    } else if prefix.starts_with(V1_SIGNATURE) {
+        read_v1(io, &prefix).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S>(io: &mut S, prefix: &[u8]) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::with_capacity(V1_MAX_LEN);
+    line.extend_from_slice(prefix);
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("header exceeds 107 bytes"));
+        }
+        let mut b = [0u8; 1];
+        read_exact(io, &mut b).await?;
+        line.push(b[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("header is not ASCII"))?;
+    parse_v1(line)
+}
+
+/// Parses a v1 header line without its trailing CRLF
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+
+    let is_v6 = match parts.next() {
+        Some("TCP4") => false,
+        Some("TCP6") => true,
+        // Anything may follow UNKNOWN and receivers must ignore it
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unsupported transport protocol")),
+    };
+
+    let (Some(src), Some(_dst), Some(sport), Some(_dport), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid("expected source and destination addresses and ports"));
+    };
+
+    let src: IpAddr = src.parse().map_err(|_| invalid("invalid source address"))?;
+    if src.is_ipv6() != is_v6 {
+        return Err(invalid("source address does not match the transport protocol"));
+    }
+    // Ports are plain decimal, a leading zero or sign is not allowed
+    if sport.starts_with(['0', '+']) && sport != "0" {
+        return Err(invalid("invalid source port"));
+    }
+    let sport: u16 = sport.parse().map_err(|_| invalid("invalid source port"))?;
+
+    Ok(Some(SocketAddr::new(src, sport)))
+}
+
+/// Parses the v2 version/command and family bytes and the address block that follows
+fn parse_v2(ver_cmd: u8, family: u8, block: &[u8]) -> Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match ver_cmd & 0x0F {
+        // LOCAL is used by the proxy for its own health checks
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+
+    // Only TCP makes sense in front of MQTT, UNSPEC means the addresses are to be ignored
+    let src = match family {
+        0x00 => return Ok(None),
+        0x11 if block.len() >= 12 => {
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([block[8], block[9]]))
+        }
+        0x21 if block.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&block[..16]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([block[32], block[33]]))
+        }
+        0x11 | 0x21 => return Err(invalid("address block is too short")),
+        _ => return Err(invalid("unsupported transport protocol")),
+    };
+    // Any TLVs after the addresses are skipped
+    Ok(Some(src))
+}
+
+async fn read_exact<S>(io: &mut S, buf: &mut [u8]) -> Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    match io.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(invalid("connection closed before the header was complete"))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn invalid(reason: &str) -> anyhow::Error {
+    MqttError::InvalidProxyHeader(reason.into()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    /// The MQTT bytes following the header, a v3.1.1 PINGREQ
+    const PAYLOAD: &[u8] = &[0xC0, 0x00];
+
+    /// A socket holding `header` followed by [`PAYLOAD`]
+    async fn socket(header: &[u8]) -> DuplexStream {
+        let (mut client, server) = duplex(1024);
+        client.write_all(header).await.unwrap();
+        client.write_all(PAYLOAD).await.unwrap();
+        server
+    }
+
+    /// A v2 PROXY header with the given version/command and family bytes
+    fn v2(ver_cmd: u8, family: u8, block: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([ver_cmd, family]);
+        header.extend((block.len() as u16).to_be_bytes());
+        header.extend(block);
+        header
+    }
+
+    async fn read(header: &[u8]) -> Result<Option<SocketAddr>> {
+        let mut io = socket(header).await;
+        let res = read_header(&mut io, TIMEOUT).await;
+        if res.is_ok() {
+            // Nothing past the header is consumed
+            let mut rest = [0u8; 2];
+            io.read_exact(&mut rest).await.unwrap();
+            assert_eq!(rest, PAYLOAD);
+        }
+        res
+    }
+
+    fn reason(res: Result<Option<SocketAddr>>) -> String {
+        match res.unwrap_err().downcast::<MqttError>() {
+            Ok(MqttError::InvalidProxyHeader(reason)) => reason,
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4_and_tcp6() {
+        let addr = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 1883\r\n").await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        let addr = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 1883\r\n").await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_keeps_the_peer() {
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(read(b"PROXY UNKNOWN ignored 1 2\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        let header = b"PROXY TCP4 2001:db8::1 192.0.2.2 4000 1883\r\n";
+        assert_eq!(reason(read(header).await), "source address does not match the transport protocol");
+        assert_eq!(
+            reason(read(b"PROXY TCP4 192.0.2.1 192.0.2.2 04000 1883\r\n").await),
+            "invalid source port"
+        );
+        assert_eq!(
+            reason(read(b"PROXY UDP4 192.0.2.1 192.0.2.2 1 2\r\n").await),
+            "unsupported transport protocol"
+        );
+        let long = [b"PROXY UNKNOWN ".as_slice(), &[b'x'; 100], b"\r\n"].concat();
+        assert_eq!(reason(read(&long).await), "header exceeds 107 bytes");
+        assert_eq!(reason(read(b"GET / HTTP/1.1\r\n\r\n").await), "missing PROXY protocol header");
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4() {
+        let mut block = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        block.extend(56324u16.to_be_bytes());
+        block.extend(1883u16.to_be_bytes());
+        let addr = read(&v2(0x21, 0x11, &block)).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_tcp6_skips_tlvs() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut block = [src.octets(), dst.octets()].concat();
+        block.extend(4000u16.to_be_bytes());
+        block.extend(1883u16.to_be_bytes());
+        // PP2_TYPE_AUTHORITY TLV
+        block.extend([0x02, 0x00, 0x04, b'h', b'o', b's', b't']);
+        let addr = read(&v2(0x21, 0x21, &block)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unspec_keep_the_peer() {
+        // LOCAL ignores the family and addresses
+        assert_eq!(read(&v2(0x20, 0x11, &[0; 12])).await.unwrap(), None);
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(read(&v2(0x21, 0x00, &[])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_malformed() {
+        assert_eq!(reason(read(&v2(0x21, 0x11, &[0; 11])).await), "address block is too short");
+        assert_eq!(reason(read(&v2(0x21, 0x21, &[0; 35])).await), "address block is too short");
+        assert_eq!(reason(read(&v2(0x11, 0x11, &[0; 12])).await), "unsupported version");
+        assert_eq!(reason(read(&v2(0x22, 0x11, &[0; 12])).await), "unsupported command");
+        assert_eq!(reason(read(&v2(0x21, 0x12, &[0; 12])).await), "unsupported transport protocol");
+    }
+
+    #[tokio::test]
+    async fn truncated_header() {
+        let (mut client, mut server) = duplex(1024);
+        let mut header = v2(0x21, 0x11, &[0; 12]);
+        header.truncate(20);
+        client.write_all(&header).await.unwrap();
+        drop(client);
+        let res = read_header(&mut server, TIMEOUT).await;
+        assert_eq!(reason(res), "connection closed before the header was complete");
+    }
+
+    #[tokio::test]
+    async fn stalled_header_times_out() {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(b"PROXY TCP4 192.0.2.1").await.unwrap();
+        let res = read_header(&mut server, Duration::from_millis(20)).await;
+        assert!(matches!(res.unwrap_err().downcast::<MqttError>(), Ok(MqttError::ReadTimeout)));
+    }
+}
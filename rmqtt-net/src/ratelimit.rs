@@ -0,0 +1,127 @@
+//! Per-IP connection rate limiting for `Listener::accept()`
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Sliding one-second window of recent connection attempts per client IP
+///
+/// Each IP keeps at most `limit` timestamps, and IPs without an attempt in the last window are
+/// swept out at most once per window, so memory stays bounded by the set of recently active
+/// clients.
+#[derive(Debug)]
+pub(crate) struct ConnRateLimiter {
+    limit: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+    last_sweep: Instant,
+}
+
+impl ConnRateLimiter {
+    pub(crate) fn new(limit: NonZeroU32) -> Self {
+        Self {
+            limit: limit.get() as usize,
+            state: Mutex::new(State { attempts: HashMap::new(), last_sweep: Instant::now() }),
+        }
+    }
+
+    /// Records an attempt from `ip`, returns false if it is over the limit
+    ///
+    /// Rejected attempts are not recorded, so a client that backs off gets through again one
+    /// window after its last accepted connection.
+    pub(crate) fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if now.duration_since(state.last_sweep) >= WINDOW {
+            state.attempts.retain(|_, times| times.back().is_some_and(|t| now.duration_since(*t) < WINDOW));
+            state.last_sweep = now;
+        }
+
+        let times = state.attempts.entry(ip).or_default();
+        while times.front().is_some_and(|t| now.duration_since(*t) >= WINDOW) {
+            times.pop_front();
+        }
+        if times.len() >= self.limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nonzero_ext::nonzero;
+
+    use super::*;
+
+    const A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn limit_applies_per_ip() {
+        let limiter = ConnRateLimiter::new(nonzero!(2u32));
+        let start = Instant::now();
+        assert!(limiter.check_at(A, start));
+        assert!(limiter.check_at(A, start + ms(10)));
+        assert!(!limiter.check_at(A, start + ms(20)));
+        assert!(limiter.check_at(B, start + ms(20)));
+    }
+
+    #[test]
+    fn window_slides_from_the_oldest_attempt() {
+        let limiter = ConnRateLimiter::new(nonzero!(2u32));
+        let start = Instant::now();
+        assert!(limiter.check_at(A, start));
+        assert!(limiter.check_at(A, start + ms(500)));
+        assert!(!limiter.check_at(A, start + ms(999)));
+        // The first attempt left the window, the second is still in it
+        assert!(limiter.check_at(A, start + ms(1000)));
+        assert!(!limiter.check_at(A, start + ms(1499)));
+        assert!(limiter.check_at(A, start + ms(1500)));
+    }
+
+    #[test]
+    fn rejected_attempts_are_not_recorded() {
+        let limiter = ConnRateLimiter::new(nonzero!(1u32));
+        let start = Instant::now();
+        assert!(limiter.check_at(A, start));
+        for i in 1..10 {
+            assert!(!limiter.check_at(A, start + ms(i * 100)));
+        }
+        assert!(limiter.check_at(A, start + ms(1000)));
+    }
+
+    #[test]
+    fn idle_ips_are_swept() {
+        let limiter = ConnRateLimiter::new(nonzero!(1u32));
+        let start = Instant::now();
+        limiter.state.lock().unwrap().last_sweep = start + ms(500);
+        assert!(limiter.check_at(A, start));
+        assert!(limiter.check_at(B, start + ms(700)));
+        // No sweep within a window of the last one, A stays although it left the window
+        assert!(!limiter.check_at(B, start + ms(1050)));
+        assert_eq!(limiter.state.lock().unwrap().attempts.len(), 2);
+        // The next sweep drops A, idle for more than a window, and keeps B
+        assert!(!limiter.check_at(B, start + ms(1600)));
+        let state = limiter.state.lock().unwrap();
+        assert!(!state.attempts.contains_key(&A));
+        assert_eq!(state.attempts[&B].len(), 1);
+    }
+}
@@ -0,0 +1,153 @@
+//! Session takeover between connections sharing a client identifier
+//!
+//! A CONNECT with a client identifier that is already connected must end the older
+//! connection. Streams reserve the identifier of the CONNECT they read and claim it in the
+//! [`ClientRegistry`] of their builder once they send a successful CONNACK, so a CONNECT the
+//! broker refuses never ends the connection holding the identifier. Claiming signals the
+//! previous holder. That stream then
+//! sends DISCONNECT with Session Taken Over (0x8E) to v5 clients, closes the socket and yields
+//! [`crate::MqttError::SessionTakenOver`]. Its per-connection state, topic aliases, send window
+//! and the like, goes away with it, anything outliving the connection belongs to the broker.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytestring::ByteString;
+use futures::task::AtomicWaker;
+
+/// Client identifiers with a live connection
+///
+/// Clones share the same table, so builders given the same registry take over each other's
+/// connections.
+///
+/// ```
+/// use rmqtt_net::ClientRegistry;
+///
+/// let registry = ClientRegistry::default();
+/// let first = registry.register("sensor-1".into());
+/// let second = registry.register("sensor-1".into());
+/// assert!(first.is_taken_over());
+/// assert!(!second.is_taken_over());
+///
+/// // The old connection going away leaves the new one registered
+/// drop(first);
+/// assert!(registry.contains("sensor-1"));
+/// drop(second);
+/// assert!(!registry.contains("sensor-1"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ClientRegistry {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    clients: Mutex<HashMap<ByteString, Arc<Takeover>>>,
+    next_id: AtomicU64,
+}
+
+/// Signal raised on the older connection when its client identifier connects again
+#[derive(Debug)]
+struct Takeover {
+    id: u64,
+    taken: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl ClientRegistry {
+    /// Registers `client_id` for a new connection, taking it over from any existing one
+    pub fn register(&self, client_id: ByteString) -> Registration {
+        let mut registration = self.reserve(client_id);
+        registration.claim();
+        registration
+    }
+
+    /// A registration for `client_id` that leaves any existing connection alone until claimed
+    pub(crate) fn reserve(&self, client_id: ByteString) -> Registration {
+        let takeover = Arc::new(Takeover {
+            id: self.inner.next_id.fetch_add(1, Ordering::Relaxed),
+            taken: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        Registration { registry: self.clone(), client_id, takeover, claimed: false, reported: false }
+    }
+
+    /// Whether `client_id` has a live connection
+    pub fn contains(&self, client_id: &str) -> bool {
+        self.clients().contains_key(client_id)
+    }
+
+    fn clients(&self) -> std::sync::MutexGuard<'_, HashMap<ByteString, Arc<Takeover>>> {
+        self.inner.clients.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A connection's claim on its client identifier, released when dropped
+#[derive(Debug)]
+pub struct Registration {
+    registry: ClientRegistry,
+    client_id: ByteString,
+    takeover: Arc<Takeover>,
+    /// Entered in the registry, taking over the previous holder
+    claimed: bool,
+    /// The stream already yielded its takeover error
+    reported: bool,
+}
+
+impl Registration {
+    /// The registered client identifier
+    pub fn client_id(&self) -> &ByteString {
+        &self.client_id
+    }
+
+    /// Whether a newer connection registered the same client identifier
+    pub fn is_taken_over(&self) -> bool {
+        self.takeover.taken.load(Ordering::Acquire)
+    }
+
+    /// Enters the reservation in the registry and signals the previous holder, once
+    pub(crate) fn claim(&mut self) {
+        if self.claimed {
+            return;
+        }
+        self.claimed = true;
+        let previous = self.registry.clients().insert(self.client_id.clone(), self.takeover.clone());
+        if let Some(previous) = previous {
+            previous.taken.store(true, Ordering::Release);
+            previous.waker.wake();
+        }
+    }
+
+    /// Whether the stream already reported the takeover and has to end
+    pub(crate) fn reported(&self) -> bool {
+        self.reported
+    }
+
+    /// Ready once, when the connection has been taken over
+    pub(crate) fn poll_taken_over(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_taken_over() {
+            self.takeover.waker.register(cx.waker());
+            // Checked again, a takeover between the first check and the registration would be lost
+            if !self.is_taken_over() {
+                return Poll::Pending;
+            }
+        }
+        if self.reported {
+            return Poll::Pending;
+        }
+        self.reported = true;
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut clients = self.registry.clients();
+        // A newer connection owns the entry after a takeover, it is not ours to remove
+        if clients.get(&self.client_id).is_some_and(|t| t.id == self.takeover.id) {
+            clients.remove(&self.client_id);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{Notify, OwnedSemaphorePermit};
+
+/// Point-in-time copy of a listener's connection counters
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ListenerStats {
+    /// Connections accepted since the listener was bound
+    pub accepted: u64,
+    /// Accepted connections that have not been dropped yet
+    pub active: u64,
+    /// TLS/WebSocket handshakes that failed or timed out
+    pub handshake_failures: u64,
+    /// Errors returned by the underlying `accept()` call
+    pub accept_errors: u64,
+    /// Connections closed right away because `max_connections` was reached
+    pub rejected: u64,
+    /// Connections closed right away by the `allow_cidrs`/`deny_cidrs` filter
+    pub denied: u64,
+    /// Connections closed right away because their IP exceeded `max_conns_per_ip_per_sec`
+    pub rate_limited: u64,
+    /// Connections closed right away because the `on_accept` callback refused them
+    pub filtered: u64,
+}
+
+/// Counters shared by a listener and every connection it accepted
+#[derive(Debug, Default)]
+pub(crate) struct ListenerCounters {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    handshake_failures: AtomicU64,
+    accept_errors: AtomicU64,
+    rejected: AtomicU64,
+    denied: AtomicU64,
+    rate_limited: AtomicU64,
+    filtered: AtomicU64,
+    /// Woken whenever `active` drops to zero
+    idle: Notify,
+}
+
+impl ListenerCounters {
+    pub(crate) fn snapshot(&self) -> ListenerStats {
+        ListenerStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            handshake_failures: self.handshake_failures.load(Ordering::Relaxed),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            filtered: self.filtered.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn active(&self) -> u64 {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Resolves once no accepted connection is alive anymore
+    pub(crate) async fn wait_idle(&self) {
+        loop {
+            // Registered before the check, so a drop in between cannot be missed
+            let notified = self.idle.notified();
+            if self.active() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub(crate) fn accept_error(&self) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn denied(&self) {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn filtered(&self) {
+        self.filtered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a new connection, which stays active until the returned guard is dropped
+    ///
+    /// The guard also holds the connection's `max_connections` slot.
+    pub(crate) fn accepted(self: &Arc<Self>, permit: Option<OwnedSemaphorePermit>) -> ActiveConnection {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::AcqRel);
+        ActiveConnection { counters: Some(self.clone()), _permit: permit }
+    }
+}
+
+/// Keeps a connection in its listener's `active` count for as long as it is alive
+///
+/// It travels from the `Acceptor` into the `Dispatcher` and then the MQTT stream. A default
+/// guard is not attached to any listener, for streams built by hand.
+#[derive(Debug, Default)]
+pub struct ActiveConnection {
+    counters: Option<Arc<ListenerCounters>>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ActiveConnection {
+    pub(crate) fn handshake_failed(&self) {
+        if let Some(counters) = &self.counters {
+            counters.handshake_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        if let Some(counters) = &self.counters {
+            if counters.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+                counters.idle.notify_waiters();
+            }
+        }
+    }
+}
@@ -1,21 +1,50 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::num::{NonZeroU16, NonZeroU32};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 use anyhow::anyhow;
-use futures::SinkExt;
+use bytestring::ByteString;
+use futures::stream::{SplitSink, SplitStream};
 use futures::StreamExt;
+use futures::{Sink, SinkExt};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::Framed;
+use tokio::time::{Instant, Sleep};
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, FramedParts};
 
 use rmqtt_codec::error::{DecodeError, SendPacketError};
-use rmqtt_codec::v3::Codec as CodecV3;
-use rmqtt_codec::v5::Codec as CodecV5;
+use rmqtt_codec::types::Publish;
+use rmqtt_codec::v3::{Codec as CodecV3, Packet as PacketV3};
+use rmqtt_codec::v5::{
+    Codec as CodecV5, Disconnect, DisconnectReasonCode, Packet as PacketV5, Subscribe, ToReasonCode,
+    Unsubscribe,
+};
 use rmqtt_codec::version::{ProtocolVersion, VersionCodec};
 use rmqtt_codec::{MqttCodec, MqttPacket};
 
 use crate::error::MqttError;
-use crate::{Builder, Result};
+use crate::health::Probe;
+use crate::{ActiveConnection, Builder, PeerCertInfo, Registration, Result};
+
+/// Transport of any listener type, so one handler can take TCP, TLS and WebSocket connections
+pub trait AnyIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AnyIo for T {}
+
+/// Type-erased transport handed out by `Listener::serve`
+pub type BoxedIo = Box<dyn AnyIo>;
+
+/// Read half of [`Dispatcher::into_packet_stream`]
+pub type PacketStream<Io> = SplitStream<MqttStream<Io>>;
+
+/// Write half of [`Dispatcher::into_packet_stream`]
+pub type PacketSink<Io> = SplitSink<MqttStream<Io>, MqttPacket>;
 
 /// MQTT protocol dispatcher handling version negotiation
 ///
@@ -27,6 +56,18 @@ pub struct Dispatcher<Io> {
     pub remote_addr: SocketAddr,
     /// Shared configuration builder
     pub cfg: Arc<Builder>,
+    /// Protocol agreed on through TLS ALPN
+    pub(crate) alpn: Option<String>,
+    /// Client certificate presented during the TLS handshake
+    pub(crate) peer_cert: Option<PeerCertInfo>,
+    /// Keeps the connection in the listener's active count
+    pub(crate) active: ActiveConnection,
+    /// A PROXY protocol header still has to be read off the stream
+    pub(crate) proxy_pending: bool,
+    /// HTTP health checks are served on this stream, WebSocket answers them during the upgrade
+    pub(crate) health_check: bool,
+    /// Runs the MQTT v5 enhanced authentication exchange, see [`Dispatcher::on_auth`]
+    pub(crate) on_auth: Option<AuthHook>,
 }
 
 impl<Io> Dispatcher<Io>
@@ -34,20 +75,188 @@ where
     Io: AsyncRead + AsyncWrite + Unpin,
 {
     /// Creates a new Dispatcher instance
-    pub(crate) fn new(io: Io, remote_addr: SocketAddr, cfg: Arc<Builder>) -> Self {
-        Dispatcher { io: Framed::new(io, MqttCodec::Version(VersionCodec)), remote_addr, cfg }
+    pub(crate) fn new(io: Io, remote_addr: SocketAddr, cfg: Arc<Builder>, active: ActiveConnection) -> Self {
+        Dispatcher {
+            io: Framed::new(io, MqttCodec::Version(VersionCodec)),
+            remote_addr,
+            cfg,
+            alpn: None,
+            peer_cert: None,
+            active,
+            proxy_pending: false,
+            health_check: false,
+            on_auth: None,
+        }
+    }
+
+    /// Erases the transport type, keeping anything already buffered by the codec
+    pub fn boxed(self) -> Dispatcher<BoxedIo>
+    where
+        Io: Send + 'static,
+    {
+        let parts = self.io.into_parts();
+        let mut boxed = FramedParts::new::<MqttPacket>(Box::new(parts.io) as BoxedIo, parts.codec);
+        boxed.read_buf = parts.read_buf;
+        boxed.write_buf = parts.write_buf;
+        Dispatcher {
+            io: Framed::from_parts(boxed),
+            remote_addr: self.remote_addr,
+            cfg: self.cfg,
+            alpn: self.alpn,
+            peer_cert: self.peer_cert,
+            active: self.active,
+            proxy_pending: self.proxy_pending,
+            health_check: self.health_check,
+            on_auth: self.on_auth,
+        }
+    }
+
+    /// Lets [`Dispatcher::mqtt`] answer `health_path` requests before probing for MQTT
+    pub(crate) fn health_check(mut self, health_check: bool) -> Self {
+        self.health_check = health_check;
+        self
+    }
+
+    /// Defers reading the PROXY protocol header to [`Dispatcher::mqtt`]
+    pub(crate) fn proxy_pending(mut self, proxy_pending: bool) -> Self {
+        self.proxy_pending = proxy_pending;
+        self
+    }
+
+    /// Records the protocol negotiated by the TLS layer
+    #[allow(dead_code)]
+    pub(crate) fn alpn(mut self, alpn: Option<String>) -> Self {
+        self.alpn = alpn;
+        self
+    }
+
+    /// Records the client certificate seen by the TLS layer
+    #[allow(dead_code)]
+    pub(crate) fn peer_cert(mut self, peer_cert: Option<PeerCertInfo>) -> Self {
+        self.peer_cert = peer_cert;
+        self
+    }
+
+    /// Handles MQTT v5 enhanced authentication for this connection
+    ///
+    /// `hook` gets the authentication method and the data of each step, first from CONNECT and
+    /// then from every AUTH the client answers with. Being called per connection, it can keep the
+    /// state of a challenge/response exchange. A CONNECT without an authentication method never
+    /// reaches it, and without a hook the method is left for the caller of
+    /// [`v5::MqttStream::recv_connect`] to refuse.
+    pub fn on_auth(mut self, hook: AuthFn) -> Self {
+        self.on_auth = Some(AuthHook { hook, completed: None });
+        self
+    }
+
+    /// Returns the client certificate of a TLS connection, if the client sent one
+    ///
+    /// Only present when `tls_cross_certificate` is enabled, otherwise no certificate is
+    /// requested from the client.
+    #[inline]
+    pub fn peer_cert_info(&self) -> Option<&PeerCertInfo> {
+        self.peer_cert.as_ref()
+    }
+
+    /// Returns the ALPN protocol negotiated during the TLS handshake, if any
+    #[inline]
+    pub fn negotiated_alpn(&self) -> Option<&str> {
+        self.alpn.as_deref()
+    }
+
+    /// Negotiates the protocol like [`Dispatcher::mqtt`] and splits the connection into packets
+    ///
+    /// The read half yields the decoded v3 or v5 packets of the client and the write half takes
+    /// packets of the same version, for proxies and test tooling working at the packet level. See
+    /// the `Stream` and `Sink` impls of [`MqttStream`] for what applies to each direction.
+    ///
+    /// ```no_run
+    /// use futures::{SinkExt, StreamExt};
+    /// use rmqtt_codec::{v3, v5, MqttPacket};
+    /// use rmqtt_net::Builder;
+    ///
+    /// # async fn example() -> rmqtt_net::Result<()> {
+    /// let listener = Builder::new().laddr("127.0.0.1:1883".parse()?).bind()?;
+    /// let (mut packets, mut sink) = listener.accept().await?.tcp()?.into_packet_stream().await?;
+    /// // Answers pings and ignores everything else
+    /// while let Some(packet) = packets.next().await {
+    ///     let pong = match packet? {
+    ///         MqttPacket::V3(v3::Packet::PingRequest) => MqttPacket::V3(v3::Packet::PingResponse),
+    ///         MqttPacket::V5(v5::Packet::PingRequest) => MqttPacket::V5(v5::Packet::PingResponse),
+    ///         _ => continue,
+    ///     };
+    ///     sink.send(pong).await?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn into_packet_stream(self) -> Result<(PacketStream<Io>, PacketSink<Io>)> {
+        let (sink, stream) = self.mqtt().await?.split();
+        Ok((stream, sink))
     }
 
     /// Negotiates protocol version and returns appropriate stream
+    ///
+    /// On plain TCP listeners with `proxy_protocol` enabled the PROXY header is consumed first and
+    /// `remote_addr` is replaced with the client address it announces. Fails with
+    /// [`MqttError::ReadTimeout`] if the start of CONNECT does not arrive within `handshake_timeout`.
     #[inline]
     pub async fn mqtt(mut self) -> Result<MqttStream<Io>> {
-        Ok(match self.probe_version().await? {
-            ProtocolVersion::MQTT3 => {
-                MqttStream::V3(v3::MqttStream { io: self.io, remote_addr: self.remote_addr, cfg: self.cfg })
+        if self.proxy_pending {
+            // Nothing has been polled through the codec yet, so its read buffer is still empty
+            match crate::proxy::read_header(self.io.get_mut(), self.cfg.handshake_timeout).await {
+                Ok(Some(addr)) => self.remote_addr = addr,
+                Ok(None) => {}
+                Err(e) => {
+                    self.active.handshake_failed();
+                    return Err(e);
+                }
             }
-            ProtocolVersion::MQTT5 => {
-                MqttStream::V5(v5::MqttStream { io: self.io, remote_addr: self.remote_addr, cfg: self.cfg })
+            self.proxy_pending = false;
+        }
+        if let (true, Some(path)) = (self.health_check, self.cfg.health_path.as_deref()) {
+            let probe = crate::health::probe(self.io.get_mut(), path);
+            match tokio::time::timeout(self.cfg.handshake_timeout, probe).await {
+                Ok(Ok(Probe::Answered)) => return Err(MqttError::HealthCheck.into()),
+                Ok(Ok(Probe::NotFound)) => return Err(MqttError::InvalidProtocol.into()),
+                Ok(Ok(Probe::Passthrough(read))) => self.io.read_buffer_mut().extend_from_slice(&read),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(MqttError::ReadTimeout.into()),
             }
+        }
+        // Slow-loris protection, a client that connects but never sends CONNECT is dropped
+        let ver = match tokio::time::timeout(self.cfg.handshake_timeout, self.probe_version()).await {
+            Ok(ver) => ver?,
+            Err(_) => return Err(MqttError::ReadTimeout.into()),
+        };
+        let max_subscriptions = if self.cfg.limit_subscription { self.cfg.max_subscriptions } else { 0 };
+        Ok(match ver {
+            ProtocolVersion::MQTT3 => MqttStream::V3(v3::MqttStream {
+                io: self.io,
+                remote_addr: self.remote_addr,
+                idle: IdleTimer::new(self.cfg.idle_timeout),
+                keepalive: IdleTimer::default(),
+                session: None,
+                topic_levels: TopicLevels::new(self.cfg.max_topic_levels),
+                subscription_limit: SubscriptionLimit::new(max_subscriptions),
+                cfg: self.cfg,
+                active: self.active,
+            }),
+            ProtocolVersion::MQTT5 => MqttStream::V5(v5::MqttStream {
+                io: self.io,
+                remote_addr: self.remote_addr,
+                idle: IdleTimer::new(self.cfg.idle_timeout),
+                keepalive: IdleTimer::default(),
+                session: None,
+                topic_levels: TopicLevels::new(self.cfg.max_topic_levels),
+                subscription_limit: SubscriptionLimit::new(max_subscriptions),
+                topic_aliases: TopicAliases::new(self.cfg.max_topic_aliases),
+                subscription_ids: SubscriptionIds::default(),
+                send_window: SendWindow::default(),
+                auth: self.on_auth,
+                cfg: self.cfg,
+                active: self.active,
+            }),
         })
     }
 
@@ -70,7 +279,734 @@ where
     }
 }
 
+/// Deadline that is pushed back every time a packet arrives
+///
+/// The default never expires. Once it fires the stream yields a single
+/// [`MqttError::IdleTimeout`] and then ends.
+#[derive(Default)]
+pub struct IdleTimer {
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    expired: bool,
+}
+
+impl IdleTimer {
+    pub(crate) fn new(timeout: Option<Duration>) -> Self {
+        IdleTimer { timeout, sleep: timeout.map(|t| Box::pin(tokio::time::sleep(t))), expired: false }
+    }
+
+    /// Restarts the timer with a new timeout
+    pub(crate) fn arm(&mut self, timeout: Option<Duration>) {
+        *self = IdleTimer::new(timeout);
+    }
+
+    /// Whether the timeout already fired
+    pub fn expired(&self) -> bool {
+        self.expired
+    }
+
+    fn reset(&mut self) {
+        if let (Some(timeout), Some(sleep)) = (self.timeout, self.sleep.as_mut()) {
+            sleep.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    /// Ready once the timeout elapsed without a [`IdleTimer::reset`]
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(sleep) = self.sleep.as_mut() else {
+            return Poll::Pending;
+        };
+        futures::ready!(sleep.as_mut().poll(cx));
+        self.sleep = None;
+        self.expired = true;
+        Poll::Ready(())
+    }
+}
+
+/// Inbound MQTT v5 topic aliases of one connection
+///
+/// A PUBLISH with both a topic and an alias maps the alias, one with only an alias gets the
+/// mapped topic filled in. Aliases are bounded by `max`, so the table never holds more than
+/// `max` topics. Once a PUBLISH is rejected the table is marked failed and the stream ends.
+/// A zero alias never gets here: the codec fails to decode the PUBLISH, so the stream ends with
+/// a Malformed Packet (0x81) DISCONNECT rather than Topic Alias invalid (0x94).
+#[derive(Debug, Default)]
+pub struct TopicAliases {
+    max: u16,
+    topics: HashMap<NonZeroU16, ByteString>,
+    failed: bool,
+}
+
+impl TopicAliases {
+    /// Creates an empty table accepting aliases up to `max`, zero disables aliases
+    pub fn new(max: u16) -> Self {
+        TopicAliases { max, topics: HashMap::new(), failed: false }
+    }
+
+    /// Highest alias accepted, the Topic Alias Maximum advertised in CONNACK
+    pub fn max(&self) -> u16 {
+        self.max
+    }
+
+    /// Whether a PUBLISH was rejected, the connection is being closed
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    pub(crate) fn set_max(&mut self, max: u16) {
+        self.max = max;
+        self.topics.retain(|alias, _| alias.get() <= max);
+    }
+
+    /// Maps or resolves the alias of `publish`, if it carries one
+    pub fn resolve(&mut self, publish: &mut Publish) -> std::result::Result<(), MqttError> {
+        let Some(alias) = publish.properties.as_ref().and_then(|p| p.topic_alias) else {
+            return Ok(());
+        };
+        let res = if alias.get() > self.max {
+            Err(MqttError::TopicAliasInvalid(alias.get()))
+        } else if publish.topic.is_empty() {
+            match self.topics.get(&alias) {
+                Some(topic) => {
+                    publish.topic = topic.clone();
+                    Ok(())
+                }
+                None => Err(MqttError::UnknownTopicAlias(alias.get())),
+            }
+        } else {
+            self.topics.insert(alias, publish.topic.clone());
+            Ok(())
+        };
+        self.failed |= res.is_err();
+        res
+    }
+}
+
+/// MQTT v5 subscription identifiers of one connection, by topic filter
+///
+/// Filled from the SUBSCRIBE and UNSUBSCRIBE packets passing through the stream, so the
+/// identifiers can be attached to outgoing PUBLISH packets. A zero identifier never gets here:
+/// the codec fails to decode the SUBSCRIBE and the stream ends with a Malformed Packet
+/// DISCONNECT. MQTT v3 has no way to carry one.
+#[derive(Debug, Default)]
+pub struct SubscriptionIds {
+    ids: HashMap<ByteString, NonZeroU32>,
+}
+
+impl SubscriptionIds {
+    /// Records the identifier of `subscribe` for each of its filters
+    ///
+    /// Subscribing again without an identifier drops the one recorded for that filter, as the
+    /// new subscription replaces the old one.
+    pub fn subscribe(&mut self, subscribe: &Subscribe) {
+        for (filter, _) in &subscribe.topic_filters {
+            match subscribe.id {
+                Some(id) => self.ids.insert(filter.clone(), id),
+                None => self.ids.remove(filter),
+            };
+        }
+    }
+
+    /// Forgets the identifiers of the filters in `unsubscribe`
+    pub fn unsubscribe(&mut self, unsubscribe: &Unsubscribe) {
+        for filter in &unsubscribe.topic_filters {
+            self.ids.remove(filter);
+        }
+    }
+
+    /// Identifiers of all subscriptions matching `topic`, sorted and without duplicates
+    pub fn matching(&self, topic: &str) -> Vec<NonZeroU32> {
+        let mut ids: Vec<_> =
+            self.ids.iter().filter(|(filter, _)| topic_matches(filter, topic)).map(|(_, id)| *id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
+
+/// Topic depth limit of one connection, from `max_topic_levels`
+///
+/// Levels are the `/`-separated parts of a topic name or filter, wildcards included, so
+/// `a/+/#` has three. The `$share/<group>/` prefix of a shared subscription is not counted. A
+/// PUBLISH over the limit ends the stream. SUBSCRIBE filters over it are taken out before the
+/// packet is yielded, and get a failure code in the SUBACK the broker sends for it.
+///
+/// ```
+/// use rmqtt_net::TopicLevels;
+///
+/// let levels = TopicLevels::new(3);
+/// assert!(levels.allows("a/b/c"));
+/// assert!(!levels.allows("a/b/c/d"));
+/// assert!(levels.allows("a/+/#"));
+/// assert!(!levels.allows("a/+/+/#"));
+/// assert!(levels.allows("$share/group/a/b/c"));
+/// assert!(!levels.allows("a/b/c/"));
+///
+/// // Zero places no limit
+/// assert!(TopicLevels::new(0).allows("a/b/c/d/e/f"));
+/// ```
+#[derive(Debug, Default)]
+pub struct TopicLevels {
+    max: usize,
+    refused: RefusedFilters,
+    failed: bool,
+}
+
+impl TopicLevels {
+    /// Creates a limit of `max` levels, zero allows any depth
+    pub fn new(max: usize) -> Self {
+        TopicLevels { max, refused: RefusedFilters::default(), failed: false }
+    }
+
+    /// Highest number of levels accepted, zero if unlimited
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Whether a PUBLISH was rejected, the connection is being closed
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Whether the topic name or filter `topic` is within the limit
+    pub fn allows(&self, topic: &str) -> bool {
+        within_levels(self.max, topic)
+    }
+
+    /// Refuses a PUBLISH to `topic` when it is too deep, the stream ends after that
+    pub(crate) fn check_publish(&mut self, topic: &str) -> std::result::Result<(), MqttError> {
+        if self.allows(topic) {
+            return Ok(());
+        }
+        self.failed = true;
+        Err(MqttError::TooManyTopicLevels)
+    }
+
+    /// Takes the filters over the limit out of SUBSCRIBE `packet_id`, they are refused in its SUBACK
+    pub(crate) fn strip<T>(&mut self, packet_id: NonZeroU16, filters: &mut Vec<(ByteString, T)>) {
+        let max = self.max;
+        self.refused.take(packet_id, filters, |filter| within_levels(max, filter));
+    }
+
+    /// Puts `failure` into the SUBACK `packet_id` wherever a filter was taken out
+    pub(crate) fn restore<T: Clone>(&mut self, packet_id: NonZeroU16, status: &mut Vec<T>, failure: T) {
+        self.refused.restore(packet_id, status, failure);
+    }
+}
+
+/// Subscriptions of one connection, counted against `max_subscriptions`
+///
+/// Only applies with `limit_subscription` set and `max_subscriptions` above zero, streams get an
+/// unlimited one otherwise. A filter
+/// counts once however often it is subscribed to, and stops counting on UNSUBSCRIBE or when the
+/// broker refuses it in its SUBACK. SUBSCRIBE filters that would go over the limit are taken out
+/// before the packet is yielded, and get Quota Exceeded (0x97) in the SUBACK, 0x80 for MQTT v3.
+/// The other filters of the packet are granted as usual.
+///
+/// ```
+/// use std::sync::Arc;
+/// use futures::StreamExt;
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// use tokio_util::codec::Framed;
+/// use rmqtt_codec::types::QoS;
+/// use rmqtt_codec::v3::{Packet, SubscribeReturnCode};
+/// use rmqtt_codec::MqttCodec;
+/// use rmqtt_net::{Builder, SubscriptionLimit, v3};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (mut client, server) = tokio::io::duplex(256);
+/// let mut stream = v3::MqttStream {
+///     io: Framed::new(server, MqttCodec::V3(Default::default())),
+///     remote_addr: "127.0.0.1:1883".parse().unwrap(),
+///     cfg: Arc::new(Builder::default()),
+///     active: Default::default(),
+///     idle: Default::default(),
+///     keepalive: Default::default(),
+///     session: None,
+///     topic_levels: Default::default(),
+///     // As set up for `limit_subscription` with `max_subscriptions` at 2
+///     subscription_limit: SubscriptionLimit::new(2),
+/// };
+///
+/// // SUBSCRIBE to `a`, `b` and `c`, one past the limit
+/// client.write_all(b"\x82\x0e\x00\x01\x00\x01a\x00\x00\x01b\x00\x00\x01c\x00").await.unwrap();
+/// let Some(Ok(Packet::Subscribe { packet_id, topic_filters })) = stream.next().await else {
+///     panic!("expected SUBSCRIBE");
+/// };
+/// assert_eq!(topic_filters.len(), 2);
+/// let status = vec![SubscribeReturnCode::Success(QoS::AtMostOnce); 2];
+/// stream.send_subscribe_ack(packet_id, status).await.unwrap();
+/// stream.flush().await.unwrap();
+/// let mut suback = [0u8; 7];
+/// client.read_exact(&mut suback).await.unwrap();
+/// assert_eq!(suback, [0x90, 5, 0, 1, 0, 0, 0x80]);
+/// assert_eq!(stream.subscription_limit.len(), 2);
+///
+/// // UNSUBSCRIBE from `a` makes room for `c`
+/// client.write_all(b"\xa2\x05\x00\x02\x00\x01a").await.unwrap();
+/// stream.next().await.unwrap().unwrap();
+/// client.write_all(b"\x82\x06\x00\x03\x00\x01c\x00").await.unwrap();
+/// let Some(Ok(Packet::Subscribe { topic_filters, .. })) = stream.next().await else {
+///     panic!("expected SUBSCRIBE");
+/// };
+/// assert_eq!(topic_filters, vec![("c".into(), QoS::AtMostOnce)]);
+/// assert_eq!(stream.subscription_limit.len(), 2);
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct SubscriptionLimit {
+    max: usize,
+    filters: HashSet<ByteString>,
+    /// Filters first subscribed to by each SUBSCRIBE, with their position in the yielded packet
+    added: HashMap<NonZeroU16, Vec<(usize, ByteString)>>,
+    refused: RefusedFilters,
+}
+
+impl SubscriptionLimit {
+    /// Creates a limit of `max` subscriptions, zero allows any number
+    pub fn new(max: usize) -> Self {
+        SubscriptionLimit { max, ..Default::default() }
+    }
+
+    /// Highest number of subscriptions accepted, zero if unlimited
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Number of subscriptions counted against the limit
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Whether no subscription is counted
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Takes the filters over the limit out of SUBSCRIBE `packet_id`, they are refused in its SUBACK
+    pub(crate) fn strip<T>(&mut self, packet_id: NonZeroU16, filters: &mut Vec<(ByteString, T)>) {
+        if self.max == 0 {
+            return;
+        }
+        let (max, counted) = (self.max, &mut self.filters);
+        let mut added = Vec::new();
+        let mut pos = 0;
+        self.refused.take(packet_id, filters, |filter| {
+            if !counted.contains(filter) {
+                if counted.len() >= max {
+                    return false;
+                }
+                counted.insert(filter.clone());
+                added.push((pos, filter.clone()));
+            }
+            pos += 1;
+            true
+        });
+        if !added.is_empty() {
+            self.added.insert(packet_id, added);
+        }
+    }
+
+    /// Stops counting the filters of an UNSUBSCRIBE
+    pub(crate) fn unsubscribe(&mut self, filters: &[ByteString]) {
+        for filter in filters {
+            self.filters.remove(filter);
+        }
+    }
+
+    /// Uncounts the filters the broker refused in SUBACK `packet_id`, then puts `failure` wherever
+    /// a filter was taken out
+    pub(crate) fn restore<T: Clone>(
+        &mut self,
+        packet_id: NonZeroU16,
+        status: &mut Vec<T>,
+        failure: T,
+        granted: impl Fn(&T) -> bool,
+    ) {
+        for (pos, filter) in self.added.remove(&packet_id).into_iter().flatten() {
+            if status.get(pos).is_some_and(|s| !granted(s)) {
+                self.filters.remove(&filter);
+            }
+        }
+        self.refused.restore(packet_id, status, failure);
+    }
+}
+
+/// Positions of the filters taken out of each SUBSCRIBE, by packet id
+///
+/// The broker only sees and acknowledges the filters left in the packet, the refused ones get
+/// their failure code back into the SUBACK at the position they had. Checks taking filters out one
+/// after the other restore them in the opposite order.
+#[derive(Debug, Default)]
+struct RefusedFilters {
+    refused: HashMap<NonZeroU16, Vec<usize>>,
+}
+
+impl RefusedFilters {
+    /// Keeps the filters of SUBSCRIBE `packet_id` accepted by `allow`, recording the others
+    fn take<T>(
+        &mut self,
+        packet_id: NonZeroU16,
+        filters: &mut Vec<(ByteString, T)>,
+        mut allow: impl FnMut(&ByteString) -> bool,
+    ) {
+        let mut refused = Vec::new();
+        let mut idx = 0;
+        filters.retain(|(filter, _)| {
+            let allowed = allow(filter);
+            if !allowed {
+                refused.push(idx);
+            }
+            idx += 1;
+            allowed
+        });
+        if !refused.is_empty() {
+            self.refused.insert(packet_id, refused);
+        }
+    }
+
+    /// Puts `failure` into the SUBACK `packet_id` wherever a filter was taken out
+    fn restore<T: Clone>(&mut self, packet_id: NonZeroU16, status: &mut Vec<T>, failure: T) {
+        // Positions are ascending, each insert lands where the filter was in the SUBSCRIBE
+        for idx in self.refused.remove(&packet_id).into_iter().flatten() {
+            status.insert(idx.min(status.len()), failure.clone());
+        }
+    }
+}
+
+/// Whether `topic` has at most `max` levels, any depth is accepted when `max` is zero
+fn within_levels(max: usize, topic: &str) -> bool {
+    max == 0 || strip_share(topic).split('/').count() <= max
+}
+
+/// The topic filter of a shared subscription, `filter` itself otherwise
+fn strip_share(filter: &str) -> &str {
+    match filter.strip_prefix("$share/").and_then(|rest| rest.split_once('/')) {
+        Some((_group, filter)) => filter,
+        None => filter,
+    }
+}
+
+/// Whether `topic` matches the MQTT topic filter `filter`, shared subscriptions included
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let filter = strip_share(filter);
+    // Wildcards at the first level never match topics starting with `$`
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Outbound QoS 1 and 2 publishes in flight to an MQTT v5 client, bounded by its Receive Maximum
+///
+/// A publish is in flight from the moment it is sent until its PUBACK, its PUBCOMP or a
+/// PUBREC with an error reason. Publishes that find the window full wait in a queue and go out
+/// as acknowledgements free up room, so a slow client is never sent more than it asked for.
+///
+/// ```
+/// use std::num::NonZeroU16;
+/// use rmqtt_net::SendWindow;
+///
+/// let id = |id| NonZeroU16::new(id).unwrap();
+/// let mut window = SendWindow::new(2);
+/// assert!(window.acquire(id(1)));
+/// assert!(window.acquire(id(2)));
+/// // The client has not acknowledged anything yet, a third publish has to wait
+/// assert!(!window.acquire(id(3)));
+/// assert!(window.release(id(1)));
+/// assert!(window.acquire(id(3)));
+/// assert_eq!(window.inflight(), 2);
+/// ```
+///
+/// A queued publish expires after the shorter of `message_expiry_interval` and its own Message
+/// Expiry Interval. Expired publishes are dropped instead of sent, the others go out with the
+/// interval lowered by the time they waited in the queue.
+///
+/// ```
+/// use std::num::NonZeroU16;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use futures::StreamExt;
+/// use tokio::io::AsyncWriteExt;
+/// use tokio_util::bytes::Bytes;
+/// use tokio_util::codec::Framed;
+/// use rmqtt_codec::types::{Publish, QoS};
+/// use rmqtt_codec::MqttCodec;
+/// use rmqtt_net::{Builder, SendWindow, v5};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (mut client, server) = tokio::io::duplex(1024);
+/// let mut stream = v5::MqttStream {
+///     io: Framed::new(server, MqttCodec::V5(Default::default())),
+///     remote_addr: "127.0.0.1:1883".parse().unwrap(),
+///     cfg: Arc::new(Builder::new().message_expiry_interval(Duration::from_millis(50))),
+///     active: Default::default(),
+///     idle: Default::default(),
+///     keepalive: Default::default(),
+///     session: None,
+///     topic_levels: Default::default(),
+///     subscription_limit: Default::default(),
+///     topic_aliases: Default::default(),
+///     subscription_ids: Default::default(),
+///     send_window: SendWindow::new(1),
+///     auth: None,
+/// };
+/// let publish = |packet_id| {
+///     Box::new(Publish {
+///         dup: false,
+///         retain: false,
+///         qos: QoS::AtLeastOnce,
+///         topic: "a".into(),
+///         packet_id: NonZeroU16::new(packet_id),
+///         payload: Bytes::new(),
+///         properties: None,
+///         delay_interval: None,
+///         create_time: None,
+///     })
+/// };
+///
+/// // The second publish waits for the PUBACK of the first, which comes too late
+/// stream.send_publish(publish(1)).await.unwrap();
+/// stream.send_publish(publish(2)).await.unwrap();
+/// tokio::time::sleep(Duration::from_millis(100)).await;
+/// client.write_all(b"\x40\x02\x00\x01").await.unwrap();
+/// stream.next().await.unwrap().unwrap();
+///
+/// // The queue is served on the next poll, nothing else arrives meanwhile
+/// assert!(tokio::time::timeout(Duration::from_millis(10), stream.next()).await.is_err());
+/// assert_eq!(stream.send_window.queued(), 0);
+/// assert_eq!(stream.send_window.expired(), 1);
+/// assert_eq!(stream.send_window.inflight(), 0);
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct SendWindow {
+    max: u16,
+    inflight: HashSet<NonZeroU16>,
+    queued: VecDeque<Queued>,
+    expired: u64,
+    refused: u64,
+}
+
+/// A publish waiting for room in the [`SendWindow`]
+#[derive(Debug)]
+struct Queued {
+    publish: Box<Publish>,
+    expires_at: Option<Instant>,
+}
+
+impl Default for SendWindow {
+    /// The Receive Maximum a client gets when its CONNECT leaves it out
+    fn default() -> Self {
+        SendWindow::new(u16::MAX)
+    }
+}
+
+impl SendWindow {
+    /// Creates an empty window allowing `max` unacknowledged publishes
+    pub fn new(max: u16) -> Self {
+        SendWindow { max, inflight: HashSet::new(), queued: VecDeque::new(), expired: 0, refused: 0 }
+    }
+
+    /// The client's Receive Maximum
+    pub fn max(&self) -> u16 {
+        self.max
+    }
+
+    /// Number of publishes sent and not acknowledged yet
+    pub fn inflight(&self) -> usize {
+        self.inflight.len()
+    }
+
+    /// Number of publishes waiting for room in the window
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// Number of queued publishes dropped because they expired before they could be sent
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// Number of queued publishes the codec refused and dropped, e.g. over the client's Maximum
+    /// Packet Size
+    pub fn refused(&self) -> u64 {
+        self.refused
+    }
+
+    /// Takes a slot for `packet_id`, false if the window is full
+    pub fn acquire(&mut self, packet_id: NonZeroU16) -> bool {
+        if self.inflight.len() >= usize::from(self.max) {
+            return false;
+        }
+        self.inflight.insert(packet_id);
+        true
+    }
+
+    /// Frees the slot of `packet_id`, false if it was not in flight
+    pub fn release(&mut self, packet_id: NonZeroU16) -> bool {
+        self.inflight.remove(&packet_id)
+    }
+
+    pub(crate) fn set_max(&mut self, max: u16) {
+        self.max = max;
+    }
+
+    pub(crate) fn is_blocked(&self) -> bool {
+        !self.queued.is_empty() || self.inflight.len() >= usize::from(self.max)
+    }
+
+    /// Queues `publish`, to be dropped if it is still queued after `expiry`
+    pub(crate) fn enqueue(&mut self, publish: Box<Publish>, expiry: Option<Duration>) {
+        let expires_at = expiry.and_then(|expiry| Instant::now().checked_add(expiry));
+        self.queued.push_back(Queued { publish, expires_at });
+    }
+
+    /// Drops the queued publishes that expired, so they no longer count against `max_mqueue_len`
+    pub(crate) fn drop_expired(&mut self) {
+        let now = Instant::now();
+        let before = self.queued.len();
+        self.queued.retain(|queued| queued.expires_at.is_none_or(|at| at > now));
+        self.expired += (before - self.queued.len()) as u64;
+    }
+
+    /// Whether the first queued publish fits into the window now
+    pub(crate) fn has_ready(&self) -> bool {
+        !self.queued.is_empty() && self.inflight.len() < usize::from(self.max)
+    }
+
+    /// Takes the first queued publish that has not expired and its slot, callers check
+    /// [`SendWindow::has_ready`] first
+    pub(crate) fn pop_ready(&mut self) -> Option<Box<Publish>> {
+        let now = Instant::now();
+        while let Some(Queued { mut publish, expires_at }) = self.queued.pop_front() {
+            if let Some(expires_at) = expires_at {
+                let left = expires_at.saturating_duration_since(now);
+                if left.is_zero() {
+                    self.expired += 1;
+                    continue;
+                }
+                // The client is told how long the message has left, rounded up to whole seconds
+                let interval = publish.properties.as_mut().and_then(|p| p.message_expiry_interval.as_mut());
+                if let Some(interval) = interval {
+                    let secs = u32::try_from(left.as_millis().div_ceil(1000)).unwrap_or(u32::MAX);
+                    *interval = NonZeroU32::new(secs).map_or(*interval, |secs| secs.min(*interval));
+                }
+            }
+            if let Some(packet_id) = publish.packet_id {
+                self.inflight.insert(packet_id);
+            }
+            return Some(publish);
+        }
+        None
+    }
+
+    /// Drops a publish taken by [`SendWindow::pop_ready`] that the codec refused, freeing its slot
+    pub(crate) fn refuse(&mut self, packet_id: Option<NonZeroU16>) {
+        if let Some(packet_id) = packet_id {
+            self.inflight.remove(&packet_id);
+        }
+        self.refused += 1;
+    }
+}
+
+/// Decision of an `on_auth` hook for one step of enhanced authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthOutcome {
+    /// Sends AUTH with Continue Authentication (0x18) and this data, then waits for the reply
+    Continue(Option<Bytes>),
+    /// Accepts the client, the data goes out with the CONNACK
+    Success(Option<Bytes>),
+    /// Refuses the client with CONNACK Not Authorized (0x87)
+    Fail,
+}
+
+/// Callback behind [`Dispatcher::on_auth`], given the authentication method and data of a step
+pub type AuthFn = Box<dyn FnMut(&str, Option<&Bytes>) -> AuthOutcome + Send>;
+
+/// Per-connection enhanced authentication hook, set through [`Dispatcher::on_auth`]
+pub struct AuthHook {
+    hook: AuthFn,
+    /// Method and final data of a successful exchange, waiting for the CONNACK
+    completed: Option<(ByteString, Option<Bytes>)>,
+}
+
+impl fmt::Debug for AuthHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthHook(..)")
+    }
+}
+
+/// Why the server ends a connection, see [`MqttStream::disconnect`]
+///
+/// ```
+/// use rmqtt_codec::v5::DisconnectReasonCode;
+/// use rmqtt_net::DisconnectReason;
+///
+/// let reason = DisconnectReason::new(DisconnectReasonCode::AdministrativeAction).reason_string("kicked by admin");
+/// assert_eq!(reason.code, DisconnectReasonCode::AdministrativeAction);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectReason {
+    /// Reason code of the DISCONNECT packet
+    pub code: DisconnectReasonCode,
+    /// Human readable explanation, sent as the Reason String property
+    pub reason_string: Option<ByteString>,
+}
+
+impl DisconnectReason {
+    /// Creates a reason with `code` and no reason string
+    pub fn new(code: DisconnectReasonCode) -> Self {
+        DisconnectReason { code, reason_string: None }
+    }
+
+    /// Adds a reason string
+    pub fn reason_string(mut self, reason_string: impl Into<ByteString>) -> Self {
+        self.reason_string = Some(reason_string.into());
+        self
+    }
+}
+
+impl From<DisconnectReasonCode> for DisconnectReason {
+    fn from(code: DisconnectReasonCode) -> Self {
+        DisconnectReason::new(code)
+    }
+}
+
+/// Takes the reason code matching the error, and its message as reason string
+impl From<&MqttError> for DisconnectReason {
+    fn from(e: &MqttError) -> Self {
+        DisconnectReason::new(e.to_reason_code()).reason_string(e.to_string())
+    }
+}
+
+impl From<DisconnectReason> for Disconnect {
+    fn from(reason: DisconnectReason) -> Self {
+        Disconnect { reason_code: reason.code, reason_string: reason.reason_string, ..Default::default() }
+    }
+}
+
+impl From<DisconnectReason> for MqttPacket {
+    fn from(reason: DisconnectReason) -> Self {
+        MqttPacket::V5(PacketV5::Disconnect(reason.into()))
+    }
+}
+
 /// Version-specific MQTT protocol streams
+// Created once per connection and matched on right away, boxing the v5 state would change the
+// variant type callers destructure
+#[allow(clippy::large_enum_variant)]
 pub enum MqttStream<Io> {
     /// MQTT v3.1.1 implementation
     V3(v3::MqttStream<Io>),
@@ -78,6 +1014,86 @@ pub enum MqttStream<Io> {
     V5(v5::MqttStream<Io>),
 }
 
+impl<Io> MqttStream<Io>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Ends the connection on the server's initiative
+    ///
+    /// v5 clients get a DISCONNECT carrying `reason` first, MQTT v3 has no server DISCONNECT so
+    /// the socket is just closed.
+    pub async fn disconnect(&mut self, reason: DisconnectReason) -> Result<()> {
+        match self {
+            MqttStream::V3(s) => s.close().await,
+            MqttStream::V5(s) => s.disconnect(reason).await,
+        }
+    }
+
+    fn io_mut(&mut self) -> &mut Framed<Io, MqttCodec> {
+        match self {
+            MqttStream::V3(s) => &mut s.io,
+            MqttStream::V5(s) => &mut s.io,
+        }
+    }
+}
+
+/// Inbound packets of either version, for packet-level access
+///
+/// Packets go through the same checks as with the version-specific streams, so limits,
+/// timeouts and takeovers still end the stream.
+impl<Io> futures::Stream for MqttStream<Io>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<MqttPacket>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            MqttStream::V3(s) => s.poll_next_unpin(cx).map(|next| next.map(|res| res.map(MqttPacket::V3))),
+            MqttStream::V5(s) => s.poll_next_unpin(cx).map(|next| next.map(|res| res.map(MqttPacket::V5))),
+        }
+    }
+}
+
+/// Outbound packets of the negotiated version, for packet-level access
+///
+/// Packets are handed to the codec as they are: the send window, subscription identifiers and
+/// SUBACK adjustments of the `send_*` methods do not apply, and `send_timeout` is left to the
+/// caller. A packet of the other protocol version fails with [`MqttError::InvalidProtocol`].
+impl<Io> Sink<MqttPacket> for MqttStream<Io>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = crate::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(self.get_mut().io_mut()).poll_ready(cx).map_err(encode_error)
+    }
+
+    fn start_send(self: Pin<&mut Self>, packet: MqttPacket) -> Result<()> {
+        let this = self.get_mut();
+        let (cfg, remote_addr, session) = match (&*this, &packet) {
+            (MqttStream::V3(s), MqttPacket::V3(_)) => (&s.cfg, s.remote_addr, s.session.as_ref()),
+            (MqttStream::V5(s), MqttPacket::V5(_)) => (&s.cfg, s.remote_addr, s.session.as_ref()),
+            _ => return Err(MqttError::InvalidProtocol.into()),
+        };
+        trace_packet(cfg, remote_addr, session, "->", &packet);
+        Pin::new(this.io_mut()).start_send(packet).map_err(encode_error)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(self.get_mut().io_mut()).poll_flush(cx).map_err(encode_error)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(self.get_mut().io_mut()).poll_close(cx).map_err(encode_error)
+    }
+}
+
+fn encode_error(e: rmqtt_codec::error::EncodeError) -> crate::Error {
+    MqttError::SendPacket(SendPacketError::Encode(e)).into()
+}
+
 pub mod v3 {
 
     use std::net::SocketAddr;
@@ -93,11 +1109,13 @@ pub mod v3 {
 
     use rmqtt_codec::error::DecodeError;
     use rmqtt_codec::types::Publish;
-    use rmqtt_codec::v3::{Connect, ConnectAckReason, Packet as PacketV3, Packet};
+    use rmqtt_codec::v3::{Connect, ConnectAckReason, Packet as PacketV3, Packet, SubscribeReturnCode};
     use rmqtt_codec::{MqttCodec, MqttPacket};
 
     use crate::error::MqttError;
-    use crate::{Builder, Error, Result};
+    use crate::{
+        ActiveConnection, Builder, Error, IdleTimer, Registration, Result, SubscriptionLimit, TopicLevels,
+    };
 
     /// MQTT v3.1.1 protocol stream implementation
     pub struct MqttStream<Io> {
@@ -107,6 +1125,19 @@ pub mod v3 {
         pub remote_addr: SocketAddr,
         /// Shared configuration builder
         pub cfg: Arc<Builder>,
+        /// Keeps the connection in the listener's active count
+        pub active: ActiveConnection,
+        /// Enforces `Builder::idle_timeout` on inbound packets
+        pub idle: IdleTimer,
+        /// Enforces the keepalive, armed once the CONNECT packet passes through the stream
+        pub keepalive: IdleTimer,
+        /// Claim on the client identifier of CONNECT, taken up by a successful CONNACK, see
+        /// [`crate::ClientRegistry`]
+        pub session: Option<Registration>,
+        /// Enforces `Builder::max_topic_levels` on PUBLISH and SUBSCRIBE
+        pub topic_levels: TopicLevels,
+        /// Enforces `Builder::max_subscriptions` on SUBSCRIBE
+        pub subscription_limit: SubscriptionLimit,
     }
 
     /// # Examples
@@ -125,6 +1156,12 @@ pub mod v3 {
     ///     io: Framed::new(stream, MqttCodec::V3(Default::default())),
     ///     remote_addr: addr,
     ///     cfg: Arc::new(Builder::default()),
+    ///     active: Default::default(),
+    ///     idle: Default::default(),
+    ///     keepalive: Default::default(),
+    ///     session: None,
+    ///     topic_levels: Default::default(),
+    ///     subscription_limit: Default::default(),
     /// };
     ///
     /// // Send a PING request
@@ -174,12 +1211,67 @@ pub mod v3 {
         }
 
         /// Acknowledges a subscription request
+        ///
+        /// Granted QoS levels above `max_qos_allowed` are lowered to it. The filters of a SUBSCRIBE
+        /// already come out of the stream capped, so a broker granting what was asked for grants
+        /// the capped QoS and delivers at it.
+        ///
+        /// ```
+        /// use std::sync::Arc;
+        /// use futures::StreamExt;
+        /// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        /// use tokio_util::codec::Framed;
+        /// use rmqtt_codec::types::QoS;
+        /// use rmqtt_codec::v3::{Packet, SubscribeReturnCode};
+        /// use rmqtt_codec::MqttCodec;
+        /// use rmqtt_net::{Builder, v3};
+        ///
+        /// # #[tokio::main(flavor = "current_thread")]
+        /// # async fn main() {
+        /// let (mut client, server) = tokio::io::duplex(256);
+        /// let mut stream = v3::MqttStream {
+        ///     io: Framed::new(server, MqttCodec::V3(Default::default())),
+        ///     remote_addr: "127.0.0.1:1883".parse().unwrap(),
+        ///     cfg: Arc::new(Builder::new().max_qos_allowed(QoS::AtLeastOnce)),
+        ///     active: Default::default(),
+        ///     idle: Default::default(),
+        ///     keepalive: Default::default(),
+        ///     session: None,
+        ///     topic_levels: Default::default(),
+        ///     subscription_limit: Default::default(),
+        /// };
+        ///
+        /// // SUBSCRIBE to `a/b` at QoS 2
+        /// client.write_all(b"\x82\x08\x00\x01\x00\x03a/b\x02").await.unwrap();
+        /// let Some(Ok(Packet::Subscribe { packet_id, topic_filters })) = stream.next().await else {
+        ///     panic!("expected SUBSCRIBE");
+        /// };
+        /// assert_eq!(topic_filters, vec![("a/b".into(), QoS::AtLeastOnce)]);
+        ///
+        /// // Even a broker granting QoS 2 has it lowered in the SUBACK
+        /// let status = vec![SubscribeReturnCode::Success(QoS::ExactlyOnce)];
+        /// stream.send_subscribe_ack(packet_id, status).await.unwrap();
+        /// stream.flush().await.unwrap();
+        /// let mut suback = [0u8; 5];
+        /// client.read_exact(&mut suback).await.unwrap();
+        /// assert_eq!(suback, [0x90, 3, 0, 1, 1]);
+        /// # }
+        /// ```
         #[inline]
         pub async fn send_subscribe_ack(
             &mut self,
             packet_id: NonZeroU16,
-            status: Vec<rmqtt_codec::v3::SubscribeReturnCode>,
+            mut status: Vec<rmqtt_codec::v3::SubscribeReturnCode>,
         ) -> Result<()> {
+            let max = self.cfg.max_qos_allowed;
+            for code in &mut status {
+                if let SubscribeReturnCode::Success(qos) = code {
+                    *qos = (*qos).min(max);
+                }
+            }
+            let granted = |code: &SubscribeReturnCode| matches!(code, SubscribeReturnCode::Success(_));
+            self.subscription_limit.restore(packet_id, &mut status, SubscribeReturnCode::Failure, granted);
+            self.topic_levels.restore(packet_id, &mut status, SubscribeReturnCode::Failure);
             self.send(PacketV3::SubscribeAck { packet_id, status }).await
         }
 
@@ -196,6 +1288,9 @@ pub mod v3 {
         }
 
         /// Responds to connection request
+        ///
+        /// An accepted CONNECT claims its client identifier once the ack is sent, taking over the
+        /// connection that held it.
         #[inline]
         pub async fn send_connect_ack(
             &mut self,
@@ -203,7 +1298,13 @@ pub mod v3 {
             session_present: bool,
         ) -> Result<()> {
             self.send(PacketV3::ConnectAck(rmqtt_codec::v3::ConnectAck { session_present, return_code }))
-                .await
+                .await?;
+            if return_code == ConnectAckReason::ConnectionAccepted {
+                if let Some(session) = self.session.as_mut() {
+                    session.claim();
+                }
+            }
+            Ok(())
         }
 
         /// Sends keep-alive ping request
@@ -221,7 +1322,9 @@ pub mod v3 {
         /// Generic packet sending method
         #[inline]
         pub async fn send(&mut self, packet: rmqtt_codec::v3::Packet) -> Result<()> {
-            super::send(&mut self.io, MqttPacket::V3(packet), self.cfg.send_timeout).await
+            let packet = MqttPacket::V3(packet);
+            super::trace_packet(&self.cfg, self.remote_addr, self.session.as_ref(), "->", &packet);
+            super::send(&mut self.io, packet, self.cfg.send_timeout).await
         }
 
         /// Flushes write buffers
@@ -248,6 +1351,50 @@ pub mod v3 {
         }
 
         /// Waits for CONNECT packet with timeout
+        ///
+        /// A client identifier longer than `max_clientid_len` is refused here with return code 0x02
+        /// (Identifier Rejected) and the call fails, an empty one is returned for the broker to assign.
+        ///
+        /// ```
+        /// use std::sync::Arc;
+        /// use std::time::Duration;
+        /// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        /// use tokio_util::codec::Framed;
+        /// use rmqtt_codec::MqttCodec;
+        /// use rmqtt_net::{Builder, v3};
+        ///
+        /// async fn connect(client_id: &str) -> (bool, Vec<u8>) {
+        ///     let (mut client, server) = tokio::io::duplex(256);
+        ///     let mut stream = v3::MqttStream {
+        ///         io: Framed::new(server, MqttCodec::V3(Default::default())),
+        ///         remote_addr: "127.0.0.1:1883".parse().unwrap(),
+        ///         cfg: Arc::new(Builder::new().max_clientid_len(4)),
+        ///         active: Default::default(),
+        ///         idle: Default::default(),
+        ///         keepalive: Default::default(),
+        ///         session: None,
+        ///         topic_levels: Default::default(),
+        ///         subscription_limit: Default::default(),
+        ///     };
+        ///     let len = client_id.len() as u8;
+        ///     let mut packet = vec![0x10, 12 + len, 0, 4, b'M', b'Q', b'T', b'T', 4, 2, 0, 60, 0, len];
+        ///     packet.extend_from_slice(client_id.as_bytes());
+        ///     client.write_all(&packet).await.unwrap();
+        ///
+        ///     let accepted = stream.recv_connect(Duration::from_secs(1)).await.is_ok();
+        ///     drop(stream);
+        ///     let mut reply = Vec::new();
+        ///     client.read_to_end(&mut reply).await.unwrap();
+        ///     (accepted, reply)
+        /// }
+        ///
+        /// # #[tokio::main(flavor = "current_thread")]
+        /// # async fn main() {
+        /// assert_eq!(connect("abcd").await, (true, vec![]));
+        /// assert_eq!(connect("abcde").await, (false, vec![0x20, 2, 0, 2]));
+        /// assert_eq!(connect("").await, (true, vec![]));
+        /// # }
+        /// ```
         #[inline]
         pub async fn recv_connect(&mut self, tm: Duration) -> Result<Box<Connect>> {
             let connect = match self.recv(tm).await {
@@ -259,21 +1406,103 @@ pub mod v3 {
                     return Err(MqttError::InvalidProtocol.into());
                 }
             };
+            if !super::clientid_allowed(&self.cfg, &connect.client_id) {
+                self.send_connect_ack(ConnectAckReason::IdentifierRejected, false).await?;
+                self.flush().await?;
+                return Err(MqttError::IdentifierRejected.into());
+            }
             Ok(connect)
         }
     }
 
+    /// Yields inbound packets
+    ///
+    /// A packet whose remaining length exceeds `max_packet_size` is refused as soon as its fixed
+    /// header is read, the connection is closed and the stream ends after the error. So is a
+    /// PUBLISH over `max_topic_levels`, while SUBSCRIBE filters over it are left out of the packet
+    /// and refused in the SUBACK, see [`MqttStream::topic_levels`]. The same goes for filters past
+    /// [`MqttStream::subscription_limit`]. Filters asking for a QoS above `max_qos_allowed` are
+    /// lowered to it.
     impl<Io> futures::Stream for MqttStream<Io>
     where
-        Io: AsyncRead + Unpin,
+        Io: AsyncRead + AsyncWrite + Unpin,
     {
         type Item = Result<rmqtt_codec::v3::Packet>;
 
         fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let next = Pin::new(&mut self.io).poll_next(cx);
-            Poll::Ready(match futures::ready!(next) {
-                Some(Ok((MqttPacket::V3(packet), _))) => Some(Ok(packet)),
+            // Nothing is read after a timeout, the connection is treated as closed
+            if self.idle.expired()
+                || self.keepalive.expired()
+                || self.topic_levels.failed()
+                || self.session.as_ref().is_some_and(Registration::reported)
+            {
+                return Poll::Ready(None);
+            }
+            if self.session.as_mut().is_some_and(|session| session.poll_taken_over(cx).is_ready()) {
+                let this = &mut *self;
+                log::debug!("{} {} taken over by a new connection", this.cfg.name, this.remote_addr);
+                let e = MqttError::SessionTakenOver;
+                super::close_now(&mut this.io, cx, None);
+                return Poll::Ready(Some(Err(e.into())));
+            }
+            let Poll::Ready(next) = Pin::new(&mut self.io).poll_next(cx) else {
+                if self.idle.poll_expired(cx).is_ready() {
+                    let this = &mut *self;
+                    log::debug!("{} {} idle timed out", this.cfg.name, this.remote_addr);
+                    super::close_now(&mut this.io, cx, None);
+                    return Poll::Ready(Some(Err(MqttError::IdleTimeout.into())));
+                }
+                if self.keepalive.poll_expired(cx).is_ready() {
+                    let this = &mut *self;
+                    log::debug!("{} {} keepalive timed out", this.cfg.name, this.remote_addr);
+                    super::close_now(&mut this.io, cx, None);
+                    return Poll::Ready(Some(Err(MqttError::KeepAliveTimeout.into())));
+                }
+                return Poll::Pending;
+            };
+            if let Some(Ok((packet, _))) = &next {
+                super::trace_packet(&self.cfg, self.remote_addr, self.session.as_ref(), "<-", packet);
+            }
+            self.idle.reset();
+            self.keepalive.reset();
+            Poll::Ready(match next {
+                Some(Ok((MqttPacket::V3(mut packet), _))) => {
+                    match &mut packet {
+                        Packet::Connect(connect) => {
+                            let window = super::keepalive_window(&self.cfg, connect.keep_alive);
+                            self.keepalive.arm(window);
+                            self.session = super::reserve(&self.cfg, &connect.client_id);
+                        }
+                        Packet::Subscribe { packet_id, topic_filters } => {
+                            self.topic_levels.strip(*packet_id, topic_filters);
+                            self.subscription_limit.strip(*packet_id, topic_filters);
+                            for (_, qos) in topic_filters.iter_mut() {
+                                *qos = (*qos).min(self.cfg.max_qos_allowed);
+                            }
+                        }
+                        Packet::Unsubscribe { topic_filters, .. } => {
+                            self.subscription_limit.unsubscribe(topic_filters);
+                        }
+                        Packet::Publish(publish) => {
+                            if let Err(e) = self.topic_levels.check_publish(&publish.topic) {
+                                let this = &mut *self;
+                                log::debug!("{} {} {}, disconnecting", this.cfg.name, this.remote_addr, e);
+                                super::close_now(&mut this.io, cx, None);
+                                return Poll::Ready(Some(Err(e.into())));
+                            }
+                        }
+                        _ => {}
+                    }
+                    Some(Ok(packet))
+                }
                 Some(Ok(_)) => Some(Err(MqttError::Decode(DecodeError::MalformedPacket).into())),
+                // MQTT 3.1.1 has no way to tell the client why, the connection is just closed
+                Some(Err(DecodeError::MaxSizeExceeded)) => {
+                    let this = &mut *self;
+                    super::log_oversized(&this.cfg, this.remote_addr);
+                    super::close_now(&mut this.io, cx, None);
+                    Some(Err(MqttError::Decode(DecodeError::MaxSizeExceeded).into()))
+                }
                 Some(Err(e)) => Some(Err(Error::from(e))),
                 None => None,
             })
@@ -288,17 +1517,26 @@ pub mod v5 {
     use std::task::{Context, Poll};
     use std::time::Duration;
 
-    use futures::StreamExt;
+    use bytestring::ByteString;
+    use futures::{Sink, StreamExt};
     use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_util::bytes::Bytes;
     use tokio_util::codec::Framed;
 
     use rmqtt_codec::error::DecodeError;
-    use rmqtt_codec::types::Publish;
-    use rmqtt_codec::v5::{Auth, Connect, Disconnect, Packet as PacketV5, Packet};
+    use rmqtt_codec::types::{Publish, QoS};
+    use rmqtt_codec::v5::{
+        Auth, AuthReasonCode, Connect, ConnectAck, ConnectAckReason, Disconnect, DisconnectReasonCode,
+        Packet as PacketV5, Packet, SubscribeAckReason,
+    };
     use rmqtt_codec::{MqttCodec, MqttPacket};
 
     use crate::error::MqttError;
-    use crate::{Builder, Error, Result};
+    use crate::stream::{AuthHook, AuthOutcome, DisconnectReason};
+    use crate::{
+        ActiveConnection, Builder, Error, IdleTimer, Registration, Result, SendWindow, SubscriptionIds,
+        SubscriptionLimit, TopicAliases, TopicLevels,
+    };
 
     /// MQTT v5.0 protocol stream implementation
     pub struct MqttStream<Io> {
@@ -308,6 +1546,27 @@ pub mod v5 {
         pub remote_addr: SocketAddr,
         /// Shared configuration builder
         pub cfg: Arc<Builder>,
+        /// Keeps the connection in the listener's active count
+        pub active: ActiveConnection,
+        /// Enforces `Builder::idle_timeout` on inbound packets
+        pub idle: IdleTimer,
+        /// Enforces the keepalive, armed once the CONNECT packet passes through the stream
+        pub keepalive: IdleTimer,
+        /// Claim on the client identifier of CONNECT, taken up by a successful CONNACK, see
+        /// [`crate::ClientRegistry`]
+        pub session: Option<Registration>,
+        /// Enforces `Builder::max_topic_levels` on PUBLISH and SUBSCRIBE
+        pub topic_levels: TopicLevels,
+        /// Enforces `Builder::max_subscriptions` on SUBSCRIBE
+        pub subscription_limit: SubscriptionLimit,
+        /// Topic aliases set by the client, up to `Builder::max_topic_aliases`
+        pub topic_aliases: TopicAliases,
+        /// Subscription identifiers attached by [`MqttStream::send_publish`]
+        pub subscription_ids: SubscriptionIds,
+        /// Keeps QoS 1 and 2 publishes within the client's Receive Maximum
+        pub send_window: SendWindow,
+        /// Enhanced authentication hook taken over from [`crate::stream::Dispatcher::on_auth`]
+        pub auth: Option<AuthHook>,
     }
 
     /// # Examples
@@ -327,6 +1586,16 @@ pub mod v5 {
     ///     io: Framed::new(stream, MqttCodec::V5(Default::default())),
     ///     remote_addr: addr,
     ///     cfg: Arc::new(Builder::default()),
+    ///     active: Default::default(),
+    ///     idle: Default::default(),
+    ///     keepalive: Default::default(),
+    ///     session: None,
+    ///     topic_levels: Default::default(),
+    ///     subscription_limit: Default::default(),
+    ///     topic_aliases: Default::default(),
+    ///     subscription_ids: Default::default(),
+    ///     send_window: Default::default(),
+    ///     auth: None,
     /// };
     ///
     /// // Send authentication packet
@@ -347,10 +1616,51 @@ pub mod v5 {
             Ok(())
         }
 
+        /// Ends the connection on the server's initiative, telling the client why
+        ///
+        /// Sends DISCONNECT with the reason code and reason string of `reason`, then closes the
+        /// socket. Unlike [`MqttStream::send_disconnect`] this does not wait for the client to
+        /// close first.
+        pub async fn disconnect(&mut self, reason: DisconnectReason) -> Result<()> {
+            self.send(PacketV5::Disconnect(reason.into())).await?;
+            self.flush().await?;
+            self.close().await
+        }
+
         /// Publishes a message to the broker
+        ///
+        /// A publish without subscription identifiers gets those of the client's subscriptions
+        /// matching its topic, see [`MqttStream::subscription_ids`].
+        ///
+        /// QoS 1 and 2 publishes go through [`MqttStream::send_window`]: once the client's Receive
+        /// Maximum is reached they are queued and sent while the stream is polled and acknowledgements
+        /// arrive. Fails with [`MqttError::SendQueueFull`] when `max_mqueue_len` publishes wait already.
+        /// Queued publishes can expire before they are sent, see [`SendWindow`].
         #[inline]
-        pub async fn send_publish(&mut self, publish: Box<Publish>) -> Result<()> {
-            self.send(PacketV5::Publish(publish)).await
+        pub async fn send_publish(&mut self, mut publish: Box<Publish>) -> Result<()> {
+            if publish.properties.as_ref().is_none_or(|p| p.subscription_ids.is_empty()) {
+                let ids = self.subscription_ids.matching(&publish.topic);
+                if !ids.is_empty() {
+                    publish.properties.get_or_insert_with(Default::default).subscription_ids = ids;
+                }
+            }
+            if let Some(packet_id) = publish.packet_id {
+                if self.send_window.is_blocked() || !self.send_window.acquire(packet_id) {
+                    self.send_window.drop_expired();
+                    if self.send_window.queued() >= self.cfg.max_mqueue_len {
+                        return Err(MqttError::SendQueueFull(self.send_window.queued()).into());
+                    }
+                    let expiry = super::message_expiry(&self.cfg, &publish);
+                    self.send_window.enqueue(publish, expiry);
+                    return Ok(());
+                }
+            }
+            let packet_id = publish.packet_id;
+            let res = self.send(PacketV5::Publish(publish)).await;
+            if let (Err(_), Some(packet_id)) = (&res, packet_id) {
+                self.send_window.release(packet_id);
+            }
+            res
         }
 
         /// Acknowledges a received publish (QoS 1)
@@ -378,8 +1688,27 @@ pub mod v5 {
         }
 
         /// Acknowledges a subscription request
+        ///
+        /// Granted QoS levels above `max_qos_allowed` are lowered to it, as in
+        /// [`crate::v3::MqttStream::send_subscribe_ack`].
         #[inline]
-        pub async fn send_subscribe_ack(&mut self, ack: rmqtt_codec::v5::SubscribeAck) -> Result<()> {
+        pub async fn send_subscribe_ack(&mut self, mut ack: rmqtt_codec::v5::SubscribeAck) -> Result<()> {
+            let granted = match self.cfg.max_qos_allowed {
+                QoS::AtMostOnce => SubscribeAckReason::GrantedQos0,
+                QoS::AtLeastOnce => SubscribeAckReason::GrantedQos1,
+                QoS::ExactlyOnce => SubscribeAckReason::GrantedQos2,
+            };
+            for reason in &mut ack.status {
+                // Codes from 0x80 up are failures, the ones below are the granted QoS
+                if u8::from(*reason) < 0x80 && u8::from(*reason) > u8::from(granted) {
+                    *reason = granted;
+                }
+            }
+            let granted = |reason: &SubscribeAckReason| u8::from(*reason) < 0x80;
+            let failure = SubscribeAckReason::QuotaExceeded;
+            self.subscription_limit.restore(ack.packet_id, &mut ack.status, failure, granted);
+            let failure = SubscribeAckReason::TopicFilterInvalid;
+            self.topic_levels.restore(ack.packet_id, &mut ack.status, failure);
             self.send(PacketV5::SubscribeAck(ack)).await
         }
 
@@ -396,9 +1725,35 @@ pub mod v5 {
         }
 
         /// Responds to connection request
+        ///
+        /// The advertised `receive_max` is capped at `Builder::max_inflight`. An ack that leaves
+        /// `topic_alias_max` at zero advertises [`TopicAliases::max`], any other value becomes the
+        /// maximum the stream enforces. After enhanced authentication a successful ack carries the
+        /// method and final data of the exchange. A successful ack claims the client identifier
+        /// once sent, taking over the connection that held it.
         #[inline]
-        pub async fn send_connect_ack(&mut self, ack: rmqtt_codec::v5::ConnectAck) -> Result<()> {
-            self.send(PacketV5::ConnectAck(Box::new(ack))).await
+        pub async fn send_connect_ack(&mut self, mut ack: rmqtt_codec::v5::ConnectAck) -> Result<()> {
+            ack.receive_max = ack.receive_max.min(self.cfg.max_inflight);
+            if ack.topic_alias_max == 0 {
+                ack.topic_alias_max = self.topic_aliases.max();
+            } else {
+                self.topic_aliases.set_max(ack.topic_alias_max);
+            }
+            let completed = self.auth.as_mut().and_then(|auth| auth.completed.take());
+            if let (ConnectAckReason::Success, None, Some((method, data))) =
+                (ack.reason_code, &ack.auth_method, completed)
+            {
+                ack.auth_method = Some(method);
+                ack.auth_data = data;
+            }
+            let success = ack.reason_code == ConnectAckReason::Success;
+            self.send(PacketV5::ConnectAck(Box::new(ack))).await?;
+            if success {
+                if let Some(session) = self.session.as_mut() {
+                    session.claim();
+                }
+            }
+            Ok(())
         }
 
         /// Sends keep-alive ping request
@@ -422,7 +1777,9 @@ pub mod v5 {
         /// Generic packet sending method
         #[inline]
         pub async fn send(&mut self, packet: rmqtt_codec::v5::Packet) -> Result<()> {
-            super::send(&mut self.io, MqttPacket::V5(packet), self.cfg.send_timeout).await
+            let packet = MqttPacket::V5(packet);
+            super::trace_packet(&self.cfg, self.remote_addr, self.session.as_ref(), "->", &packet);
+            super::send(&mut self.io, packet, self.cfg.send_timeout).await
         }
 
         /// Flushes write buffers
@@ -449,9 +1806,17 @@ pub mod v5 {
         }
 
         /// Waits for CONNECT packet with timeout
+        ///
+        /// With an [`MqttStream::auth`] hook, a CONNECT naming an authentication method is only
+        /// returned once the AUTH exchange succeeded, with the method and data taken out of it so
+        /// the caller treats it as a plain CONNECT. A refused client gets its CONNACK here and the
+        /// call fails. Each step of the exchange must arrive within `tm`.
+        ///
+        /// A client identifier longer than `max_clientid_len` is refused before that with CONNACK
+        /// Client Identifier not valid (0x85), an empty one is returned for the broker to assign.
         #[inline]
         pub async fn recv_connect(&mut self, tm: Duration) -> Result<Box<Connect>> {
-            let connect = match self.recv(tm).await {
+            let mut connect = match self.recv(tm).await {
                 Ok(Some(Packet::Connect(connect))) => connect,
                 Err(e) => {
                     return Err(e);
@@ -460,28 +1825,359 @@ pub mod v5 {
                     return Err(MqttError::InvalidProtocol.into());
                 }
             };
+            if !super::clientid_allowed(&self.cfg, &connect.client_id) {
+                let ack = ConnectAck {
+                    reason_code: ConnectAckReason::ClientIdentifierNotValid,
+                    ..Default::default()
+                };
+                self.send_connect_ack(ack).await?;
+                self.flush().await?;
+                return Err(MqttError::IdentifierRejected.into());
+            }
+            if let (Some(mut auth), Some(method)) = (self.auth.take(), connect.auth_method.take()) {
+                let res = self.authenticate(&mut auth, &method, connect.auth_data.take(), tm).await;
+                auth.completed = res.as_ref().ok().map(|data| (method, data.clone()));
+                self.auth = Some(auth);
+                res?;
+            }
             Ok(connect)
         }
+
+        /// Runs the AUTH exchange until the hook accepts or refuses the client
+        async fn authenticate(
+            &mut self,
+            auth: &mut AuthHook,
+            method: &ByteString,
+            mut data: Option<Bytes>,
+            tm: Duration,
+        ) -> Result<Option<Bytes>> {
+            loop {
+                let challenge = match (auth.hook)(method, data.as_ref()) {
+                    AuthOutcome::Success(data) => return Ok(data),
+                    AuthOutcome::Continue(challenge) => challenge,
+                    AuthOutcome::Fail => {
+                        let ack =
+                            ConnectAck { reason_code: ConnectAckReason::NotAuthorized, ..Default::default() };
+                        self.send_connect_ack(ack).await?;
+                        self.flush().await?;
+                        return Err(MqttError::AuthFailed.into());
+                    }
+                };
+                self.send_auth(Auth {
+                    reason_code: AuthReasonCode::ContinueAuth,
+                    auth_method: Some(method.clone()),
+                    auth_data: challenge,
+                    ..Default::default()
+                })
+                .await?;
+                self.flush().await?;
+                // The client must answer with Continue Authentication under the same method
+                data = match self.recv(tm).await? {
+                    Some(Packet::Auth(reply))
+                        if reply.reason_code == AuthReasonCode::ContinueAuth
+                            && reply.auth_method.as_ref() == Some(method) =>
+                    {
+                        reply.auth_data
+                    }
+                    _ => {
+                        let ack =
+                            ConnectAck { reason_code: ConnectAckReason::ProtocolError, ..Default::default() };
+                        self.send_connect_ack(ack).await?;
+                        self.flush().await?;
+                        return Err(MqttError::InvalidProtocol.into());
+                    }
+                };
+            }
+        }
+    }
+
+    /// Yields inbound packets
+    ///
+    /// A packet whose remaining length exceeds `max_packet_size` is refused as soon as its fixed
+    /// header is read: DISCONNECT with Packet Too Large (0x95) is sent, the connection is closed
+    /// and the stream ends after the error. Topic aliases are resolved through
+    /// [`MqttStream::topic_aliases`], a PUBLISH with an invalid or unmapped alias is refused the same
+    /// way with Topic Alias Invalid (0x94) or Protocol Error (0x82), and one over `max_topic_levels`
+    /// with Topic Name invalid (0x90). SUBSCRIBE filters over that limit are left out of the packet
+    /// and refused with Topic Filter invalid (0x8F) in the SUBACK, see [`MqttStream::topic_levels`].
+    /// Filters past [`MqttStream::subscription_limit`] are refused the same way with Quota Exceeded
+    /// (0x97), and those asking for a QoS above `max_qos_allowed` are lowered to it.
+    /// Each poll also sends the publishes queued in [`MqttStream::send_window`] that fit into the
+    /// window again.
+    impl<Io> MqttStream<Io>
+    where
+        Io: AsyncRead + AsyncWrite + Unpin,
+    {
+        /// Writes out queued publishes that fit into the send window, without blocking
+        fn poll_send_queued(&mut self, cx: &mut Context<'_>) {
+            if !self.send_window.has_ready() {
+                return;
+            }
+            while self.send_window.has_ready() {
+                if !matches!(Pin::new(&mut self.io).poll_ready(cx), Poll::Ready(Ok(()))) {
+                    break;
+                }
+                let Some(publish) = self.send_window.pop_ready() else { break };
+                let packet_id = publish.packet_id;
+                let packet = MqttPacket::V5(PacketV5::Publish(publish));
+                super::trace_packet(&self.cfg, self.remote_addr, self.session.as_ref(), "->", &packet);
+                // Over the client's Maximum Packet Size, it is discarded as if sent [MQTT-3.1.2-25]
+                if Pin::new(&mut self.io).start_send(packet).is_err() {
+                    self.send_window.refuse(packet_id);
+                }
+            }
+            let _ = Pin::new(&mut self.io).poll_flush(cx);
+        }
     }
 
     impl<Io> futures::Stream for MqttStream<Io>
     where
-        Io: AsyncRead + Unpin,
+        Io: AsyncRead + AsyncWrite + Unpin,
     {
         type Item = Result<rmqtt_codec::v5::Packet>;
 
         fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let next = Pin::new(&mut self.io).poll_next(cx);
-            Poll::Ready(match futures::ready!(next) {
-                Some(Ok((MqttPacket::V5(packet), _))) => Some(Ok(packet)),
+            // Nothing is read after a timeout, the connection is treated as closed
+            if self.idle.expired()
+                || self.keepalive.expired()
+                || self.topic_aliases.failed()
+                || self.topic_levels.failed()
+                || self.session.as_ref().is_some_and(Registration::reported)
+            {
+                return Poll::Ready(None);
+            }
+            if self.session.as_mut().is_some_and(|session| session.poll_taken_over(cx).is_ready()) {
+                let this = &mut *self;
+                log::debug!("{} {} taken over by a new connection", this.cfg.name, this.remote_addr);
+                let e = MqttError::SessionTakenOver;
+                super::close_now(&mut this.io, cx, Some(DisconnectReason::from(&e).into()));
+                return Poll::Ready(Some(Err(e.into())));
+            }
+            self.poll_send_queued(cx);
+            let Poll::Ready(next) = Pin::new(&mut self.io).poll_next(cx) else {
+                if self.idle.poll_expired(cx).is_ready() {
+                    let this = &mut *self;
+                    log::debug!("{} {} idle timed out", this.cfg.name, this.remote_addr);
+                    let e = MqttError::IdleTimeout;
+                    super::close_now(&mut this.io, cx, Some(DisconnectReason::from(&e).into()));
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                if self.keepalive.poll_expired(cx).is_ready() {
+                    let this = &mut *self;
+                    log::debug!("{} {} keepalive timed out", this.cfg.name, this.remote_addr);
+                    let e = MqttError::KeepAliveTimeout;
+                    super::close_now(&mut this.io, cx, Some(DisconnectReason::from(&e).into()));
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                return Poll::Pending;
+            };
+            if let Some(Ok((packet, _))) = &next {
+                super::trace_packet(&self.cfg, self.remote_addr, self.session.as_ref(), "<-", packet);
+            }
+            self.idle.reset();
+            self.keepalive.reset();
+            Poll::Ready(match next {
+                Some(Ok((MqttPacket::V5(mut packet), _))) => {
+                    match &mut packet {
+                        Packet::Connect(connect) => {
+                            let window = super::keepalive_window(&self.cfg, connect.keep_alive);
+                            self.keepalive.arm(window);
+                            self.send_window.set_max(connect.receive_max.map_or(u16::MAX, |max| max.get()));
+                            self.session = super::reserve(&self.cfg, &connect.client_id);
+                        }
+                        Packet::PublishAck(ack) => {
+                            self.send_window.release(ack.packet_id);
+                        }
+                        // A PUBREC with an error reason ends the QoS 2 flow as well
+                        Packet::PublishReceived(ack) if u8::from(ack.reason_code) >= 0x80 => {
+                            self.send_window.release(ack.packet_id);
+                        }
+                        Packet::PublishComplete(ack2) => {
+                            self.send_window.release(ack2.packet_id);
+                        }
+                        Packet::Subscribe(subscribe) => {
+                            self.topic_levels.strip(subscribe.packet_id, &mut subscribe.topic_filters);
+                            self.subscription_limit.strip(subscribe.packet_id, &mut subscribe.topic_filters);
+                            for (_, opts) in subscribe.topic_filters.iter_mut() {
+                                opts.qos = opts.qos.min(self.cfg.max_qos_allowed);
+                            }
+                            self.subscription_ids.subscribe(subscribe);
+                        }
+                        Packet::Unsubscribe(unsubscribe) => {
+                            self.subscription_limit.unsubscribe(&unsubscribe.topic_filters);
+                            self.subscription_ids.unsubscribe(unsubscribe);
+                        }
+                        Packet::Publish(publish) => {
+                            let res = self
+                                .topic_aliases
+                                .resolve(publish)
+                                .and_then(|()| self.topic_levels.check_publish(&publish.topic));
+                            if let Err(e) = res {
+                                let this = &mut *self;
+                                log::debug!("{} {} {}, disconnecting", this.cfg.name, this.remote_addr, e);
+                                super::close_now(&mut this.io, cx, Some(DisconnectReason::from(&e).into()));
+                                return Poll::Ready(Some(Err(e.into())));
+                            }
+                        }
+                        _ => {}
+                    }
+                    Some(Ok(packet))
+                }
                 Some(Ok(_)) => Some(Err(MqttError::Decode(DecodeError::MalformedPacket).into())),
-                Some(Err(e)) => Some(Err(Error::from(e))),
+                Some(Err(DecodeError::MaxSizeExceeded)) => {
+                    let disconnect = DisconnectReason::new(DisconnectReasonCode::PacketTooLarge)
+                        .reason_string("packet exceeds the maximum packet size");
+                    let disconnect = Some(disconnect.into());
+                    let this = &mut *self;
+                    super::log_oversized(&this.cfg, this.remote_addr);
+                    super::close_now(&mut this.io, cx, disconnect);
+                    Some(Err(MqttError::Decode(DecodeError::MaxSizeExceeded).into()))
+                }
+                Some(Err(e @ DecodeError::Io(_))) => Some(Err(Error::from(e))),
+                // Includes a zero topic alias, the codec reports it without saying which property
+                Some(Err(e)) => {
+                    let e = MqttError::Decode(e);
+                    let this = &mut *self;
+                    log::debug!("{} {} {}, disconnecting", this.cfg.name, this.remote_addr, e);
+                    super::close_now(&mut this.io, cx, Some(DisconnectReason::from(&e).into()));
+                    Some(Err(e.into()))
+                }
                 None => None,
             })
         }
     }
 }
 
+/// Logs `packet` at trace level if `Builder::packet_trace` is on, `dir` is `<-` or `->`
+fn trace_packet(
+    cfg: &Builder,
+    remote_addr: SocketAddr,
+    session: Option<&Registration>,
+    dir: &str,
+    packet: &MqttPacket,
+) {
+    if cfg.packet_trace {
+        let client_id = session.map_or("-", |s| s.client_id());
+        log::trace!("{} {} {} {} {}", cfg.name, remote_addr, client_id, dir, PacketSummary(packet));
+    }
+}
+
+/// Packet type and the fields worth tracing, never a payload or credentials
+struct PacketSummary<'a>(&'a MqttPacket);
+
+impl fmt::Display for PacketSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [&str; 16] = [
+            "RESERVED",
+            "CONNECT",
+            "CONNACK",
+            "PUBLISH",
+            "PUBACK",
+            "PUBREC",
+            "PUBREL",
+            "PUBCOMP",
+            "SUBSCRIBE",
+            "SUBACK",
+            "UNSUBSCRIBE",
+            "UNSUBACK",
+            "PINGREQ",
+            "PINGRESP",
+            "DISCONNECT",
+            "AUTH",
+        ];
+        let (packet_type, publish, client_id) = match self.0 {
+            MqttPacket::V3(p @ PacketV3::Publish(publish)) => (p.packet_type(), Some(publish), None),
+            MqttPacket::V5(p @ PacketV5::Publish(publish)) => (p.packet_type(), Some(publish), None),
+            MqttPacket::V3(p @ PacketV3::Connect(connect)) => {
+                (p.packet_type(), None, Some(&connect.client_id))
+            }
+            MqttPacket::V5(p @ PacketV5::Connect(connect)) => {
+                (p.packet_type(), None, Some(&connect.client_id))
+            }
+            MqttPacket::V3(p) => (p.packet_type(), None, None),
+            MqttPacket::V5(p) => (p.packet_type(), None, None),
+            MqttPacket::Version(ver) => return write!(f, "protocol version {ver:?}"),
+        };
+        f.write_str(NAMES[usize::from(packet_type >> 4)])?;
+        if let Some(client_id) = client_id {
+            write!(f, " client_id={client_id}")?;
+        }
+        if let Some(p) = publish {
+            write!(f, " topic={} qos={} len={}", p.topic, u8::from(p.qos), p.payload.len())?;
+            if let Some(packet_id) = p.packet_id {
+                write!(f, " packet_id={packet_id}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How long `publish` may wait for delivery, the shorter of `message_expiry_interval` and its
+/// Message Expiry Interval, None when neither is set
+fn message_expiry(cfg: &Builder, publish: &Publish) -> Option<Duration> {
+    let configured = (!cfg.message_expiry_interval.is_zero()).then_some(cfg.message_expiry_interval);
+    let interval = publish.properties.as_ref().and_then(|p| p.message_expiry_interval);
+    configured.into_iter().chain(interval.map(|secs| Duration::from_secs(secs.get().into()))).min()
+}
+
+/// Reserves the client identifier of a CONNECT, an empty one is left for the broker to assign
+///
+/// Identifiers over `max_clientid_len` are refused by `recv_connect`, they never take over the
+/// connection holding them.
+fn reserve(cfg: &Builder, client_id: &ByteString) -> Option<Registration> {
+    (!client_id.is_empty() && clientid_allowed(cfg, client_id))
+        .then(|| cfg.client_registry.reserve(client_id.clone()))
+}
+
+/// Whether a CONNECT client identifier fits `max_clientid_len`, counted in bytes
+fn clientid_allowed(cfg: &Builder, client_id: &str) -> bool {
+    client_id.len() <= cfg.max_clientid_len
+}
+
+/// How long a client may stay silent given the keepalive from its CONNECT, None if unlimited
+///
+/// The keepalive is clamped to `min_keepalive..=max_keepalive`, zero falls back to
+/// `max_keepalive` unless `allow_zero_keepalive` is set. The window is then
+/// `keepalive * keepalive_backoff * 1.5`, so 1.125 times the keepalive with the default backoff.
+fn keepalive_window(cfg: &Builder, keep_alive: u16) -> Option<Duration> {
+    let keep_alive = match keep_alive {
+        0 if cfg.allow_zero_keepalive => return None,
+        0 => cfg.max_keepalive,
+        keep_alive => keep_alive.clamp(cfg.min_keepalive, cfg.max_keepalive),
+    };
+    if keep_alive == 0 {
+        return None;
+    }
+    Duration::try_from_secs_f32(f32::from(keep_alive) * cfg.keepalive_backoff * 1.5).ok()
+}
+
+fn log_oversized(cfg: &Builder, remote_addr: SocketAddr) {
+    log::debug!(
+        "{} {} sent a packet over max_packet_size {}, disconnecting",
+        cfg.name,
+        remote_addr,
+        cfg.max_packet_size
+    );
+}
+
+/// Ends a connection from inside `poll_next`, after a refused packet or an idle or keepalive timeout
+///
+/// Nothing can be awaited there: `disconnect` is queued if the write buffer has room and the
+/// close gets as far as it can without blocking. On an oversized packet the codec has already
+/// stopped reading, so its payload is never buffered.
+fn close_now<Io>(io: &mut Framed<Io, MqttCodec>, cx: &mut Context<'_>, disconnect: Option<MqttPacket>)
+where
+    Io: AsyncWrite + Unpin,
+{
+    let mut io = Pin::new(io);
+    if let Some(packet) = disconnect {
+        if let Poll::Ready(Ok(())) = io.as_mut().poll_ready(cx) {
+            let _ = io.as_mut().start_send(packet);
+        }
+    }
+    let _ = io.poll_close(cx);
+}
+
 #[inline]
 async fn send<Io>(io: &mut Framed<Io, MqttCodec>, packet: MqttPacket, send_timeout: Duration) -> Result<()>
 where
@@ -535,3 +2231,546 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use rmqtt_codec::types::QoS;
+    use rmqtt_codec::v5::{
+        Auth, AuthReasonCode, Connect, ConnectAck, ConnectAckReason, Packet, PublishProperties,
+    };
+
+    use super::*;
+
+    /// v5 CONNECT with Clean Start, a 60 second keepalive and client identifier `c1`
+    const CONNECT: &[u8] = &[0x10, 15, 0, 4, b'M', b'Q', b'T', b'T', 5, 0x02, 0, 60, 0, 0, 2, b'c', b'1'];
+
+    fn v5_stream(io: DuplexStream, cfg: &Arc<Builder>) -> v5::MqttStream<DuplexStream> {
+        v5::MqttStream {
+            io: Framed::new(io, MqttCodec::V5(Default::default())),
+            remote_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 1883)),
+            cfg: cfg.clone(),
+            active: Default::default(),
+            idle: Default::default(),
+            keepalive: Default::default(),
+            session: None,
+            topic_levels: Default::default(),
+            subscription_limit: Default::default(),
+            topic_aliases: Default::default(),
+            subscription_ids: Default::default(),
+            send_window: Default::default(),
+            auth: None,
+        }
+    }
+
+    /// A stream that has read `packet`, with the client end of its socket
+    async fn received(cfg: &Arc<Builder>, packet: &[u8]) -> (DuplexStream, v5::MqttStream<DuplexStream>) {
+        let (mut client, server) = duplex(4096);
+        let mut stream = v5_stream(server, cfg);
+        client.write_all(packet).await.unwrap();
+        assert!(matches!(stream.next().await, Some(Ok(_))));
+        (client, stream)
+    }
+
+    async fn read_all(client: &mut DuplexStream) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        client.read_to_end(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn successful_connack_takes_over_the_holder() {
+        let cfg = Arc::new(Builder::default());
+        let (mut first_client, mut first) = received(&cfg, CONNECT).await;
+        first.send_connect_ack(ConnectAck::default()).await.unwrap();
+        first.flush().await.unwrap();
+
+        let (_second_client, mut second) = received(&cfg, CONNECT).await;
+        assert!(!first.session.as_ref().unwrap().is_taken_over());
+        second.send_connect_ack(ConnectAck::default()).await.unwrap();
+        second.flush().await.unwrap();
+
+        let e = first.next().await.unwrap().unwrap_err();
+        assert!(matches!(e.downcast_ref::<MqttError>(), Some(MqttError::SessionTakenOver)));
+        assert!(first.next().await.is_none());
+        drop(first);
+        // CONNACK, then DISCONNECT with Session Taken Over
+        let bytes = read_all(&mut first_client).await;
+        let disconnect = &bytes[2 + bytes[1] as usize..];
+        assert_eq!((disconnect[0], disconnect[2]), (0xE0, 0x8E));
+
+        assert!(cfg.client_registry.contains("c1"));
+        assert!(tokio::time::timeout(Duration::from_millis(50), second.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refused_connect_leaves_the_holder_connected() {
+        let cfg = Arc::new(Builder::default());
+        let (_first_client, mut first) = received(&cfg, CONNECT).await;
+        first.send_connect_ack(ConnectAck::default()).await.unwrap();
+        first.flush().await.unwrap();
+
+        let (mut second_client, mut second) = received(&cfg, CONNECT).await;
+        let ack = ConnectAck { reason_code: ConnectAckReason::NotAuthorized, ..Default::default() };
+        second.send_connect_ack(ack).await.unwrap();
+        second.flush().await.unwrap();
+        drop(second);
+        // CONNACK with Not Authorized
+        let bytes = read_all(&mut second_client).await;
+        assert_eq!((bytes[0], bytes[3]), (0x20, 0x87));
+
+        assert!(!first.session.as_ref().unwrap().is_taken_over());
+        assert!(cfg.client_registry.contains("c1"));
+        assert!(tokio::time::timeout(Duration::from_millis(50), first.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_without_connack_holds_nothing() {
+        let cfg = Arc::new(Builder::default());
+        let (_client, stream) = received(&cfg, CONNECT).await;
+        assert_eq!(stream.session.as_ref().map(|s| s.client_id().as_ref()), Some("c1"));
+        assert!(!cfg.client_registry.contains("c1"));
+    }
+
+    fn secs(secs: f32) -> Option<Duration> {
+        Some(Duration::from_secs_f32(secs))
+    }
+
+    #[test]
+    fn keepalive_window_applies_the_backoff() {
+        let cfg = Builder::default();
+        assert_eq!(super::keepalive_window(&cfg, 60), secs(67.5));
+        let cfg = cfg.keepalive_backoff(1.0);
+        assert_eq!(super::keepalive_window(&cfg, 60), secs(90.0));
+        assert_eq!(super::keepalive_window(&cfg, u16::MAX), secs(98302.5));
+    }
+
+    #[test]
+    fn keepalive_window_clamps_to_the_configured_range() {
+        let cfg = Builder::default().min_keepalive(30).max_keepalive(120).keepalive_backoff(1.0);
+        assert_eq!(super::keepalive_window(&cfg, 5), secs(45.0));
+        assert_eq!(super::keepalive_window(&cfg, 60), secs(90.0));
+        assert_eq!(super::keepalive_window(&cfg, 600), secs(180.0));
+    }
+
+    #[test]
+    fn keepalive_window_for_zero_keepalive() {
+        let cfg = Builder::default().max_keepalive(120).keepalive_backoff(1.0);
+        assert_eq!(super::keepalive_window(&cfg, 0), None);
+        let cfg = cfg.allow_zero_keepalive(false);
+        assert_eq!(super::keepalive_window(&cfg, 0), secs(180.0));
+        // With nothing to fall back to the connection is left unlimited
+        let cfg = cfg.max_keepalive(0);
+        assert_eq!(super::keepalive_window(&cfg, 0), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_timeout_disconnects() {
+        let cfg = Arc::new(Builder::default());
+        let start = tokio::time::Instant::now();
+        let (mut client, mut stream) = received(&cfg, CONNECT).await;
+
+        let e = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(e.downcast_ref::<MqttError>(), Some(MqttError::KeepAliveTimeout)));
+        assert_eq!(start.elapsed(), Duration::from_secs_f32(67.5));
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        // DISCONNECT with Keep Alive Timeout
+        let bytes = read_all(&mut client).await;
+        assert_eq!((bytes[0], bytes[2]), (0xE0, 0x8D));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout_disconnects() {
+        let cfg = Arc::new(Builder::default());
+        let start = tokio::time::Instant::now();
+        let (mut client, mut stream) = received(&cfg, CONNECT).await;
+        stream.idle = IdleTimer::new(secs(10.0));
+
+        let e = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(e.downcast_ref::<MqttError>(), Some(MqttError::IdleTimeout)));
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        drop(stream);
+        // DISCONNECT with Keep Alive Timeout, the code MQTT v5 has for it
+        let bytes = read_all(&mut client).await;
+        assert_eq!((bytes[0], bytes[2]), (0xE0, 0x8D));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn packets_push_the_keepalive_back() {
+        let cfg = Arc::new(Builder::default());
+        let (mut client, mut stream) = received(&cfg, CONNECT).await;
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        client.write_all(&[0xC0, 0]).await.unwrap();
+        assert!(matches!(stream.next().await, Some(Ok(Packet::PingRequest))));
+        let pinged = tokio::time::Instant::now();
+
+        let e = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(e.downcast_ref::<MqttError>(), Some(MqttError::KeepAliveTimeout)));
+        assert_eq!(pinged.elapsed(), Duration::from_secs_f32(67.5));
+    }
+
+    fn publish(topic: &str, alias: Option<u16>) -> Publish {
+        Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: topic.into(),
+            packet_id: None,
+            payload: Bytes::new(),
+            properties: alias
+                .map(|alias| PublishProperties { topic_alias: NonZeroU16::new(alias), ..Default::default() }),
+            delay_interval: None,
+            create_time: None,
+        }
+    }
+
+    /// Topic of `publish` after passing it through `aliases`
+    fn resolve(
+        aliases: &mut TopicAliases,
+        topic: &str,
+        alias: u16,
+    ) -> std::result::Result<ByteString, MqttError> {
+        let mut publish = publish(topic, Some(alias));
+        aliases.resolve(&mut publish).map(|()| publish.topic)
+    }
+
+    #[test]
+    fn topic_alias_maps_and_resolves() {
+        let mut aliases = TopicAliases::new(10);
+        assert_eq!(resolve(&mut aliases, "a/b", 1).unwrap(), "a/b");
+        assert_eq!(resolve(&mut aliases, "", 1).unwrap(), "a/b");
+        // An alias and topic together remap the alias
+        assert_eq!(resolve(&mut aliases, "c/d", 1).unwrap(), "c/d");
+        assert_eq!(resolve(&mut aliases, "", 1).unwrap(), "c/d");
+        assert_eq!(resolve(&mut aliases, "e", 10).unwrap(), "e");
+        assert_eq!(resolve(&mut aliases, "", 10).unwrap(), "e");
+        // Publishes without an alias pass through
+        let mut plain = publish("f", None);
+        aliases.resolve(&mut plain).unwrap();
+        assert_eq!(plain.topic, "f");
+        assert!(!aliases.failed());
+    }
+
+    #[test]
+    fn topic_alias_above_the_maximum_fails_the_stream() {
+        let mut aliases = TopicAliases::new(10);
+        assert!(matches!(resolve(&mut aliases, "a", 11), Err(MqttError::TopicAliasInvalid(11))));
+        assert!(aliases.failed());
+        // Zero disables aliases
+        let mut aliases = TopicAliases::new(0);
+        assert!(matches!(resolve(&mut aliases, "a", 1), Err(MqttError::TopicAliasInvalid(1))));
+        assert!(aliases.failed());
+    }
+
+    #[test]
+    fn unknown_topic_alias_fails_the_stream() {
+        let mut aliases = TopicAliases::new(10);
+        resolve(&mut aliases, "a", 1).unwrap();
+        assert!(matches!(resolve(&mut aliases, "", 2), Err(MqttError::UnknownTopicAlias(2))));
+        assert!(aliases.failed());
+    }
+
+    #[test]
+    fn lowering_the_maximum_drops_the_aliases_above_it() {
+        let mut aliases = TopicAliases::new(10);
+        resolve(&mut aliases, "a", 2).unwrap();
+        resolve(&mut aliases, "b", 8).unwrap();
+        aliases.set_max(5);
+        assert_eq!(aliases.max(), 5);
+        assert_eq!(resolve(&mut aliases, "", 2).unwrap(), "a");
+        assert!(matches!(resolve(&mut aliases, "", 8), Err(MqttError::TopicAliasInvalid(8))));
+    }
+
+    type Client = Framed<DuplexStream, MqttCodec>;
+
+    /// A stream running `hook` for enhanced authentication, with a client framing the other end
+    #[tokio::test]
+    async fn zero_topic_alias_disconnects() {
+        let cfg = Arc::new(Builder::default());
+        let (mut client, mut stream) = received(&cfg, CONNECT).await;
+        // PUBLISH "a" with a Topic Alias property of 0
+        client.write_all(&[0x30, 7, 0, 1, b'a', 3, 0x23, 0, 0]).await.unwrap();
+
+        let e = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<MqttError>(),
+            Some(MqttError::Decode(DecodeError::MalformedPacket))
+        ));
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        // DISCONNECT with Malformed Packet
+        let bytes = read_all(&mut client).await;
+        assert_eq!((bytes[0], bytes[2]), (0xE0, 0x81));
+    }
+
+    fn auth_stream(cfg: &Arc<Builder>, hook: AuthFn) -> (Client, v5::MqttStream<DuplexStream>) {
+        let (client, server) = duplex(4096);
+        let mut stream = v5_stream(server, cfg);
+        stream.auth = Some(AuthHook { hook, completed: None });
+        (Framed::new(client, MqttCodec::V5(Default::default())), stream)
+    }
+
+    async fn send(client: &mut Client, packet: Packet) {
+        client.send(MqttPacket::V5(packet)).await.unwrap();
+    }
+
+    async fn recv(client: &mut Client) -> Packet {
+        match client.next().await {
+            Some(Ok((MqttPacket::V5(packet), _))) => packet,
+            other => panic!("expected a v5 packet, got {other:?}"),
+        }
+    }
+
+    fn auth_connect(method: &str, data: &'static [u8]) -> Packet {
+        Packet::Connect(Box::new(Connect {
+            client_id: "c1".into(),
+            auth_method: Some(method.into()),
+            auth_data: Some(Bytes::from_static(data)),
+            ..Default::default()
+        }))
+    }
+
+    fn auth(method: &str, data: &'static [u8]) -> Packet {
+        Packet::Auth(Auth {
+            reason_code: AuthReasonCode::ContinueAuth,
+            auth_method: Some(method.into()),
+            auth_data: Some(Bytes::from_static(data)),
+            ..Default::default()
+        })
+    }
+
+    /// Challenges the client once, then accepts the expected response
+    fn challenge_response() -> AuthFn {
+        let mut step = 0;
+        Box::new(move |method, data| {
+            step += 1;
+            match (step, method, data.map(|d| d.as_ref())) {
+                (1, "SCRAM", Some(b"first")) => AuthOutcome::Continue(Some(Bytes::from_static(b"challenge"))),
+                (2, "SCRAM", Some(b"response")) => AuthOutcome::Success(Some(Bytes::from_static(b"final"))),
+                _ => AuthOutcome::Fail,
+            }
+        })
+    }
+
+    const TM: Duration = Duration::from_secs(1);
+
+    #[tokio::test]
+    async fn auth_exchange_continues_then_succeeds() {
+        let cfg = Arc::new(Builder::default());
+        let (mut client, mut stream) = auth_stream(&cfg, challenge_response());
+        send(&mut client, auth_connect("SCRAM", b"first")).await;
+
+        let server = async {
+            let connect = stream.recv_connect(TM).await.unwrap();
+            stream.send_connect_ack(ConnectAck::default()).await.unwrap();
+            stream.flush().await.unwrap();
+            connect
+        };
+        let client_side = async {
+            let Packet::Auth(challenge) = recv(&mut client).await else { panic!("expected AUTH") };
+            assert_eq!(challenge.reason_code, AuthReasonCode::ContinueAuth);
+            assert_eq!(challenge.auth_method.as_deref(), Some("SCRAM"));
+            assert_eq!(challenge.auth_data.as_deref(), Some(b"challenge".as_slice()));
+            send(&mut client, auth("SCRAM", b"response")).await;
+            recv(&mut client).await
+        };
+        let (connect, ack) = tokio::join!(server, client_side);
+
+        assert_eq!(connect.client_id, "c1");
+        let Packet::ConnectAck(ack) = ack else { panic!("expected CONNACK") };
+        assert_eq!(ack.reason_code, ConnectAckReason::Success);
+        assert_eq!(ack.auth_method.as_deref(), Some("SCRAM"));
+        assert_eq!(ack.auth_data.as_deref(), Some(b"final".as_slice()));
+        assert!(cfg.client_registry.contains("c1"));
+    }
+
+    #[tokio::test]
+    async fn auth_hook_failure_refuses_the_client() {
+        let cfg = Arc::new(Builder::default());
+        let (mut client, mut stream) = auth_stream(&cfg, challenge_response());
+        send(&mut client, auth_connect("SCRAM", b"wrong")).await;
+
+        let e = stream.recv_connect(TM).await.unwrap_err();
+        assert!(matches!(e.downcast_ref::<MqttError>(), Some(MqttError::AuthFailed)));
+        let Packet::ConnectAck(ack) = recv(&mut client).await else { panic!("expected CONNACK") };
+        assert_eq!(ack.reason_code, ConnectAckReason::NotAuthorized);
+        assert!(!cfg.client_registry.contains("c1"));
+    }
+
+    #[tokio::test]
+    async fn auth_reply_with_another_method_is_a_protocol_error() {
+        let cfg = Arc::new(Builder::default());
+        let (mut client, mut stream) = auth_stream(&cfg, challenge_response());
+        send(&mut client, auth_connect("SCRAM", b"first")).await;
+
+        let client_side = async {
+            assert!(matches!(recv(&mut client).await, Packet::Auth(_)));
+            send(&mut client, auth("OTHER", b"response")).await;
+            recv(&mut client).await
+        };
+        let (res, ack) = tokio::join!(stream.recv_connect(TM), client_side);
+
+        let e = res.unwrap_err();
+        assert!(matches!(e.downcast_ref::<MqttError>(), Some(MqttError::InvalidProtocol)));
+        let Packet::ConnectAck(ack) = ack else { panic!("expected CONNACK") };
+        assert_eq!(ack.reason_code, ConnectAckReason::ProtocolError);
+        assert!(!cfg.client_registry.contains("c1"));
+    }
+
+    #[test]
+    fn topic_filters_match() {
+        let cases = [
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/b", "a/b/c", false),
+            ("a/+", "a/b", true),
+            ("a/+", "a/b/c", false),
+            ("a/+", "a/", true),
+            ("+/+", "a/b", true),
+            ("a/+/c", "a/b/c", true),
+            ("a/#", "a", true),
+            ("a/#", "a/b/c", true),
+            ("a/#", "b/c", false),
+            ("#", "a/b/c", true),
+            ("#", "/a", true),
+            // Wildcards at the first level skip topics starting with `$`
+            ("#", "$SYS/uptime", false),
+            ("+/uptime", "$SYS/uptime", false),
+            ("$SYS/#", "$SYS/uptime", true),
+            ("$SYS/+", "$SYS/uptime", true),
+            // Shared subscriptions match on the filter after the group
+            ("$share/g/a/+", "a/b", true),
+            ("$share/g/a/+", "g/a/b", false),
+            ("$share/g/#", "a/b", true),
+            ("$share/g/#", "$SYS/uptime", false),
+        ];
+        for (filter, topic, expected) in cases {
+            assert_eq!(super::topic_matches(filter, topic), expected, "{filter} against {topic}");
+        }
+    }
+
+    fn qos1(packet_id: u16, payload: &'static [u8]) -> Box<Publish> {
+        let mut publish = publish("a", None);
+        publish.qos = QoS::AtLeastOnce;
+        publish.packet_id = NonZeroU16::new(packet_id);
+        publish.payload = Bytes::from_static(payload);
+        Box::new(publish)
+    }
+
+    /// A v5 stream past its CONNECT whose publishes fail to encode over 30 bytes, as over a
+    /// client's Maximum Packet Size
+    async fn size_limited(max_inflight: u16) -> (DuplexStream, v5::MqttStream<DuplexStream>) {
+        let cfg = Arc::new(Builder::default());
+        let (client, mut stream) = received(&cfg, CONNECT).await;
+        stream.send_window = SendWindow::new(max_inflight);
+        let MqttCodec::V5(codec) = stream.io.codec_mut() else { unreachable!() };
+        codec.set_max_outbound_size(30);
+        (client, stream)
+    }
+
+    #[tokio::test]
+    async fn queued_publish_the_codec_refuses_is_dropped() {
+        let (mut client, mut stream) = size_limited(1).await;
+        stream.send_publish(qos1(1, b"x")).await.unwrap();
+        stream.send_publish(qos1(2, &[0; 64])).await.unwrap();
+        stream.send_publish(qos1(3, b"z")).await.unwrap();
+        assert_eq!(stream.send_window.queued(), 2);
+
+        // The ack frees the slot, the oversized publish is dropped and the one behind it takes it
+        client.write_all(&[0x40, 2, 0, 1]).await.unwrap();
+        assert!(matches!(stream.next().await, Some(Ok(Packet::PublishAck(_)))));
+        assert!(tokio::time::timeout(Duration::from_millis(10), stream.next()).await.is_err());
+        assert_eq!(stream.send_window.refused(), 1);
+        assert_eq!((stream.send_window.queued(), stream.send_window.inflight()), (0, 1));
+        drop(stream);
+        let mut client = Framed::new(client, MqttCodec::V5(Default::default()));
+        for packet_id in [1, 3] {
+            let Packet::Publish(publish) = recv(&mut client).await else { panic!("expected PUBLISH") };
+            assert_eq!(publish.packet_id, NonZeroU16::new(packet_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_that_fails_to_send_frees_its_slot() {
+        let (_client, mut stream) = size_limited(1).await;
+        assert!(stream.send_publish(qos1(1, &[0; 64])).await.is_err());
+        assert_eq!(stream.send_window.inflight(), 0);
+
+        // Not blocked by the failed one
+        stream.send_publish(qos1(2, b"x")).await.unwrap();
+        assert_eq!((stream.send_window.queued(), stream.send_window.inflight()), (0, 1));
+    }
+
+    fn subscribe(id: Option<u32>, filters: &[&str]) -> Subscribe {
+        Subscribe {
+            packet_id: NonZeroU16::MIN,
+            id: id.and_then(NonZeroU32::new),
+            user_properties: Default::default(),
+            topic_filters: filters.iter().map(|f| ((*f).into(), Default::default())).collect(),
+        }
+    }
+
+    fn ids(ids: &[u32]) -> Vec<NonZeroU32> {
+        ids.iter().map(|id| NonZeroU32::new(*id).unwrap()).collect()
+    }
+
+    #[test]
+    fn subscription_ids_of_matching_filters() {
+        let mut subs = SubscriptionIds::default();
+        subs.subscribe(&subscribe(Some(3), &["a/+"]));
+        subs.subscribe(&subscribe(Some(1), &["a/#", "$share/g/a/b"]));
+        subs.subscribe(&subscribe(Some(7), &["$SYS/#"]));
+        subs.subscribe(&subscribe(None, &["a/b/c"]));
+
+        assert_eq!(subs.matching("a/b"), ids(&[1, 3]));
+        assert_eq!(subs.matching("a/b/c"), ids(&[1]));
+        assert_eq!(subs.matching("$SYS/uptime"), ids(&[7]));
+        assert_eq!(subs.matching("b"), ids(&[]));
+    }
+
+    #[tokio::test]
+    async fn zero_subscription_id_disconnects() {
+        let cfg = Arc::new(Builder::default());
+        let (mut client, mut stream) = received(&cfg, CONNECT).await;
+        // SUBSCRIBE to "a" with a Subscription Identifier property of 0
+        client.write_all(&[0x82, 9, 0, 1, 2, 0x0B, 0, 0, 1, b'a', 0]).await.unwrap();
+
+        let e = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<MqttError>(),
+            Some(MqttError::Decode(DecodeError::MalformedPacket))
+        ));
+        assert!(stream.next().await.is_none());
+        assert!(stream.subscription_ids.matching("a").is_empty());
+        drop(stream);
+        // DISCONNECT with Malformed Packet
+        let bytes = read_all(&mut client).await;
+        assert_eq!((bytes[0], bytes[2]), (0xE0, 0x81));
+    }
+
+    #[test]
+    fn subscribing_again_replaces_the_id() {
+        let mut subs = SubscriptionIds::default();
+        subs.subscribe(&subscribe(Some(1), &["a/+", "b"]));
+        subs.subscribe(&subscribe(Some(2), &["a/+"]));
+        assert_eq!(subs.matching("a/x"), ids(&[2]));
+        assert_eq!(subs.matching("b"), ids(&[1]));
+
+        // Without an identifier the new subscription has none
+        subs.subscribe(&subscribe(None, &["a/+"]));
+        assert_eq!(subs.matching("a/x"), ids(&[]));
+
+        let unsubscribe = Unsubscribe {
+            packet_id: NonZeroU16::MIN,
+            user_properties: Default::default(),
+            topic_filters: vec!["b".into()],
+        };
+        subs.unsubscribe(&unsubscribe);
+        assert_eq!(subs.matching("b"), ids(&[]));
+    }
+}
@@ -0,0 +1,159 @@
+//! TLS backends
+//!
+//! OpenSSL is the default. The `rustls` feature swaps in a pure Rust implementation for targets
+//! that cannot link against OpenSSL; when both features are enabled rustls is used. Both backends
+//! are driven by the same `Builder` fields, only the stream type handed to the `Dispatcher`
+//! differs, see [`TlsStream`].
+
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+mod openssl;
+#[cfg(feature = "rustls")]
+mod rustls;
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub(crate) use self::openssl::{
+    accept, build_acceptor, negotiated_alpn, peer_cert_info, reload_ocsp, TlsAcceptor,
+};
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub use self::openssl::{CustomSslAcceptor, TlsStream};
+
+#[cfg(feature = "rustls")]
+pub use self::rustls::TlsStream;
+#[cfg(feature = "rustls")]
+pub(crate) use self::rustls::{
+    accept, build_acceptor, negotiated_alpn, peer_cert_info, reload_ocsp, TlsAcceptor,
+};
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+pub(crate) use self::disabled::{build_acceptor, reload_ocsp, TlsAcceptor};
+
+/// Name of the compiled-in backend, `"none"` without either feature
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+pub const BACKEND: &str = "openssl";
+#[cfg(feature = "rustls")]
+pub const BACKEND: &str = "rustls";
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+pub const BACKEND: &str = "none";
+
+/// PEM encoded certificate or key material held in memory
+///
+/// Its `Debug` output only shows the length, so a logged `Builder` does not leak private keys.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PemBytes(Vec<u8>);
+
+impl From<Vec<u8>> for PemBytes {
+    fn from(pem: Vec<u8>) -> Self {
+        PemBytes(pem)
+    }
+}
+
+impl AsRef<[u8]> for PemBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PemBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PemBytes(<{} bytes>)", self.0.len())
+    }
+}
+
+/// Where the PEM data for a certificate chain or private key comes from
+#[derive(Clone, Copy)]
+#[cfg_attr(not(any(feature = "openssl", feature = "rustls")), allow(dead_code))]
+pub(crate) enum Pem<'a> {
+    File(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl fmt::Display for Pem<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pem::File(path) => f.write_str(path),
+            Pem::Bytes(_) => f.write_str("in-memory PEM"),
+        }
+    }
+}
+
+/// PKCS#12 archive holding the certificate chain and private key
+///
+/// The password is left out of the `Debug` output.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pkcs12Bundle {
+    /// Path to the `.p12`/`.pfx` file
+    pub path: PathBuf,
+    /// Password the archive was exported with, empty if it has none
+    #[serde(default)]
+    pub password: String,
+}
+
+impl fmt::Debug for Pkcs12Bundle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs12Bundle").field("path", &self.path).finish_non_exhaustive()
+    }
+}
+
+/// Certificate chain and private key of the default server certificate
+#[derive(Clone, Copy)]
+#[cfg_attr(not(any(feature = "openssl", feature = "rustls")), allow(dead_code))]
+pub(crate) enum Identity<'a> {
+    Pem {
+        cert: Pem<'a>,
+        key: Pem<'a>,
+    },
+    // Only the OpenSSL backend reads PKCS#12
+    #[cfg_attr(feature = "rustls", allow(dead_code))]
+    Pkcs12(&'a Pkcs12Bundle),
+}
+
+/// Identity of the certificate a client presented during the TLS handshake
+///
+/// The fingerprint is the SHA-256 digest of the DER encoded certificate, which stays stable
+/// across backends and is what device identities are best keyed on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCertInfo {
+    /// Common name of the certificate subject, if it has one
+    pub subject_cn: Option<String>,
+    /// SHA-256 digest of the DER encoded certificate
+    pub fingerprint_sha256: [u8; 32],
+}
+
+impl PeerCertInfo {
+    /// Returns the fingerprint as lowercase hex without separators
+    pub fn fingerprint_hex(&self) -> String {
+        self.fingerprint_sha256.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+impl fmt::Display for PeerCertInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CN={} SHA256={}", self.subject_cn.as_deref().unwrap_or("-"), self.fingerprint_hex())
+    }
+}
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+mod disabled {
+    use anyhow::anyhow;
+
+    use super::Identity;
+    use crate::{Builder, Result};
+
+    /// No backend compiled in, a TLS listener can never be created
+    #[derive(Clone)]
+    pub(crate) enum TlsAcceptor {}
+
+    pub(crate) fn build_acceptor(_cfg: &Builder, _identity: Identity) -> Result<TlsAcceptor> {
+        Err(anyhow!("TLS support requires the `openssl` or `rustls` feature"))
+    }
+
+    pub(crate) fn reload_ocsp(acceptor: &TlsAcceptor) -> Result<()> {
+        match *acceptor {}
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::ocsp::{OcspResponse, OcspResponseStatus};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{
+    AlpnError, NameType, SniError, Ssl, SslAcceptor, SslAcceptorBuilder, SslMethod, SslOptions,
+    SslVerifyMode, SslVersion,
+};
+use openssl::x509::X509;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{Identity, Pem, Pkcs12Bundle};
+use crate::{Builder, PeerCertInfo, Result, TlsVersion};
+
+/// Stream type produced by the OpenSSL backend
+pub type TlsStream<S> = tokio_openssl::SslStream<S>;
+
+#[derive(Clone)]
+pub(crate) struct TlsAcceptor {
+    inner: Arc<SslAcceptor>,
+    ocsp: Option<Arc<OcspStaple>>,
+}
+
+/// OpenSSL acceptor supplied through `Builder::tls_acceptor`
+#[derive(Clone)]
+pub struct CustomSslAcceptor(pub(crate) Arc<SslAcceptor>);
+
+impl fmt::Debug for CustomSslAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomSslAcceptor(..)")
+    }
+}
+
+impl From<&CustomSslAcceptor> for TlsAcceptor {
+    fn from(custom: &CustomSslAcceptor) -> Self {
+        TlsAcceptor { inner: custom.0.clone(), ocsp: None }
+    }
+}
+
+/// OCSP response shared with the status callback, so it can be replaced without a new acceptor
+struct OcspStaple {
+    path: PathBuf,
+    der: RwLock<Option<Vec<u8>>>,
+}
+
+impl OcspStaple {
+    fn load(path: &Path) -> Result<Self> {
+        Ok(Self { path: path.to_path_buf(), der: RwLock::new(read_ocsp_response(path)?) })
+    }
+
+    fn reload(&self) -> Result<()> {
+        let der = read_ocsp_response(&self.path)?;
+        *self.der.write().unwrap_or_else(|e| e.into_inner()) = der;
+        Ok(())
+    }
+}
+
+/// Reads and checks a DER encoded OCSP response, a missing file disables stapling
+fn read_ocsp_response(path: &Path) -> Result<Option<Vec<u8>>> {
+    let der = match std::fs::read(path) {
+        Ok(der) => der,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::warn!("OCSP response {} not found, handshakes proceed without stapling", path.display());
+            return Ok(None);
+        }
+        Err(e) => return Err(anyhow!("Failed to read OCSP response {}: {}", path.display(), e)),
+    };
+
+    let response = OcspResponse::from_der(&der)
+        .map_err(|e| anyhow!("Malformed OCSP response {}: {}", path.display(), e))?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(anyhow!(
+            "OCSP response {} has unsuccessful status {:?}",
+            path.display(),
+            response.status()
+        ));
+    }
+    Ok(Some(der))
+}
+
+/// Creates an OpenSSL acceptor for one certificate/key pair with the shared TLS settings
+fn ssl_acceptor_builder(cfg: &Builder, identity: Identity) -> Result<SslAcceptorBuilder> {
+    // Create OpenSSL acceptor
+    let mut acceptor_builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+
+    // Configure certificates, the leaf goes first and the rest of the chain is sent after it
+    let (pkey, chain) = match identity {
+        Identity::Pem { cert, key } => (load_private_key(key)?, load_chain(cert)?),
+        Identity::Pkcs12(bundle) => load_pkcs12(bundle)?,
+    };
+    let (leaf, intermediates) = chain.split_first().ok_or_else(|| anyhow!("No TLS certificate found"))?;
+    acceptor_builder.set_certificate(leaf)?;
+    for intermediate in intermediates {
+        acceptor_builder.add_extra_chain_cert(intermediate.clone())?;
+    }
+    acceptor_builder.set_private_key(&pkey)?;
+    acceptor_builder.check_private_key()?;
+
+    // mozilla_intermediate turns TLS 1.3 off, the configured range decides instead
+    acceptor_builder.clear_options(SslOptions::NO_TLSV1_3);
+    acceptor_builder.set_min_proto_version(Some(ssl_version(cfg.tls_min_version)))?;
+    acceptor_builder.set_max_proto_version(Some(ssl_version(cfg.tls_max_version)))?;
+
+    // Left alone, mozilla_intermediate's lists apply
+    if let Some(ciphers) = &cfg.tls_ciphers {
+        acceptor_builder.set_cipher_list(ciphers)?;
+    }
+    if let Some(ciphersuites) = &cfg.tls_ciphersuites {
+        acceptor_builder.set_ciphersuites(ciphersuites)?;
+    }
+
+    if !cfg.tls_alpn.is_empty() {
+        let protos = alpn_wire_format(&cfg.tls_alpn)?;
+        acceptor_builder.set_alpn_protos(&protos)?;
+        acceptor_builder.set_alpn_select_callback(move |_ssl, client| {
+            select_alpn(&protos, client).ok_or(AlpnError::ALERT_FATAL)
+        });
+    }
+
+    // Configure client verification
+    if cfg.tls_cross_certificate {
+        // Trust the dedicated client CA and advertise it, or fall back to the server chain
+        let cas = match &cfg.tls_client_ca {
+            Some(client_ca) => {
+                let cas = load_chain(Pem::File(client_ca))?;
+                for ca in &cas {
+                    acceptor_builder.add_client_ca(ca)?;
+                }
+                cas
+            }
+            None => chain,
+        };
+        for ca in cas {
+            acceptor_builder.cert_store_mut().add_cert(ca)?;
+        }
+        acceptor_builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        acceptor_builder.set_verify_depth(cfg.tls_verify_depth);
+    } else {
+        acceptor_builder.set_verify(SslVerifyMode::NONE);
+    }
+
+    Ok(acceptor_builder)
+}
+
+fn load_private_key(key: Pem) -> Result<PKey<Private>> {
+    let pem = match key {
+        Pem::File(key_file) => std::fs::read(key_file)
+            .map_err(|e| anyhow!("Failed to read TLS private key {}: {}", key_file, e))?,
+        Pem::Bytes(pem) => pem.to_vec(),
+    };
+    PKey::private_key_from_pem(&pem).map_err(|e| anyhow!("Failed to load TLS private key {}: {}", key, e))
+}
+
+fn load_chain(cert: Pem) -> Result<Vec<X509>> {
+    let pem = match cert {
+        Pem::File(cert_file) => std::fs::read(cert_file)
+            .map_err(|e| anyhow!("Failed to read TLS certificate chain {}: {}", cert_file, e))?,
+        Pem::Bytes(pem) => pem.to_vec(),
+    };
+    let chain = X509::stack_from_pem(&pem)
+        .map_err(|e| anyhow!("Failed to load TLS certificate chain {}: {}", cert, e))?;
+    if chain.is_empty() {
+        return Err(anyhow!("No certificates found in {}", cert));
+    }
+    Ok(chain)
+}
+
+/// Opens a PKCS#12 bundle, returning its key and the chain with the leaf first
+fn load_pkcs12(bundle: &Pkcs12Bundle) -> Result<(PKey<Private>, Vec<X509>)> {
+    let path = bundle.path.display();
+    let der =
+        std::fs::read(&bundle.path).map_err(|e| anyhow!("Failed to read PKCS#12 bundle {}: {}", path, e))?;
+    let archive = Pkcs12::from_der(&der).map_err(|e| anyhow!("Malformed PKCS#12 bundle {}: {}", path, e))?;
+    let parsed = archive.parse2(&bundle.password).map_err(|e| {
+        // The MAC over the archive is keyed by the password, so a mismatch is reported this way
+        if e.errors().iter().any(|e| e.reason() == Some("mac verify failure")) {
+            anyhow!("Wrong password for PKCS#12 bundle {}", path)
+        } else {
+            anyhow!("Failed to decrypt PKCS#12 bundle {}: {}", path, e)
+        }
+    })?;
+
+    let pkey = parsed.pkey.ok_or_else(|| anyhow!("PKCS#12 bundle {} contains no private key", path))?;
+    let cert = parsed.cert.ok_or_else(|| anyhow!("PKCS#12 bundle {} contains no certificate", path))?;
+    let mut chain = vec![cert];
+    chain.extend(parsed.ca.into_iter().flatten());
+    Ok((pkey, chain))
+}
+
+/// Encodes protocol names as length-prefixed bytes, as used on the wire
+fn alpn_wire_format(protocols: &[String]) -> Result<Vec<u8>> {
+    let mut wire = Vec::new();
+    for protocol in protocols {
+        let len = u8::try_from(protocol.len())
+            .ok()
+            .filter(|len| *len > 0)
+            .ok_or_else(|| anyhow!("Invalid ALPN protocol name {:?}", protocol))?;
+        wire.push(len);
+        wire.extend_from_slice(protocol.as_bytes());
+    }
+    Ok(wire)
+}
+
+/// Returns the first of our protocols the client also offers, borrowed from the client's list
+fn select_alpn<'a>(server: &[u8], client: &'a [u8]) -> Option<&'a [u8]> {
+    let client_protos = || wire_protos(client);
+    wire_protos(server).find_map(|proto| client_protos().find(|offered| *offered == proto))
+}
+
+fn wire_protos(mut wire: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let (len, rest) = wire.split_first()?;
+        let proto = rest.get(..*len as usize)?;
+        wire = &rest[*len as usize..];
+        Some(proto)
+    })
+}
+
+fn ssl_version(version: TlsVersion) -> SslVersion {
+    match version {
+        TlsVersion::Tls12 => SslVersion::TLS1_2,
+        TlsVersion::Tls13 => SslVersion::TLS1_3,
+    }
+}
+
+/// Builds the acceptor for the default certificate, plus the per-SNI ones
+pub(crate) fn build_acceptor(cfg: &Builder, identity: Identity) -> Result<TlsAcceptor> {
+    let mut acceptor_builder = ssl_acceptor_builder(cfg, identity)?;
+
+    if !cfg.tls_sni_certs.is_empty() {
+        let mut sni_contexts = HashMap::new();
+        for (sni, cert, key) in &cfg.tls_sni_certs {
+            let context =
+                ssl_acceptor_builder(cfg, Identity::Pem { cert: Pem::File(cert), key: Pem::File(key) })?
+                    .build()
+                    .into_context();
+            sni_contexts.insert(sni.to_ascii_lowercase(), context);
+        }
+
+        acceptor_builder.set_servername_callback(move |ssl, _alert| {
+            let servername = ssl.servername(NameType::HOST_NAME).map(|name| name.to_ascii_lowercase());
+
+            // Clients without SNI, or with an unknown name, keep the default certificate
+            if let Some(context) = servername.and_then(|name| sni_contexts.get(&name)) {
+                ssl.set_ssl_context(context).map_err(|_| SniError::ALERT_FATAL)?;
+            }
+            Ok(())
+        });
+    }
+
+    // Set on the default context only, the SNI contexts serve other certificates
+    let ocsp = match &cfg.tls_ocsp_response {
+        Some(path) => {
+            let staple = Arc::new(OcspStaple::load(path)?);
+            let callback_staple = staple.clone();
+            acceptor_builder.set_status_callback(move |ssl| {
+                match callback_staple.der.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+                    Some(der) => {
+                        ssl.set_ocsp_status(der)?;
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            })?;
+            Some(staple)
+        }
+        None => None,
+    };
+
+    Ok(TlsAcceptor { inner: Arc::new(acceptor_builder.build()), ocsp })
+}
+
+/// Swaps in the OCSP response currently on disk
+pub(crate) fn reload_ocsp(acceptor: &TlsAcceptor) -> Result<()> {
+    match &acceptor.ocsp {
+        Some(staple) => staple.reload(),
+        None => Err(anyhow!("tls_ocsp_response is not set on this listener")),
+    }
+}
+
+/// Runs the OpenSSL server handshake on an accepted stream
+pub(crate) async fn accept<S>(
+    acceptor: &TlsAcceptor,
+    socket: S,
+    handshake_timeout: Duration,
+) -> Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ssl = Ssl::new(acceptor.inner.context())?;
+    let tls_stream = TlsStream::new(ssl, socket)?;
+
+    // Perform TLS handshake
+    match tokio::time::timeout(handshake_timeout, async {
+        let mut stream = tls_stream;
+        std::pin::Pin::new(&mut stream).accept().await?;
+        Ok::<TlsStream<S>, openssl::ssl::Error>(stream)
+    })
+    .await
+    {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(crate::MqttError::ReadTimeout.into()),
+    }
+}
+
+/// Protocol picked by the ALPN select callback
+pub(crate) fn negotiated_alpn<S>(stream: &TlsStream<S>) -> Option<String> {
+    stream.ssl().selected_alpn_protocol().map(|proto| String::from_utf8_lossy(proto).into_owned())
+}
+
+/// Subject CN and fingerprint of the certificate the client sent, if any
+pub(crate) fn peer_cert_info<S>(stream: &TlsStream<S>) -> Option<PeerCertInfo> {
+    let cert = stream.ssl().peer_certificate()?;
+    let subject_cn = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| std::str::from_utf8(entry.data().as_slice()).ok())
+        .map(String::from);
+    let digest = cert.digest(MessageDigest::sha256()).ok()?;
+    Some(PeerCertInfo { subject_cn, fingerprint_sha256: digest.as_ref().try_into().ok()? })
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::ssl::SslConnector;
+    use openssl::x509::extension::{BasicConstraints, KeyUsage};
+    use openssl::x509::{X509Name, X509NameRef};
+    use tokio::io::duplex;
+
+    use super::*;
+
+    struct Cert {
+        cert: X509,
+        key: PKey<Private>,
+    }
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn name(cn: &str) -> X509Name {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        name.build()
+    }
+
+    /// Issues a certificate for `cn`, self-signed without an issuer
+    fn issue(cn: &str, serial: u32, ca: bool, issuer: Option<&Cert>) -> Cert {
+        let key = key();
+        let subject = name(cn);
+        let (issuer_name, issuer_key): (&X509NameRef, &PKey<Private>) = match issuer {
+            Some(issuer) => (issuer.cert.subject_name(), &issuer.key),
+            None => (&subject, &key),
+        };
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(issuer_name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        if ca {
+            builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+            builder.append_extension(KeyUsage::new().key_cert_sign().crl_sign().build().unwrap()).unwrap();
+        }
+        builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+        Cert { cert: builder.build(), key }
+    }
+
+    /// Handshakes a client presenting `leaf` and `intermediates` with a server requiring
+    /// client certificates under `root`
+    async fn handshake(root: &Cert, leaf: &Cert, intermediates: &[&Cert], depth: u32) -> Result<()> {
+        let server = issue("server", 10, false, Some(root));
+        // Without tls_client_ca the server chain is trusted, the root is appended to it
+        let cert = [server.cert.to_pem().unwrap(), root.cert.to_pem().unwrap()].concat();
+        let key = server.key.private_key_to_pem_pkcs8().unwrap();
+        let cfg = Builder::new().tls_cross_certificate(true).tls_verify_depth(depth);
+        let acceptor =
+            build_acceptor(&cfg, Identity::Pem { cert: Pem::Bytes(&cert), key: Pem::Bytes(&key) })?;
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_certificate(&leaf.cert).unwrap();
+        for intermediate in intermediates {
+            connector.add_extra_chain_cert(intermediate.cert.clone()).unwrap();
+        }
+        connector.set_private_key(&leaf.key).unwrap();
+        let ssl = connector.build().configure().unwrap().into_ssl("server").unwrap();
+
+        let (client_io, server_io) = duplex(16 * 1024);
+        let client = async move {
+            let mut stream = TlsStream::new(ssl, client_io).unwrap();
+            let _ = std::pin::Pin::new(&mut stream).connect().await;
+            stream
+        };
+        let (server, _client) = tokio::join!(accept(&acceptor, server_io, Duration::from_secs(5)), client);
+        server.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn verify_depth_limits_the_client_chain() {
+        let root = issue("root", 1, true, None);
+        let regional = issue("regional", 2, true, Some(&root));
+        let issuing = issue("issuing", 3, true, Some(&regional));
+        let device = issue("device", 4, false, Some(&issuing));
+        let direct = issue("direct", 5, false, Some(&root));
+
+        // Two intermediates need a depth of at least 2
+        assert!(handshake(&root, &device, &[&issuing, &regional], 4).await.is_ok());
+        assert!(handshake(&root, &device, &[&issuing, &regional], 2).await.is_ok());
+        assert!(handshake(&root, &device, &[&issuing, &regional], 1).await.is_err());
+        assert!(handshake(&root, &direct, &[], 1).await.is_ok());
+        // The chain must still lead to the trusted root
+        assert!(handshake(&root, &device, &[&issuing], 4).await.is_err());
+    }
+}
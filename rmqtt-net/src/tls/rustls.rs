@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{Identity, Pem};
+use crate::{Builder, PeerCertInfo, Result, TlsVersion};
+
+/// Stream type produced by the rustls backend
+pub type TlsStream<S> = tokio_rustls::server::TlsStream<S>;
+
+pub(crate) type TlsAcceptor = tokio_rustls::TlsAcceptor;
+
+fn load_certs(cert: Pem) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = match cert {
+        Pem::File(cert_file) => CertificateDer::pem_file_iter(cert_file)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>()),
+        Pem::Bytes(pem) => CertificateDer::pem_slice_iter(pem).collect::<std::result::Result<Vec<_>, _>>(),
+    }
+    .map_err(|e| anyhow!("Failed to load TLS certificate chain {}: {}", cert, e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", cert));
+    }
+    Ok(certs)
+}
+
+fn load_certified_key(provider: &CryptoProvider, cert: Pem, key: Pem) -> Result<Arc<CertifiedKey>> {
+    let key_der = match key {
+        Pem::File(key_file) => PrivateKeyDer::from_pem_file(key_file),
+        Pem::Bytes(pem) => PrivateKeyDer::from_pem_slice(pem),
+    }
+    .map_err(|e| anyhow!("Failed to load TLS private key {}: {}", key, e))?;
+    let key = provider.key_provider.load_private_key(key_der)?;
+    Ok(Arc::new(CertifiedKey::new(load_certs(cert)?, key)))
+}
+
+/// Picks the certificate by SNI hostname, falling back to the default one
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniResolver").field("names", &self.by_name.keys()).finish()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certified_key = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+        Some(certified_key.clone())
+    }
+}
+
+/// Builds the rustls acceptor for the default certificate, plus the per-SNI ones
+///
+/// rustls has no CBC suites, so clients that only speak legacy suites such as
+/// `ECDHE-RSA-AES256-SHA` cannot connect through this backend.
+pub(crate) fn build_acceptor(cfg: &Builder, identity: Identity) -> Result<TlsAcceptor> {
+    let Identity::Pem { cert, key } = identity else {
+        return Err(anyhow!("tls_pkcs12 is only supported by the OpenSSL backend"));
+    };
+    // The cipher strings use OpenSSL syntax, there is no faithful mapping to rustls suites
+    if cfg.tls_ciphers.is_some() || cfg.tls_ciphersuites.is_some() {
+        return Err(anyhow!("tls_ciphers/tls_ciphersuites are only supported by the OpenSSL backend"));
+    }
+    if cfg.tls_ocsp_response.is_some() {
+        return Err(anyhow!("tls_ocsp_response is only supported by the OpenSSL backend"));
+    }
+
+    let provider = Arc::new(ring::default_provider());
+
+    let mut by_name = HashMap::new();
+    for (sni, cert, key) in &cfg.tls_sni_certs {
+        by_name.insert(
+            sni.to_ascii_lowercase(),
+            load_certified_key(&provider, Pem::File(cert), Pem::File(key))?,
+        );
+    }
+    let resolver = SniResolver { default: load_certified_key(&provider, cert, key)?, by_name };
+
+    let versions: Vec<&'static rustls::SupportedProtocolVersion> =
+        [(TlsVersion::Tls12, &rustls::version::TLS12), (TlsVersion::Tls13, &rustls::version::TLS13)]
+            .into_iter()
+            .filter(|(version, _)| (cfg.tls_min_version..=cfg.tls_max_version).contains(version))
+            .map(|(_, supported)| supported)
+            .collect();
+
+    let config_builder =
+        ServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(&versions)?;
+
+    // Configure client verification
+    let config_builder = if cfg.tls_cross_certificate {
+        let mut roots = RootCertStore::empty();
+        // Without a dedicated client CA the server cert doubles as one
+        let client_ca = cfg.tls_client_ca.as_deref().map_or(cert, Pem::File);
+        for ca in load_certs(client_ca)? {
+            roots.add(ca)?;
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+        config_builder.with_client_cert_verifier(verifier)
+    } else {
+        config_builder.with_no_client_auth()
+    };
+
+    let mut config = config_builder.with_cert_resolver(Arc::new(resolver));
+    // rustls aborts the handshake itself when the client offers no protocol from this list
+    config.alpn_protocols = cfg.tls_alpn.iter().map(|proto| proto.as_bytes().to_vec()).collect();
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Runs the rustls server handshake on an accepted stream
+pub(crate) async fn accept<S>(
+    acceptor: &TlsAcceptor,
+    socket: S,
+    handshake_timeout: Duration,
+) -> Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match tokio::time::timeout(handshake_timeout, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(crate::MqttError::ReadTimeout.into()),
+    }
+}
+
+/// OCSP stapling cannot be configured with this backend, see `build_acceptor`
+pub(crate) fn reload_ocsp(_acceptor: &TlsAcceptor) -> Result<()> {
+    Err(anyhow!("tls_ocsp_response is only supported by the OpenSSL backend"))
+}
+
+/// Protocol agreed on through ALPN
+pub(crate) fn negotiated_alpn<S>(stream: &TlsStream<S>) -> Option<String> {
+    stream.get_ref().1.alpn_protocol().map(|proto| String::from_utf8_lossy(proto).into_owned())
+}
+
+/// Subject CN and fingerprint of the certificate the client sent, if any
+pub(crate) fn peer_cert_info<S>(stream: &TlsStream<S>) -> Option<PeerCertInfo> {
+    let cert = stream.get_ref().1.peer_certificates()?.first()?;
+    let digest = ::ring::digest::digest(&::ring::digest::SHA256, cert.as_ref());
+    Some(PeerCertInfo {
+        subject_cn: subject_cn(cert.as_ref()),
+        fingerprint_sha256: digest.as_ref().try_into().ok()?,
+    })
+}
+
+/// Finds the subject common name in a DER certificate
+///
+/// rustls hands out the raw certificate only, walking the few DER elements up to the subject is
+/// simpler than pulling in a full X.509 parser.
+fn subject_cn(cert: &[u8]) -> Option<String> {
+    const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(certificate)?;
+    // Optional [0] version, then serial, signature algorithm, issuer and validity
+    let (tag, _, rest) = der_element(tbs)?;
+    if tag == 0xA0 {
+        tbs = rest;
+    }
+    for _ in 0..4 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (_, mut subject, _) = der_element(tbs)?;
+
+    // Name ::= SEQUENCE OF SET OF SEQUENCE { type OID, value ANY }
+    while !subject.is_empty() {
+        let (_, set, rest) = der_element(subject)?;
+        subject = rest;
+        let (_, attribute, _) = der_element(set)?;
+        let (oid_tag, oid, value) = der_element(attribute)?;
+        if oid_tag == 0x06 && oid == OID_COMMON_NAME {
+            let (_, value, _) = der_element(value)?;
+            return std::str::from_utf8(value).ok().map(String::from);
+        }
+    }
+    None
+}
+
+/// Splits one DER element into its tag, contents and whatever follows it
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7F) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
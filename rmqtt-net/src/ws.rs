@@ -0,0 +1,255 @@
+//! WebSocket transport for MQTT over `ws://`
+//!
+//! The HTTP upgrade is handled here rather than by tungstenite so that plain HTTP requests can be
+//! answered with a proper `400 Bad Request`, or `404 Not Found` outside `ws_path`, instead of having
+//! the connection dropped.
+
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::anyhow;
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::{Error as WSError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::bytes::Bytes;
+
+use crate::health::{self, path_matches};
+use crate::{Builder, MqttError, Result};
+
+/// Upper bound for the upgrade request, anything larger is not a sane MQTT client
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32;
+const BAD_REQUEST: &str = "400 Bad Request";
+const NOT_FOUND: &str = "404 Not Found";
+
+/// Byte stream over binary WebSocket messages, so the MQTT codec can sit on top of it
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    cached_data: Option<Bytes>,
+    idx: usize,
+}
+
+impl<S> WsStream<S> {
+    pub(crate) fn new(inner: WebSocketStream<S>) -> Self {
+        Self { inner, cached_data: None, idx: 0 }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(cached_data) = self.cached_data.take() {
+                let cached_buf = &cached_data[self.idx..];
+                let len = cached_buf.len().min(buf.remaining());
+                buf.put_slice(&cached_buf[..len]);
+                if len < cached_buf.len() {
+                    self.idx += len;
+                    self.cached_data = Some(cached_data);
+                } else {
+                    self.idx = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                // An empty read would look like EOF to the codec
+                Some(Ok(Message::Binary(data))) if data.is_empty() => {}
+                Some(Ok(Message::Binary(data))) => {
+                    self.cached_data = Some(data);
+                }
+                // Control frames are answered by tungstenite, text frames are not valid MQTT
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "MQTT over WebSocket requires binary frames",
+                    )));
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(to_error(e))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let Err(e) = ready!(Pin::new(&mut self.inner).poll_ready(cx)) {
+            return Poll::Ready(Err(to_error(e)));
+        }
+        if let Err(e) = Pin::new(&mut self.inner).start_send(Message::Binary(Bytes::copy_from_slice(buf))) {
+            return Poll::Ready(Err(to_error(e)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(to_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(to_error)
+    }
+}
+
+fn to_error(e: WSError) -> io::Error {
+    match e {
+        WSError::ConnectionClosed => io::Error::from(ErrorKind::ConnectionAborted),
+        WSError::AlreadyClosed => io::Error::from(ErrorKind::NotConnected),
+        WSError::Io(io_e) => io_e,
+        _ => io::Error::other(e.to_string()),
+    }
+}
+
+/// Performs the server side of the HTTP upgrade and wraps the stream for MQTT
+pub(crate) async fn accept<S>(mut stream: S, cfg: &Builder) -> Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let (key, subprotocol, header_len) = loop {
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("WebSocket client closed the connection during the upgrade"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(header_len)) => {
+                if let Some(health_path) = &cfg.health_path {
+                    if req.method == Some("GET") && path_matches(req.path, health_path) {
+                        health::respond(&mut stream, health::OK_RESPONSE).await;
+                        return Err(MqttError::HealthCheck.into());
+                    }
+                }
+                if !path_matches(req.path, &cfg.ws_path) {
+                    return Err(reject(&mut stream, NOT_FOUND, "No WebSocket endpoint at this path").await);
+                }
+                match validate_upgrade(&req, &cfg.ws_subprotocols) {
+                    Ok((key, subprotocol)) => break (key, subprotocol, header_len),
+                    Err(reason) => return Err(reject(&mut stream, BAD_REQUEST, reason).await),
+                }
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_LEN => {}
+            Ok(httparse::Status::Partial) => {
+                return Err(reject(&mut stream, BAD_REQUEST, "Request header too large").await);
+            }
+            Err(e) => {
+                return Err(reject(&mut stream, BAD_REQUEST, &format!("Malformed HTTP request: {e}")).await)
+            }
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes()),
+        subprotocol
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+
+    // The client may pipeline its CONNECT right behind the upgrade request
+    let ws_stream =
+        WebSocketStream::from_partially_read(stream, buf.split_off(header_len), Role::Server, None).await;
+    Ok(WsStream::new(ws_stream))
+}
+
+/// Returns the `Sec-WebSocket-Key` of a valid MQTT upgrade request and the subprotocol to echo
+fn validate_upgrade<'a>(
+    req: &httparse::Request,
+    subprotocols: &'a [String],
+) -> std::result::Result<(String, &'a str), &'static str> {
+    if req.method != Some("GET") {
+        return Err("WebSocket upgrade must use GET");
+    }
+
+    let header = |name: &str| {
+        req.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+            .map(str::trim)
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err("Expected a WebSocket upgrade request");
+    }
+    if header("Sec-WebSocket-Version") != Some("13") {
+        return Err("Unsupported Sec-WebSocket-Version, expected 13");
+    }
+    // The client lists its subprotocols by preference, the first one we also accept wins
+    let offered = header("Sec-WebSocket-Protocol").ok_or("Missing Sec-WebSocket-Protocol header")?;
+    let subprotocol = offered
+        .split(',')
+        .map(str::trim)
+        .find_map(|offer| subprotocols.iter().find(|p| p.eq_ignore_ascii_case(offer)))
+        .ok_or("No supported subprotocol in Sec-WebSocket-Protocol")?;
+
+    let key = header("Sec-WebSocket-Key").ok_or("Missing Sec-WebSocket-Key header")?;
+    Ok((key.to_string(), subprotocol))
+}
+
+/// Answers a failed upgrade with `status` and returns the matching error
+async fn reject<S>(stream: &mut S, status: &str, reason: &str) -> anyhow::Error
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Connection: close\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Length: {}\r\n\r\n{}",
+        status,
+        reason.len(),
+        reason
+    );
+    // The connection is dropped either way, a failed write changes nothing
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+    anyhow!("WebSocket upgrade rejected: {reason}")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn empty_binary_frames_are_skipped() {
+        let (client, server) = duplex(1024);
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let mut server = WsStream::new(WebSocketStream::from_raw_socket(server, Role::Server, None).await);
+
+        client.send(Message::Binary(Bytes::new())).await.unwrap();
+        client.send(Message::Binary(Bytes::from_static(b"ab"))).await.unwrap();
+        let mut buf = [0u8; 8];
+        let n = server.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ab");
+    }
+}
@@ -1,33 +1,274 @@
-const CRC16TAB: [u16; 256] = [
-    0x0000, 0x1021, 0x2042, 0x3063, 0x4084, 0x50a5, 0x60c6, 0x70e7, 0x8108, 0x9129, 0xa14a, 0xb16b,
-    0xc18c, 0xd1ad, 0xe1ce, 0xf1ef, 0x1231, 0x0210, 0x3273, 0x2252, 0x52b5, 0x4294, 0x72f7, 0x62d6,
-    0x9339, 0x8318, 0xb37b, 0xa35a, 0xd3bd, 0xc39c, 0xf3ff, 0xe3de, 0x2462, 0x3443, 0x0420, 0x1401,
-    0x64e6, 0x74c7, 0x44a4, 0x5485, 0xa56a, 0xb54b, 0x8528, 0x9509, 0xe5ee, 0xf5cf, 0xc5ac, 0xd58d,
-    0x3653, 0x2672, 0x1611, 0x0630, 0x76d7, 0x66f6, 0x5695, 0x46b4, 0xb75b, 0xa77a, 0x9719, 0x8738,
-    0xf7df, 0xe7fe, 0xd79d, 0xc7bc, 0x48c4, 0x58e5, 0x6886, 0x78a7, 0x0840, 0x1861, 0x2802, 0x3823,
-    0xc9cc, 0xd9ed, 0xe98e, 0xf9af, 0x8948, 0x9969, 0xa90a, 0xb92b, 0x5af5, 0x4ad4, 0x7ab7, 0x6a96,
-    0x1a71, 0x0a50, 0x3a33, 0x2a12, 0xdbfd, 0xcbdc, 0xfbbf, 0xeb9e, 0x9b79, 0x8b58, 0xbb3b, 0xab1a,
-    0x6ca6, 0x7c87, 0x4ce4, 0x5cc5, 0x2c22, 0x3c03, 0x0c60, 0x1c41, 0xedae, 0xfd8f, 0xcdec, 0xddcd,
-    0xad2a, 0xbd0b, 0x8d68, 0x9d49, 0x7e97, 0x6eb6, 0x5ed5, 0x4ef4, 0x3e13, 0x2e32, 0x1e51, 0x0e70,
-    0xff9f, 0xefbe, 0xdfdd, 0xcffc, 0xbf1b, 0xaf3a, 0x9f59, 0x8f78, 0x9188, 0x81a9, 0xb1ca, 0xa1eb,
-    0xd10c, 0xc12d, 0xf14e, 0xe16f, 0x1080, 0x00a1, 0x30c2, 0x20e3, 0x5004, 0x4025, 0x7046, 0x6067,
-    0x83b9, 0x9398, 0xa3fb, 0xb3da, 0xc33d, 0xd31c, 0xe37f, 0xf35e, 0x02b1, 0x1290, 0x22f3, 0x32d2,
-    0x4235, 0x5214, 0x6277, 0x7256, 0xb5ea, 0xa5cb, 0x95a8, 0x8589, 0xf56e, 0xe54f, 0xd52c, 0xc50d,
-    0x34e2, 0x24c3, 0x14a0, 0x0481, 0x7466, 0x6447, 0x5424, 0x4405, 0xa7db, 0xb7fa, 0x8799, 0x97b8,
-    0xe75f, 0xf77e, 0xc71d, 0xd73c, 0x26d3, 0x36f2, 0x0691, 0x16b0, 0x6657, 0x7676, 0x4615, 0x5634,
-    0xd94c, 0xc96d, 0xf90e, 0xe92f, 0x99c8, 0x89e9, 0xb98a, 0xa9ab, 0x5844, 0x4865, 0x7806, 0x6827,
-    0x18c0, 0x08e1, 0x3882, 0x28a3, 0xcb7d, 0xdb5c, 0xeb3f, 0xfb1e, 0x8bf9, 0x9bd8, 0xabbb, 0xbb9a,
-    0x4a75, 0x5a54, 0x6a37, 0x7a16, 0x0af1, 0x1ad0, 0x2ab3, 0x3a92, 0xfd2e, 0xed0f, 0xdd6c, 0xcd4d,
-    0xbdaa, 0xad8b, 0x9de8, 0x8dc9, 0x7c26, 0x6c07, 0x5c64, 0x4c45, 0x3ca2, 0x2c83, 0x1ce0, 0x0cc1,
-    0xef1f, 0xff3e, 0xcf5d, 0xdf7c, 0xaf9b, 0xbfba, 0x8fd9, 0x9ff8, 0x6e17, 0x7e36, 0x4e55, 0x5e74,
-    0x2e93, 0x3eb2, 0x0ed1, 0x1ef0,
-];
+/// Lookup table for `Crc16::DEFAULT`, other parameters take the bitwise path.
+const CRC16TAB: [u16; 256] = table(Crc16::DEFAULT.poly);
+
+/// Builds the byte-at-a-time table for a non-reflected `poly`.
+const fn table(poly: u16) -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ poly
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC16 parameters, shifting out the high bit first unless `reflect` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc16 {
+    pub poly: u16,
+    pub init: u16,
+    pub reflect: bool,
+}
+
+impl Crc16 {
+    /// CRC-16/XMODEM (poly 0x1021, init 0, not reflected, no final xor), used by the LG devices.
+    ///
+    /// Check values: `b"123456789"` gives 0x31C3 and empty input gives 0x0000, the init value.
+    pub const DEFAULT: Crc16 = Crc16 {
+        poly: 0x1021,
+        init: 0,
+        reflect: false,
+    };
+    #[allow(dead_code)]
+    pub const CCITT_FALSE: Crc16 = Crc16 {
+        poly: 0x1021,
+        init: 0xFFFF,
+        reflect: false,
+    };
+    #[allow(dead_code)]
+    pub const MODBUS: Crc16 = Crc16 {
+        poly: 0x8005,
+        init: 0xFFFF,
+        reflect: true,
+    };
+
+    pub fn compute(&self, data: &[u8]) -> u16 {
+        let mut state = self.state();
+        state.update(data);
+        state.finalize()
+    }
+
+    /// Starts a checksum fed in chunks, see `Crc16State`.
+    pub fn state(&self) -> Crc16State {
+        Crc16State {
+            params: *self,
+            // The reflected loop works on the bit-reversed register
+            crc: if self.reflect {
+                self.init.reverse_bits()
+            } else {
+                self.init
+            },
+        }
+    }
+}
+
+/// Checksum in progress, for frames that arrive in pieces.
+///
+/// Feeding a frame in several `update` calls gives the same result as `Crc16::compute` on the
+/// whole of it.
+#[derive(Debug, Clone)]
+pub struct Crc16State {
+    params: Crc16,
+    crc: u16,
+}
+
+impl Default for Crc16State {
+    fn default() -> Self {
+        Crc16::DEFAULT.state()
+    }
+}
+
+impl Crc16State {
+    pub fn update(&mut self, bytes: &[u8]) {
+        let Crc16 { poly, reflect, .. } = self.params;
+
+        if self.params == Crc16::DEFAULT {
+            for &byte in bytes {
+                let idx = ((self.crc >> 8) ^ byte as u16) & 0x00FF;
+                self.crc = (self.crc << 8) ^ CRC16TAB[idx as usize];
+            }
+        } else if reflect {
+            let poly = poly.reverse_bits();
+            for &byte in bytes {
+                self.crc ^= byte as u16;
+                for _ in 0..8 {
+                    self.crc = if self.crc & 1 != 0 {
+                        (self.crc >> 1) ^ poly
+                    } else {
+                        self.crc >> 1
+                    };
+                }
+            }
+        } else {
+            for &byte in bytes {
+                self.crc ^= (byte as u16) << 8;
+                for _ in 0..8 {
+                    self.crc = if self.crc & 0x8000 != 0 {
+                        (self.crc << 1) ^ poly
+                    } else {
+                        self.crc << 1
+                    };
+                }
+            }
+        }
+    }
+
+    pub fn finalize(self) -> u16 {
+        self.crc
+    }
+}
 
 pub fn crc16(data: &[u8]) -> u16 {
-    let mut crc: u16 = 0;
-    for &byte in data {
-        let idx = ((crc >> 8) ^ byte as u16) & 0x00FF;
-        crc = ((crc << 8) ^ CRC16TAB[idx as usize]) & 0xFFFF;
+    Crc16::DEFAULT.compute(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bit at a time CRC straight from the parameters, with no table and no shared state code.
+    fn reference(params: Crc16, data: &[u8]) -> u16 {
+        let mut crc = params.init;
+        for &byte in data {
+            let byte = if params.reflect {
+                byte.reverse_bits()
+            } else {
+                byte
+            };
+            crc ^= (byte as u16) << 8;
+            for _ in 0..8 {
+                crc = if crc & 0x8000 != 0 {
+                    (crc << 1) ^ params.poly
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        if params.reflect {
+            crc.reverse_bits()
+        } else {
+            crc
+        }
+    }
+
+    /// xorshift32, enough to spread inputs without pulling in a crate.
+    fn random_bytes(seed: &mut u32, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 17;
+                *seed ^= *seed << 5;
+                *seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn check_values() {
+        assert_eq!(Crc16::DEFAULT.compute(b"123456789"), 0x31C3);
+        assert_eq!(Crc16::CCITT_FALSE.compute(b"123456789"), 0x29B1);
+        assert_eq!(Crc16::MODBUS.compute(b"123456789"), 0x4B37);
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn empty_input_gives_the_init_value() {
+        assert_eq!(Crc16::DEFAULT.compute(&[]), 0x0000);
+        assert_eq!(Crc16::CCITT_FALSE.compute(&[]), 0xFFFF);
+        assert_eq!(Crc16::MODBUS.compute(&[]), 0xFFFF);
+    }
+
+    #[test]
+    fn rac_report_frame() {
+        // A RAC status report (current temperature 24 °C, mode cool) in the layout
+        // `DeviceManager` accepts. The trailing CRC was worked out with a separate bitwise
+        // XMODEM, not with this module.
+        let frame = [
+            0x01, 0x01, 0x04, 0x00, 0x00, 0x00, 0x87, 0x02, 0x04, 0x01, 0x05, 0x7F, 0x50, 0x30,
+            0x7E, 0x40, 0x9C, 0x72,
+        ];
+
+        assert_eq!(crc16(&frame[2..frame.len() - 2]), 0x9C72);
+    }
+
+    #[test]
+    fn chunked_updates_match_one_shot() {
+        let frame = [
+            0x04, 0x00, 0x00, 0x00, 0x87, 0x02, 0x04, 0x01, 0x05, 0x7F, 0x50, 0x30, 0x7E, 0x40,
+        ];
+
+        for params in [Crc16::DEFAULT, Crc16::CCITT_FALSE, Crc16::MODBUS] {
+            let whole = params.compute(&frame);
+
+            for split in 0..=frame.len() {
+                let (head, tail) = frame.split_at(split);
+                let mut state = params.state();
+                state.update(head);
+                state.update(tail);
+                assert_eq!(state.finalize(), whole, "{params:?} split at {split}");
+            }
+
+            let mut state = params.state();
+            for byte in frame.chunks(1) {
+                state.update(byte);
+            }
+            assert_eq!(state.finalize(), whole, "{params:?} byte by byte");
+        }
+    }
+
+    #[test]
+    fn default_state_is_xmodem() {
+        let mut state = Crc16State::default();
+        state.update(b"1234");
+        state.update(b"56789");
+        assert_eq!(state.finalize(), 0x31C3);
+    }
+
+    #[test]
+    fn table_matches_the_bitwise_definition() {
+        for (i, &entry) in CRC16TAB.iter().enumerate() {
+            assert_eq!(entry, reference(Crc16::DEFAULT, &[i as u8]), "entry {i}");
+        }
+    }
+
+    #[test]
+    fn table_driven_crc_matches_the_reference_on_edge_inputs() {
+        let inputs: [&[u8]; 6] = [&[], &[0x00], &[0xFF], &[0x80], &[0x00; 64], &[0xFF; 64]];
+
+        for params in [Crc16::DEFAULT, Crc16::CCITT_FALSE, Crc16::MODBUS] {
+            for input in inputs {
+                assert_eq!(
+                    params.compute(input),
+                    reference(params, input),
+                    "{params:?} {input:02x?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn table_driven_crc_matches_the_reference_on_random_inputs() {
+        let mut seed = 0x2545_F491;
+
+        for params in [Crc16::DEFAULT, Crc16::CCITT_FALSE, Crc16::MODBUS] {
+            for len in 0..300 {
+                let input = random_bytes(&mut seed, len);
+                assert_eq!(
+                    params.compute(&input),
+                    reference(params, &input),
+                    "{params:?} {input:02x?}"
+                );
+            }
+        }
     }
-    crc
 }
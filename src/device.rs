@@ -1,31 +1,51 @@
+use async_trait::async_trait;
 use rmqtt::context::ServerContext;
 use rumqttc::AsyncClient;
+use serde::Deserialize;
 use serde_json::json;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
 
 use crate::{
+    clock::Clock,
     crc16::crc16,
-    tlv::{build_tlv, Tlv},
+    reload::SharedLiveConf,
+    tlv::{build_tlv_into, dump, Tlv, TlvBuilder, TlvError, TlvIter},
 };
 
 #[allow(non_camel_case_types)]
 #[derive(Clone)]
 pub enum DeviceTypes {
-    RAC_056905_WW,
+    RAC_056905_WW(TemperatureUnit),
     CST_570004_WW,
 }
 
 impl DeviceTypes {
+    pub fn new(kind: &str, temperature_unit: TemperatureUnit) -> Self {
+        match kind {
+            "RAC_056905_WW" => DeviceTypes::RAC_056905_WW(temperature_unit),
+            "CST_570004_WW" => DeviceTypes::CST_570004_WW,
+            _ => panic!("unknown device"),
+        }
+    }
+
     fn get_ha_class(&self) -> String {
         match self {
-            Self::RAC_056905_WW => crate::devices::RAC_056905_WW::RAC_056905_WW.get_ha_class(),
+            Self::RAC_056905_WW(unit) => {
+                crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit).get_ha_class()
+            }
             Self::CST_570004_WW => crate::devices::CST_570004_WW::CST_570004_WW.get_ha_class(),
         }
     }
 
     fn get_model(&self) -> String {
         match self {
-            Self::RAC_056905_WW => crate::devices::RAC_056905_WW::RAC_056905_WW.get_model(),
+            Self::RAC_056905_WW(unit) => {
+                crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit).get_model()
+            }
             Self::CST_570004_WW => crate::devices::CST_570004_WW::CST_570004_WW.get_model(),
         }
     }
@@ -36,9 +56,8 @@ impl DeviceTypes {
         ponder_prefix: String,
     ) -> serde_json::Map<String, serde_json::Value> {
         match self {
-            Self::RAC_056905_WW => {
-                crate::devices::RAC_056905_WW::RAC_056905_WW.get_inner_config(id, ponder_prefix)
-            }
+            Self::RAC_056905_WW(unit) => crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit)
+                .get_inner_config(id, ponder_prefix),
             Self::CST_570004_WW => {
                 crate::devices::CST_570004_WW::CST_570004_WW.get_inner_config(id, ponder_prefix)
             }
@@ -47,21 +66,249 @@ impl DeviceTypes {
 
     fn get_field_by_id(&self, t: u16) -> Option<Box<dyn Field>> {
         match self {
-            Self::RAC_056905_WW => crate::devices::RAC_056905_WW::RAC_056905_WW.get_field_by_id(t),
+            Self::RAC_056905_WW(unit) => {
+                crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit).get_field_by_id(t)
+            }
             Self::CST_570004_WW => crate::devices::CST_570004_WW::CST_570004_WW.get_field_by_id(t),
         }
     }
 
     fn get_field_by_ha(&self, prop: String) -> Option<Box<dyn Field>> {
         match self {
-            Self::RAC_056905_WW => {
-                crate::devices::RAC_056905_WW::RAC_056905_WW.get_field_by_ha(prop)
+            Self::RAC_056905_WW(unit) => {
+                crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit).get_field_by_ha(prop)
             }
             Self::CST_570004_WW => {
                 crate::devices::CST_570004_WW::CST_570004_WW.get_field_by_ha(prop)
             }
         }
     }
+
+    fn get_number_fields(&self) -> Vec<Box<dyn Field>> {
+        match self {
+            Self::RAC_056905_WW(unit) => {
+                crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit).get_number_fields()
+            }
+            Self::CST_570004_WW => crate::devices::CST_570004_WW::CST_570004_WW.get_number_fields(),
+        }
+    }
+
+    fn get_attributes(&self, raw_clip_state: &HashMap<u16, u32>) -> Option<serde_json::Value> {
+        match self {
+            Self::RAC_056905_WW(unit) => crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit)
+                .get_attributes(raw_clip_state),
+            Self::CST_570004_WW => {
+                crate::devices::CST_570004_WW::CST_570004_WW.get_attributes(raw_clip_state)
+            }
+        }
+    }
+
+    fn get_attributes_template(&self) -> Option<String> {
+        match self {
+            Self::RAC_056905_WW(unit) => {
+                crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit).get_attributes_template()
+            }
+            Self::CST_570004_WW => {
+                crate::devices::CST_570004_WW::CST_570004_WW.get_attributes_template()
+            }
+        }
+    }
+
+    fn has_attributes(&self) -> bool {
+        match self {
+            Self::RAC_056905_WW(unit) => {
+                crate::devices::RAC_056905_WW::RAC_056905_WW::new(*unit).has_attributes()
+            }
+            Self::CST_570004_WW => crate::devices::CST_570004_WW::CST_570004_WW.has_attributes(),
+        }
+    }
+}
+
+/// Unit temperatures are shown in on the HA side, devices always work in 0.5 °C steps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TemperatureUnit {
+    #[default]
+    #[serde(rename = "C")]
+    Celsius,
+    #[serde(rename = "F")]
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// Converts a raw reading to this unit, whole degrees in Fahrenheit.
+    pub fn decode(self, raw: u32) -> f32 {
+        match self {
+            Self::Celsius => raw as f32 / 2.0,
+            Self::Fahrenheit => (raw as f32 / 2.0 * 9.0 / 5.0 + 32.0).round(),
+        }
+    }
+
+    /// Converts a value in this unit to raw 0.5 °C steps, rounded to the nearest step.
+    pub fn encode(self, v: f32) -> f32 {
+        match self {
+            Self::Celsius => (v * 2.0).round(),
+            Self::Fahrenheit => ((v - 32.0) * 5.0 / 9.0 * 2.0).round(),
+        }
+    }
+
+    /// The `temperature_unit` HA expects in the discovery config.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Celsius => "C",
+            Self::Fahrenheit => "F",
+        }
+    }
+
+    /// Setpoint step in this unit, half a degree Celsius does not map onto whole Fahrenheit.
+    pub fn step(self) -> f32 {
+        match self {
+            Self::Celsius => 0.5,
+            Self::Fahrenheit => 1.0,
+        }
+    }
+}
+
+/// `temperature_unit` from the config with its per-device overrides.
+#[derive(Debug, Clone, Default)]
+pub struct TemperatureUnits {
+    pub default: TemperatureUnit,
+    pub devices: HashMap<String, TemperatureUnit>,
+}
+
+impl TemperatureUnits {
+    pub fn for_device(&self, id: &str) -> TemperatureUnit {
+        self.devices.get(id).copied().unwrap_or(self.default)
+    }
+}
+
+/// Why a frame from a device was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeviceError {
+    /// The CRC the frame carries does not match the one computed over its body.
+    CrcMismatch { expected: u16, actual: u16 },
+    /// The frame is too short to hold the two leading bytes and a CRC.
+    Truncated { len: usize },
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::CrcMismatch { expected, actual } => {
+                write!(f, "CRC {expected:04X} does not match {actual:04X}")
+            }
+            DeviceError::Truncated { len } => {
+                write!(f, "frame of {len} bytes is too short to carry a CRC")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+/// Why a value from HA can't be written to a field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldError {
+    /// The field takes a number and the value isn't one.
+    NotANumber { field: String, value: String },
+    /// The value parsed but falls outside what the device accepts.
+    OutOfRange {
+        field: String,
+        value: f32,
+        min: f32,
+        max: f32,
+    },
+    /// The value isn't one of the options this model supports.
+    Unsupported { field: String, value: String },
+    /// The field only reports state, there is nothing to write.
+    ReadOnly { field: String },
+}
+
+impl FieldError {
+    /// Parses `v` as a finite number, `NaN` and infinities are rejected as well.
+    pub fn parse_number(field: &str, v: &str) -> Result<f32, FieldError> {
+        v.parse::<f32>()
+            .ok()
+            .filter(|n| n.is_finite())
+            .ok_or_else(|| FieldError::NotANumber {
+                field: field.to_string(),
+                value: v.to_string(),
+            })
+    }
+
+    /// Checks that `value` lies within `min..=max`.
+    pub fn check_range(field: &str, value: f32, min: f32, max: f32) -> Result<(), FieldError> {
+        if value < min || value > max {
+            return Err(FieldError::OutOfRange {
+                field: field.to_string(),
+                value,
+                min,
+                max,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldError::NotANumber { field, value } => {
+                write!(f, "{field} '{value}' is not a number")
+            }
+            FieldError::OutOfRange {
+                field,
+                value,
+                min,
+                max,
+            } => write!(f, "{field} {value} is outside {min}..={max}"),
+            FieldError::Unsupported { field, value } => {
+                write!(f, "{field} '{value}' not supported on this model")
+            }
+            FieldError::ReadOnly { field } => write!(f, "{field} is read-only"),
+        }
+    }
+}
+
+impl std::error::Error for FieldError {}
+
+/// Checks the trailing big-endian CRC16 of a device frame.
+///
+/// The CRC covers everything between the two leading bytes and itself. Inbound frames share the
+/// layout `DeviceWrapper::send` builds, which checks its own output with this in debug builds.
+pub fn verify_frame(buf: &[u8]) -> Result<(), DeviceError> {
+    if buf.len() < 4 {
+        return Err(DeviceError::Truncated { len: buf.len() });
+    }
+
+    let (body, trailer) = buf[2..].split_at(buf.len() - 4);
+    let expected = u16::from_be_bytes([trailer[0], trailer[1]]);
+    let actual = crc16(body);
+
+    if expected != actual {
+        return Err(DeviceError::CrcMismatch { expected, actual });
+    }
+
+    Ok(())
+}
+
+/// Lays out a command frame in `buf`, replacing its contents: the header, the TLVs and their
+/// length, then the CRC.
+fn encode_frame(header: [u8; 5], tlv: &[Tlv], buf: &mut Vec<u8>) -> Result<(), TlvError> {
+    let [b0, b1, b2, b3, b4] = header;
+
+    buf.clear();
+    buf.extend_from_slice(&[b0, b1, 0x04, 0x00, 0x00, 0x00, 0x65, b2, b3, b4, 0]);
+
+    build_tlv_into(tlv, buf)?;
+    buf[10] = (buf.len() - 11) as u8;
+
+    // The CRC covers everything after the two leading bytes
+    let result = crc16(&buf[2..]);
+    buf.extend_from_slice(&[((result >> 8) as u8), (result as u8)]);
+    debug_assert_eq!(verify_frame(buf), Ok(()));
+
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -72,41 +319,46 @@ pub struct DeviceWrapper {
     raw_clip_state: HashMap<u16, u32>,
     device: DeviceTypes,
     ha_mqtt_client: AsyncClient,
+    clock: Arc<dyn Clock>,
+    live_config: SharedLiveConf,
+    // Last value published to HA per property, keyed by the property topic suffix.
+    ha_state: HashMap<String, String>,
+    last_seen: Option<i64>,
+    // Frames dropped for a bad CRC since startup, a steady climb points at firmware or RF trouble.
+    crc_errors: u64,
+    // Frame assembly buffer reused by `send`, shared by clones of the same device.
+    scratch: Arc<Mutex<Vec<u8>>>,
 }
 
 impl DeviceWrapper {
-    async fn init(&self, discovery_prefix: String, ponder_prefix: String) {
-        self.publish_config(discovery_prefix, ponder_prefix).await;
-        self.query().await;
-    }
-
-    pub async fn new(
+    pub fn new(
         scx: ServerContext,
         ha_mqtt_client: AsyncClient,
-        discovery_prefix: String,
-        ponder_prefix: String,
-        kind: String,
+        clock: Arc<dyn Clock>,
+        live_config: SharedLiveConf,
+        device: DeviceTypes,
         id: String,
         topic: String,
     ) -> Self {
-        let device = match kind.as_str() {
-            "RAC_056905_WW" => DeviceTypes::RAC_056905_WW,
-            "CST_570004_WW" => DeviceTypes::CST_570004_WW,
-            _ => panic!("unknown device"),
-        };
-
-        let s = Self {
+        Self {
             scx,
             id,
             topic,
             raw_clip_state: HashMap::new(),
             device,
             ha_mqtt_client,
-        };
-
-        s.init(discovery_prefix, ponder_prefix).await;
+            clock,
+            live_config,
+            ha_state: HashMap::new(),
+            last_seen: None,
+            crc_errors: 0,
+            scratch: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
 
-        return s;
+    pub async fn start(&mut self, discovery_prefix: String, ponder_prefix: String) {
+        self.publish_config(discovery_prefix, ponder_prefix).await;
+        self.query().await;
     }
 
     async fn pre_set_property(&mut self, prop: String, value: String) {
@@ -114,31 +366,27 @@ impl DeviceWrapper {
 
         if let Some(def) = self.device.get_field_by_ha(prop) {
             if def.writable() {
-                let new_value = def.write_xform(value.clone());
-
-                if let Some(new_v) = new_value {
-                    if let None = def.write_callback(value) {
-                        raw_clip_state = Some((def.id(), new_v));
+                let new_value = def.write_xform(value.clone()).await.inspect_err(|e| {
+                    eprintln!("Not writing {} to {}: {e}", def.name(), self.get_id())
+                });
 
-                        let mut attach = Vec::new();
+                if let Ok(new_v) = new_value {
+                    if def.write_callback(value).await.is_none() {
+                        let mut tlv = TlvBuilder::new().add(def.id(), new_v);
 
-                        if let Some(array) = def.write_attach(new_v) {
-                            attach = array;
+                        for id in def.write_attach(new_v).unwrap_or_default() {
+                            tlv = tlv.add_from_state(id, &self.raw_clip_state);
                         }
 
-                        let write_fields = [&[def.id()], attach.as_slice()].concat();
+                        let tlv = match tlv.build() {
+                            Ok(tlv) => tlv,
+                            Err(e) => {
+                                eprintln!("Not writing {} to {}: {e}", def.name(), self.get_id());
+                                return;
+                            }
+                        };
 
-                        let tlv: Vec<Tlv> = write_fields
-                            .into_iter()
-                            .map(|id| Tlv {
-                                t: id,
-                                v: if id == def.id() {
-                                    new_v
-                                } else {
-                                    self.get_raw_clip_state(id).unwrap()
-                                },
-                            })
-                            .collect();
+                        raw_clip_state = Some((def.id(), new_v));
 
                         self.send([1, 1, 2, 1, 1], tlv).await;
                     }
@@ -151,7 +399,7 @@ impl DeviceWrapper {
         }
     }
 
-    pub async fn set_property(&mut self, prop: String, value: String) {
+    pub async fn set_property(&mut self, ponder_prefix: String, prop: String, value: String) {
         let mut raw_clip_state = None;
 
         let clone = self.clone();
@@ -159,38 +407,64 @@ impl DeviceWrapper {
 
         if let Some(def) = maybe_field {
             if def.writable() {
-                if let Some((p, v)) = def.pre_write_xform_set_property(value.clone()) {
-                    self.pre_set_property(p, v).await;
+                if let Err(e) = def.validate(&value).await {
+                    eprintln!("Rejected {} for {}: {e}", def.name(), self.get_id());
+
+                    // Not part of the device state, so this bypasses `ha_publish_property`.
+                    self.publish_to_ha(
+                        format!("{}/{}/error", ponder_prefix, self.get_id()),
+                        e.to_string(),
+                        false,
+                    )
+                    .await;
+
+                    return;
                 }
 
-                let new_value = def.write_xform(value.clone());
+                match def.pre_write_xform_set_property(value.clone()) {
+                    Some(PreWrite::Before(p, v)) => self.pre_set_property(p, v).await,
+                    // The value has no raw form, the other property is all there is to write.
+                    Some(PreWrite::Instead(p, v)) => {
+                        self.pre_set_property(p, v).await;
+                        return;
+                    }
+                    None => {}
+                }
 
-                if let Some(new_v) = new_value {
-                    if let None = def.write_callback(value) {
-                        raw_clip_state = Some((def.id(), new_v));
+                let new_value = def.write_xform(value.clone()).await.inspect_err(|e| {
+                    eprintln!("Not writing {} to {}: {e}", def.name(), self.get_id())
+                });
 
-                        let mut attach = Vec::new();
+                if let Ok(new_v) = new_value {
+                    if def.write_callback(value.clone()).await.is_none() {
+                        let mut tlv = TlvBuilder::new().add(def.id(), new_v);
 
-                        if let Some(array) = def.write_attach(new_v) {
-                            attach = array;
+                        for id in def.write_attach(new_v).unwrap_or_default() {
+                            tlv = tlv.add_from_state(id, &self.raw_clip_state);
                         }
 
-                        let write_fields = [&[def.id()], attach.as_slice()].concat();
-
-                        let tlv: Vec<Tlv> = write_fields
-                            .into_iter()
-                            .map(|id| Tlv {
-                                t: id,
-                                v: if id == def.id() {
-                                    new_v
-                                } else {
-                                    // eprintln!("get raw clip state for id: {:X}", id);
-                                    self.get_raw_clip_state(id).unwrap()
-                                },
-                            })
-                            .collect();
+                        let tlv = match tlv.build() {
+                            Ok(tlv) => tlv,
+                            Err(e) => {
+                                eprintln!("Not writing {} to {}: {e}", def.name(), self.get_id());
+                                return;
+                            }
+                        };
+
+                        raw_clip_state = Some((def.id(), new_v));
 
                         self.send([1, 1, 2, 1, 1], tlv).await;
+
+                        if def.readable() && def.echo_on_write() {
+                            self.ha_publish_property(
+                                ponder_prefix,
+                                self.get_id(),
+                                def.name(),
+                                value,
+                                true,
+                            )
+                            .await;
+                        }
                     }
                 }
             }
@@ -202,48 +476,25 @@ impl DeviceWrapper {
     }
 
     async fn send(&self, header: [u8; 5], tlv: Vec<Tlv>) {
-        let [b0, b1, b2, b3, b4] = header;
-
-        let tlv_buf = build_tlv(&tlv);
-
-        let mut buf = [
-            &[
-                0x04,
-                0x00,
-                0x00,
-                0x00,
-                0x65,
-                b2,
-                b3,
-                b4,
-                tlv_buf.len() as u8,
-            ],
-            tlv_buf.as_slice(),
-        ]
-        .concat();
-
-        let result = crc16(&buf);
-
-        buf = [
-            &[b0, b1],
-            buf.as_slice(),
-            &[((result >> 8) as u8), (result as u8 & 0xff)],
-        ]
-        .concat();
-
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64;
+        if self.live_config.read().unwrap().verbose_decode {
+            eprintln!(
+                "send {} {}: {}",
+                self.device.get_model(),
+                self.get_id(),
+                dump(&tlv)
+            );
+        }
 
-        let message_str = json!({
-            "did": self.get_id(),
-            "mid": timestamp,
-            "cmd": "packet",
-            "type": 1,
-            "data": hex::encode(&buf)
-        })
-        .to_string();
+        let data = {
+            let mut buf = self.scratch.lock().unwrap_or_else(|e| e.into_inner());
+
+            if let Err(e) = encode_frame(header, &tlv, &mut buf) {
+                eprintln!("Not sending command to {}: {e}", self.get_id());
+                return;
+            }
+
+            hex::encode(buf.as_slice())
+        };
 
         let from = rmqtt::types::From::from_custom(rmqtt::types::Id::new(
             self.scx.node.id(),
@@ -254,17 +505,7 @@ impl DeviceWrapper {
             None,
         ));
 
-        let message = Box::new(rmqtt::codec::types::Publish {
-            topic: self.get_topic().into(),
-            retain: false,
-            qos: rmqtt::codec::types::QoS::AtMostOnce,
-            dup: false,
-            payload: message_str.into(),
-            packet_id: None,
-            properties: Some(Default::default()),
-            delay_interval: None,
-            create_time: Some(timestamp),
-        });
+        let message = self.command(data);
 
         let message = self
             .scx
@@ -281,30 +522,86 @@ impl DeviceWrapper {
         }
     }
 
-    async fn query(&self) {
-        self.send([1, 1, 2, 2, 1], vec![Tlv { t: 0x1f5, v: 2 }])
-            .await
+    /// Wraps hex-encoded frame `data` in the `packet` message the device reads, stamped by the
+    /// clock.
+    fn command(&self, data: String) -> Box<rmqtt::codec::types::Publish> {
+        let timestamp = self.clock.now_millis();
+
+        let message_str = json!({
+            "did": self.get_id(),
+            "mid": timestamp,
+            "cmd": "packet",
+            "type": 1,
+            "data": data
+        })
+        .to_string();
+
+        Box::new(rmqtt::codec::types::Publish {
+            topic: self.get_topic().into(),
+            retain: false,
+            qos: rmqtt::codec::types::QoS::AtMostOnce,
+            dup: false,
+            payload: message_str.into(),
+            packet_id: None,
+            properties: Some(Default::default()),
+            delay_interval: None,
+            create_time: Some(timestamp),
+        })
     }
 
-    async fn ha_publish_config(&self, discovery_prefix: String, ponder_prefix: String) {
-        let id = self.get_id();
+    async fn query(&self) {
+        self.send([1, 1, 2, 2, 1], vec![Tlv::new(0x1f5, 2)]).await
+    }
 
-        let discovery_topic_config = format!(
+    fn discovery_topic_config(&self, discovery_prefix: String, ponder_prefix: String) -> String {
+        format!(
             "{}/{}/{}/{}/config",
             discovery_prefix,
             self.device.get_ha_class(),
             ponder_prefix,
-            id
-        );
+            self.get_id()
+        )
+    }
 
-        let config = self.get_config(ponder_prefix);
+    fn number_topic_config(
+        &self,
+        discovery_prefix: String,
+        ponder_prefix: String,
+        field: &dyn Field,
+    ) -> String {
+        format!(
+            "{}/number/{}/{}_{}/config",
+            discovery_prefix,
+            ponder_prefix,
+            self.get_id(),
+            field.name()
+        )
+    }
+
+    async fn ha_publish_config(&self, discovery_prefix: String, ponder_prefix: String) {
+        let discovery_topic_config =
+            self.discovery_topic_config(discovery_prefix.clone(), ponder_prefix.clone());
+
+        let config = self.get_config(ponder_prefix.clone());
 
         self.publish_to_ha(discovery_topic_config, config, false)
             .await;
+
+        for field in self.device.get_number_fields() {
+            if let Some(config) = number_config(&ponder_prefix, &self.get_id(), field.as_ref()) {
+                let number_topic_config = self.number_topic_config(
+                    discovery_prefix.clone(),
+                    ponder_prefix.clone(),
+                    field.as_ref(),
+                );
+
+                self.publish_to_ha(number_topic_config, config, false).await;
+            }
+        }
     }
 
     async fn ha_publish_property(
-        &self,
+        &mut self,
         ponder_prefix: String,
         id: String,
         property: String,
@@ -318,66 +615,120 @@ impl DeviceWrapper {
 
         let device_topic_property = format!("{}/{}/{}", ponder_prefix, id, property);
 
+        self.ha_state.insert(property, value.clone());
+
         self.publish_to_ha(device_topic_property, value, retain)
             .await;
     }
 
     async fn publish_to_ha(&self, topic: String, payload: String, retain: bool) {
+        let qos = self.live_config.read().unwrap().publish_qos;
+
         self.ha_mqtt_client
-            .publish(topic, rumqttc::QoS::AtMostOnce, retain, payload)
+            .publish(topic, qos, retain, payload)
             .await
             .unwrap();
     }
 
-    pub async fn process_tlv(&mut self, ponder_prefix: String, tlv: Vec<Tlv>) {
-        for Tlv { t, v } in tlv {
+    /// Applies a frame checked with `TlvIter::validate`, elements that fail to parse are skipped.
+    pub async fn process_tlv(&mut self, ponder_prefix: String, tlv: TlvIter<'_>) {
+        self.last_seen = Some(self.clock.now_millis());
+
+        if self.live_config.read().unwrap().verbose_decode {
+            let packet: Vec<Tlv> = tlv.clone().flatten().collect();
+
+            eprintln!(
+                "packet {} {}: {}",
+                self.device.get_model(),
+                self.get_id(),
+                dump(&packet)
+            );
+        }
+
+        for Tlv { t, v, .. } in tlv.flatten() {
             self.process_key_value(ponder_prefix.clone(), t, v).await;
         }
+
+        self.publish_attributes(ponder_prefix).await;
     }
 
-    async fn process_key_value(&mut self, ponder_prefix: String, mut t: u16, v: u32) {
-        loop {
-            self.set_raw_clip_state(t, v);
+    async fn publish_attributes(&mut self, ponder_prefix: String) {
+        if let Some(attributes) = self.device.get_attributes(&self.raw_clip_state) {
+            self.ha_publish_property(
+                ponder_prefix,
+                self.get_id(),
+                String::from("attributes"),
+                attributes.to_string(),
+                true,
+            )
+            .await;
+        }
+    }
 
-            // eprintln!(
-            //     "{} set raw clip state: t: {:X}, v: {}",
-            //     self.device.get_model(),
-            //     t,
-            //     v
-            // );
+    async fn process_key_value(&mut self, ponder_prefix: String, mut t: u16, mut v: u32) {
+        self.set_raw_clip_state(t, v);
 
+        let verbose_decode = self.live_config.read().unwrap().verbose_decode;
+
+        loop {
             let clone = self.clone();
             let maybe_field = clone.device.get_field_by_id(t);
 
             if let Some(def) = maybe_field {
                 let new_v = def
                     .read_xform(v, &self.raw_clip_state())
+                    .await
                     .unwrap_or(v.to_string());
 
-                if let Some(new_t) = def.read_callback(new_v.clone()) {
+                if verbose_decode {
+                    eprintln!(
+                        "decode {} {}: tag 0x{:03X} raw {} -> {} = {}",
+                        self.device.get_model(),
+                        self.get_id(),
+                        t,
+                        v,
+                        def.name(),
+                        new_v
+                    );
+                }
+
+                if def.readable() {
+                    self.ha_publish_property(
+                        ponder_prefix.clone(),
+                        self.get_id(),
+                        def.name(),
+                        new_v.clone(),
+                        true,
+                    )
+                    .await
+                }
+
+                if let Some(new_t) = def.read_callback(new_v) {
+                    // Fields reached through a callback are re-read from their own raw value,
+                    // derived fields without one just see the value that triggered them.
                     t = new_t;
 
-                    continue;
-                } else {
-                    if def.readable() {
-                        self.ha_publish_property(
-                            ponder_prefix,
-                            self.get_id(),
-                            def.name(),
-                            new_v,
-                            true,
-                        )
-                        .await
+                    if let Some(raw) = self.get_raw_clip_state(t) {
+                        v = raw;
                     }
-                    break;
+
+                    continue;
                 }
-            } else {
-                break;
+            } else if verbose_decode {
+                eprintln!(
+                    "decode {} {}: tag 0x{:03X} raw {} -> unmatched",
+                    self.device.get_model(),
+                    self.get_id(),
+                    t,
+                    v
+                );
             }
+
+            break;
         }
     }
 
-    pub async fn publish_config(&self, discovery_prefix: String, ponder_prefix: String) {
+    pub async fn publish_config(&mut self, discovery_prefix: String, ponder_prefix: String) {
         self.ha_publish_config(discovery_prefix, ponder_prefix.clone())
             .await;
 
@@ -391,6 +742,54 @@ impl DeviceWrapper {
         .await;
     }
 
+    pub async fn clear_config(&self, discovery_prefix: String, ponder_prefix: String) {
+        let discovery_topic_config =
+            self.discovery_topic_config(discovery_prefix.clone(), ponder_prefix.clone());
+
+        // An empty retained payload makes HA drop the entity and the broker forget the config.
+        self.publish_to_ha(discovery_topic_config, String::new(), true)
+            .await;
+
+        for field in self.device.get_number_fields() {
+            let number_topic_config = self.number_topic_config(
+                discovery_prefix.clone(),
+                ponder_prefix.clone(),
+                field.as_ref(),
+            );
+
+            self.publish_to_ha(number_topic_config, String::new(), true)
+                .await;
+        }
+    }
+
+    /// Everything last published to HA for this device, plus `last_seen` as epoch millis once a
+    /// frame has been received and `crc_errors` once one has been dropped.
+    pub fn ha_state(&self) -> HashMap<String, String> {
+        let mut state = self.ha_state.clone();
+
+        if let Some(last_seen) = self.last_seen {
+            state.insert(String::from("last_seen"), last_seen.to_string());
+        }
+        if self.crc_errors > 0 {
+            state.insert(String::from("crc_errors"), self.crc_errors.to_string());
+        }
+
+        state
+    }
+
+    /// Counts a rejected frame, so persistent failures show up in `ha_state`.
+    pub fn record_error(&mut self, error: &DeviceError) {
+        match error {
+            DeviceError::CrcMismatch { .. } => self.crc_errors += 1,
+            // Not a CRC failure, there was no CRC to check.
+            DeviceError::Truncated { .. } => {}
+        }
+    }
+
+    pub fn get_kind(&self) -> String {
+        self.device.get_model()
+    }
+
     fn get_id(&self) -> String {
         self.id.clone()
     }
@@ -431,13 +830,75 @@ impl DeviceWrapper {
             },
         });
 
+        if self.device.has_attributes() {
+            let value = value.as_object_mut().unwrap();
+            value.insert(
+                String::from("json_attributes_topic"),
+                json!(format!("{}/{}/attributes", ponder_prefix, id)),
+            );
+            if let Some(template) = self.device.get_attributes_template() {
+                value.insert(String::from("json_attributes_template"), json!(template));
+            }
+        }
+
         value.as_object_mut().unwrap().append(&mut inner_config);
 
         value.to_string()
     }
 }
 
-pub trait Field: Send {
+/// Discovery config for a field's `number` entity, `None` unless the field has a `NumberConfig`.
+fn number_config(ponder_prefix: &str, id: &str, field: &dyn Field) -> Option<String> {
+    let number = field.number()?;
+
+    let unique_id = format!("{}_{}", id, field.name());
+
+    let mut value = json!({
+        "availability": [ { "topic": format!("{}/{}/availability", ponder_prefix, id) }, { "topic": format!("{}/availability", ponder_prefix) } ],
+        "name": number.name,
+        "object_id": unique_id,
+        "unique_id": unique_id,
+        "min": number.min,
+        "max": number.max,
+        "step": number.step,
+        "state_topic": format!("{}/{}/{}", ponder_prefix, id, field.name()),
+        "command_topic": format!("{}/{}/{}/set", ponder_prefix, id, field.name()),
+        "device": {
+            "identifiers": id,
+        },
+    });
+
+    if let Some(precision) = number.suggested_display_precision {
+        value.as_object_mut().unwrap().insert(
+            String::from("suggested_display_precision"),
+            json!(precision),
+        );
+    }
+
+    Some(value.to_string())
+}
+
+/// Describes a field exposed to HA as a standalone `number` entity next to the main component.
+pub struct NumberConfig {
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    pub step: f32,
+    pub suggested_display_precision: Option<u8>,
+}
+
+/// A property `set_property` writes on behalf of a field, e.g. `power` for an HVAC mode.
+#[derive(Debug, PartialEq)]
+pub enum PreWrite {
+    /// Written first, the field's own value follows.
+    Before(String, String),
+    /// Written in place of the field's value, which is not sent.
+    Instead(String, String),
+}
+
+// Transforms are async so a field can look things up outside `raw_clip_state`.
+#[async_trait]
+pub trait Field: Send + Sync {
     fn id(&self) -> u16;
 
     fn name(&self) -> String;
@@ -446,14 +907,32 @@ pub trait Field: Send {
 
     fn writable(&self) -> bool;
 
-    fn read_xform(&self, v: u32, raw_clip_state: &HashMap<u16, u32>) -> Option<String>;
+    async fn read_xform(&self, v: u32, raw_clip_state: &HashMap<u16, u32>) -> Option<String>;
     fn read_callback(&self, v: String) -> Option<u16>;
 
-    fn pre_write_xform_set_property(&self, v: String) -> Option<(String, String)>;
-    fn write_xform(&self, v: String) -> Option<u32>;
-    fn write_callback(&self, v: String) -> Option<()>;
+    fn pre_write_xform_set_property(&self, v: String) -> Option<PreWrite>;
+    // Checked before any transform, the error is published to `<ponder_prefix>/<id>/error`.
+    // Number fields are held to their `NumberConfig` range unless a field says otherwise.
+    async fn validate(&self, v: &str) -> Result<(), FieldError> {
+        match self.number() {
+            Some(number) => {
+                let value = FieldError::parse_number(&self.name(), v)?;
+                FieldError::check_range(&self.name(), value, number.min, number.max)
+            }
+            None => Ok(()),
+        }
+    }
+    async fn write_xform(&self, v: String) -> Result<u32, FieldError>;
+    async fn write_callback(&self, v: String) -> Option<()>;
 
     fn write_attach(&self, raw: u32) -> Option<Vec<u16>>;
+
+    fn echo_on_write(&self) -> bool;
+
+    // Only consulted for fields returned by `HADevice::get_number_fields`.
+    fn number(&self) -> Option<NumberConfig> {
+        None
+    }
 }
 
 pub trait HADevice: Clone {
@@ -470,4 +949,215 @@ pub trait HADevice: Clone {
     fn get_field_by_id(&self, t: u16) -> Option<Box<dyn Field>>;
 
     fn get_field_by_ha(&self, prop: String) -> Option<Box<dyn Field>>;
+
+    /// Writable numeric fields that get their own `number` entity, sharing the device entry.
+    ///
+    /// Each one is announced on `<discovery_prefix>/number/<ponder_prefix>/<id>_<name>/config`
+    /// and uses the usual `<ponder_prefix>/<id>/<name>` state and `.../set` command topics.
+    fn get_number_fields(&self) -> Vec<Box<dyn Field>> {
+        Vec::new()
+    }
+
+    /// Structured attributes published to `<ponder_prefix>/<id>/attributes` after every frame.
+    ///
+    /// The returned value is sent as-is, so devices are free to nest diagnostics, e.g.
+    /// `{ "network": { "rssi": -60, "ip": "..." } }`. Returning `None` (the default) publishes
+    /// nothing, HA simply never receives attributes for the entity.
+    fn get_attributes(&self, _raw_clip_state: &HashMap<u16, u32>) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Optional `json_attributes_template` advertised next to the attributes topic.
+    ///
+    /// Only needed when the payload from `get_attributes` is nested and HA should see a flattened
+    /// view, e.g. `{{ {"rssi": value_json.network.rssi, "ip": value_json.network.ip} | tojson }}`.
+    fn get_attributes_template(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether `get_attributes` can return a value, the attributes topic is only advertised then.
+    ///
+    /// Discovery runs before the first frame, so it can't tell from `get_attributes` itself.
+    fn has_attributes(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, reload::LiveConf};
+    use std::sync::RwLock;
+
+    // A frame laid out the way `DeviceWrapper::send` builds them, one 2-byte TLV and its CRC.
+    const FRAME: &str = "01010400000065020201027d425a6e";
+
+    #[test]
+    fn verify_frame_accepts_a_good_frame() {
+        assert_eq!(verify_frame(&hex::decode(FRAME).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn verify_frame_rejects_a_flipped_bit() {
+        let mut buf = hex::decode(FRAME).unwrap();
+        buf[11] ^= 0x01;
+
+        assert!(matches!(
+            verify_frame(&buf),
+            Err(DeviceError::CrcMismatch {
+                expected: 0x5a6e,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn verify_frame_rejects_short_frames() {
+        for len in 0..4 {
+            assert_eq!(
+                verify_frame(&vec![0; len]),
+                Err(DeviceError::Truncated { len })
+            );
+        }
+        // Two leading bytes and a CRC over nothing.
+        assert_eq!(verify_frame(&[0, 0, 0, 0]), Ok(()));
+    }
+
+    #[test]
+    fn encoded_frames_verify() {
+        let mut buf = vec![0xff; 3];
+        let tlv = [Tlv::new(0x1f7, 1), Tlv::new(0x1fa, 300)];
+
+        encode_frame([1, 1, 2, 1, 1], &tlv, &mut buf).unwrap();
+
+        assert_eq!(&buf[..11], &[1, 1, 4, 0, 0, 0, 0x65, 2, 1, 1, 6]);
+        assert_eq!(buf.len(), 11 + 6 + 2);
+        assert_eq!(verify_frame(&buf), Ok(()));
+    }
+
+    #[test]
+    fn number_config_describes_the_setpoint() {
+        let device = DeviceTypes::new("RAC_056905_WW", TemperatureUnit::Celsius);
+        let fields = device.get_number_fields();
+        let config = number_config("ponder", "abc", fields[0].as_ref()).unwrap();
+        let config: serde_json::Value = serde_json::from_str(&config).unwrap();
+
+        assert_eq!(config["min"], 16.0);
+        assert_eq!(config["max"], 30.0);
+        assert_eq!(config["step"], 0.5);
+        assert_eq!(config["suggested_display_precision"], 1);
+        assert_eq!(config["unique_id"], "abc_temperature");
+        assert_eq!(config["command_topic"], "ponder/abc/temperature/set");
+    }
+
+    #[test]
+    fn number_config_in_fahrenheit_uses_whole_degrees() {
+        let device = DeviceTypes::new("RAC_056905_WW", TemperatureUnit::Fahrenheit);
+        let fields = device.get_number_fields();
+        let config = number_config("ponder", "abc", fields[0].as_ref()).unwrap();
+        let config: serde_json::Value = serde_json::from_str(&config).unwrap();
+
+        // 16 °C is 60.8 °F and 30 °C is 86 °F.
+        assert_eq!(config["min"], 61.0);
+        assert_eq!(config["max"], 86.0);
+        assert_eq!(config["step"], 1.0);
+        assert_eq!(config["suggested_display_precision"], 0);
+    }
+
+    #[test]
+    fn fields_without_a_number_config_have_no_entity() {
+        let device = DeviceTypes::new("RAC_056905_WW", TemperatureUnit::Celsius);
+        let power = device.get_field_by_ha(String::from("power")).unwrap();
+
+        assert!(number_config("ponder", "abc", power.as_ref()).is_none());
+    }
+
+    #[test]
+    fn odd_raw_temperatures_keep_the_half_degree() {
+        assert_eq!(TemperatureUnit::Celsius.decode(51), 25.5);
+        assert_eq!(TemperatureUnit::Celsius.decode(50), 25.0);
+        // 25.5 °C is 77.9 °F, 22.5 °C is exactly 72.5 °F and rounds away from zero.
+        assert_eq!(TemperatureUnit::Fahrenheit.decode(51), 78.0);
+        assert_eq!(TemperatureUnit::Fahrenheit.decode(45), 73.0);
+    }
+
+    fn pre_write(kind: &str, prop: &str, value: &str) -> Option<PreWrite> {
+        let device = DeviceTypes::new(kind, TemperatureUnit::Celsius);
+        let field = device.get_field_by_ha(String::from(prop)).unwrap();
+        field.pre_write_xform_set_property(String::from(value))
+    }
+
+    #[test]
+    fn mode_off_is_written_as_power_alone() {
+        let off = Some(PreWrite::Instead(
+            String::from("power"),
+            String::from("OFF"),
+        ));
+
+        assert_eq!(pre_write("RAC_056905_WW", "mode", "off"), off);
+        assert_eq!(pre_write("CST_570004_WW", "mode", "off"), off);
+    }
+
+    #[test]
+    fn other_modes_keep_their_own_write() {
+        assert_eq!(pre_write("RAC_056905_WW", "mode", "cool"), None);
+        assert_eq!(
+            pre_write("CST_570004_WW", "mode", "heat"),
+            Some(PreWrite::Before(String::from("power"), String::from("ON")))
+        );
+    }
+
+    #[test]
+    fn celsius_round_trips_through_raw() {
+        for raw in 32..=60 {
+            let unit = TemperatureUnit::Celsius;
+            assert_eq!(unit.encode(unit.decode(raw)), raw as f32);
+        }
+    }
+
+    async fn wrapper(clock: Arc<MockClock>) -> DeviceWrapper {
+        let scx = ServerContext::new().build().await;
+        let (client, _) = AsyncClient::new(rumqttc::MqttOptions::new("test", "localhost", 1883), 1);
+        let live_config = Arc::new(RwLock::new(LiveConf {
+            publish_qos: rumqttc::QoS::AtMostOnce,
+            verbose_decode: false,
+        }));
+
+        DeviceWrapper::new(
+            scx,
+            client,
+            clock,
+            live_config,
+            DeviceTypes::new("RAC_056905_WW", TemperatureUnit::Celsius),
+            String::from("did1"),
+            String::from("lime/devices/did1"),
+        )
+    }
+
+    #[tokio::test]
+    async fn config_leaves_out_attributes_the_device_never_publishes() {
+        let dev = wrapper(Arc::new(MockClock::new(0))).await;
+        let config: serde_json::Value =
+            serde_json::from_str(&dev.get_config(String::from("ponder"))).unwrap();
+
+        assert_eq!(config["unique_id"], "did1");
+        assert!(config.get("json_attributes_topic").is_none());
+        assert!(config.get("json_attributes_template").is_none());
+    }
+
+    #[tokio::test]
+    async fn commands_carry_the_clock_time() {
+        let clock = Arc::new(MockClock::new(1_700_000_000_000));
+        let dev = wrapper(clock.clone()).await;
+
+        for mid in [1_700_000_000_000, 1_700_000_000_250] {
+            let publish = dev.command(String::from(FRAME));
+            let message: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+
+            assert_eq!(message["mid"], mid);
+            assert_eq!(message["data"], FRAME);
+            assert_eq!(publish.create_time, Some(mid));
+            clock.advance(250);
+        }
+    }
 }
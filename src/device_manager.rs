@@ -1,9 +1,14 @@
-use rmqtt::context::ServerContext;
+use rmqtt::{codec::v5::PublishProperties, context::ServerContext};
 use rumqttc::AsyncClient;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
-use crate::{device::DeviceWrapper, tlv::parse_tlv};
+use crate::{
+    clock::Clock,
+    device::{verify_frame, DeviceTypes, DeviceWrapper, TemperatureUnits},
+    reload::SharedLiveConf,
+    tlv::{parse_tlv_spans, TlvIter},
+};
 
 pub struct DeviceManager {
     pub devices: HashMap<String, DeviceWrapper>,
@@ -11,9 +16,13 @@ pub struct DeviceManager {
 
     pub scx: ServerContext,
     pub ha_mqtt_client: AsyncClient,
+    pub clock: Arc<dyn Clock>,
+    pub live_config: SharedLiveConf,
 
     pub discovery_prefix: String,
     pub ponder_prefix: String,
+
+    pub temperature_units: TemperatureUnits,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -28,8 +37,11 @@ impl DeviceManager {
     pub fn new(
         scx: ServerContext,
         ha_mqtt_client: AsyncClient,
+        clock: Arc<dyn Clock>,
+        live_config: SharedLiveConf,
         discovery_prefix: String,
         ponder_prefix: String,
+        temperature_units: TemperatureUnits,
     ) -> Self {
         Self {
             devices: HashMap::default(),
@@ -37,18 +49,27 @@ impl DeviceManager {
 
             scx,
             ha_mqtt_client,
+            clock,
+            live_config,
 
             discovery_prefix,
             ponder_prefix,
+
+            temperature_units,
         }
     }
 
-    pub async fn on_publish(&mut self, topic: String, payload_serialized: String) {
+    pub async fn on_publish(
+        &mut self,
+        topic: String,
+        payload_serialized: String,
+        properties: Option<PublishProperties>,
+    ) {
         // eprintln!("\ntopic: {}\npayload: {}", topic, payload_serialized);
 
         if topic.starts_with("clip/") {
             let payload: Payload =
-                serde_json::from_str(&payload_serialized.trim_end_matches("\0")).unwrap();
+                serde_json::from_str(payload_serialized.trim_end_matches("\0")).unwrap();
 
             if topic == format!("clip/message/devices/{}", payload.did) {
                 if payload.cmd == "completeProvisioning_ack" {
@@ -71,106 +92,159 @@ impl DeviceManager {
                             && buf[8] == 0x04
                             && buf[10] == (buf.len() - 13) as u8
                         {
-                            let tlv = parse_tlv(&buf[11..buf.len() - 2]);
-
-                            // eprintln!("\nTLV: {:?}", tlv);
-
-                            device.process_tlv(self.ponder_prefix.clone(), tlv).await;
+                            if let Err(e) = verify_frame(&buf) {
+                                eprintln!("Dropping packet from {}: {e}", payload.did);
+                                device.record_error(&e);
+                            } else {
+                                let tlv_buf = &buf[11..buf.len() - 2];
+                                let tlv = TlvIter::new(tlv_buf);
+
+                                match tlv.validate() {
+                                    Ok(()) => {
+                                        device.process_tlv(self.ponder_prefix.clone(), tlv).await;
+                                    }
+                                    Err(e) => {
+                                        // Offsets in `e` are relative to the TLV bytes, so log those
+                                        eprintln!(
+                                            "Dropping packet from {}: {e}, TLV bytes {}",
+                                            payload.did,
+                                            hex::encode(tlv_buf)
+                                        );
+                                        for (tlv, span) in parse_tlv_spans(tlv_buf) {
+                                            eprintln!("  {tlv} from bytes {span:?}");
+                                        }
+                                    }
+                                }
+                            }
                         }
                     }
                 }
             }
 
-            if topic == format!("clip/provisioning/devices/{}", payload.did) {
-                if payload.cmd == "preDeploy" || payload.cmd == "deploy" {
-                    self.deploy_msg_list
-                        .insert(payload.did.clone(), payload_serialized);
-
-                    let from = rmqtt::types::From::from_custom(rmqtt::types::Id::new(
-                        self.scx.node.id(),
-                        0,
-                        None,
-                        None,
-                        rmqtt::types::ClientId::new(),
-                        None,
-                    ));
-
-                    let timestamp = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as i64;
-
-                    let publish = rmqtt::codec::types::Publish {
-                        topic: format!("lime/devices/{}", payload.did).into(),
-                        retain: false,
-                        qos: rmqtt::codec::types::QoS::AtMostOnce,
-                        dup: false,
-                        payload: deploy_response(payload, timestamp).into(),
-                        packet_id: None,
-                        properties: Some(Default::default()),
-                        delay_interval: None,
-                        create_time: Some(timestamp),
-                    };
-
-                    let message = Box::new(publish);
-
-                    let message = self
-                        .scx
-                        .extends
-                        .hook_mgr()
-                        .message_publish(None, from.clone(), &message)
-                        .await
-                        .unwrap_or(message);
-
-                    if let Err(e) = rmqtt::session::SessionState::forwards(
-                        &self.scx, from, message, false, None,
-                    )
+            if topic == format!("clip/provisioning/devices/{}", payload.did)
+                && (payload.cmd == "preDeploy" || payload.cmd == "deploy")
+            {
+                self.deploy_msg_list
+                    .insert(payload.did.clone(), payload_serialized);
+
+                let from = rmqtt::types::From::from_custom(rmqtt::types::Id::new(
+                    self.scx.node.id(),
+                    0,
+                    None,
+                    None,
+                    rmqtt::types::ClientId::new(),
+                    None,
+                ));
+
+                let message = Box::new(self.deploy_publish(payload, properties.as_ref()));
+
+                let message = self
+                    .scx
+                    .extends
+                    .hook_mgr()
+                    .message_publish(None, from.clone(), &message)
                     .await
-                    {
-                        eprintln!("Error forwarding message: {e:?}");
-                    }
+                    .unwrap_or(message);
+
+                if let Err(e) =
+                    rmqtt::session::SessionState::forwards(&self.scx, from, message, false, None)
+                        .await
+                {
+                    eprintln!("Error forwarding message: {e:?}");
                 }
             }
         }
     }
 
     async fn complete_provisioning(&mut self, device_id: String, kind: String) {
-        if self.deploy_msg_list.get(&device_id).is_none() {
+        if !self.deploy_msg_list.contains_key(&device_id) {
             eprintln!("completeProvisioning_ack received without deploy/preDeploy");
             return;
         }
 
-        if self.devices.get(&device_id).is_some() {
-            eprintln!("completeProvisioning_ack received twice?");
-            return;
+        if let Some(dev) = self.devices.get(&device_id) {
+            if dev.get_kind() == kind {
+                eprintln!("completeProvisioning_ack received twice?");
+                return;
+            }
+
+            // The HA class can differ between kinds, so the old config may live under a topic the
+            // new device will never overwrite.
+            println!(
+                "Device {} changed kind from {} to {}, clearing stale discovery config",
+                device_id,
+                dev.get_kind(),
+                kind
+            );
+
+            dev.clear_config(self.discovery_prefix.clone(), self.ponder_prefix.clone())
+                .await;
+
+            self.devices.remove(&device_id);
         }
 
-        let dev = DeviceWrapper::new(
+        let mut dev = DeviceWrapper::new(
             self.scx.clone(),
             self.ha_mqtt_client.clone(),
-            self.discovery_prefix.clone(),
-            self.ponder_prefix.clone(),
-            kind,
+            self.clock.clone(),
+            self.live_config.clone(),
+            DeviceTypes::new(&kind, self.temperature_units.for_device(&device_id)),
             device_id.clone(),
             format!("lime/devices/{}", device_id),
-        )
-        .await;
+        );
+
+        dev.start(self.discovery_prefix.clone(), self.ponder_prefix.clone())
+            .await;
 
         self.devices.insert(device_id.clone(), dev);
 
         println!("Device {} started", device_id);
     }
 
-    pub async fn on_discovery(&self) {
-        for dev in self.devices.values() {
+    /// The `completeProvisioning` answer to a deploy request, stamped by the clock.
+    fn deploy_publish(
+        &self,
+        payload: Payload,
+        properties: Option<&PublishProperties>,
+    ) -> rmqtt::codec::types::Publish {
+        let timestamp = self.clock.now_millis();
+
+        // Devices listen on `lime/devices/{did}`, a v5 client may ask for another topic
+        let topic = properties
+            .and_then(|p| p.response_topic.clone())
+            .unwrap_or_else(|| format!("lime/devices/{}", payload.did).into());
+
+        rmqtt::codec::types::Publish {
+            topic,
+            retain: false,
+            qos: rmqtt::codec::types::QoS::AtMostOnce,
+            dup: false,
+            payload: deploy_response(payload, timestamp).into(),
+            packet_id: None,
+            properties: Some(forwarded_properties(properties)),
+            delay_interval: None,
+            create_time: Some(timestamp),
+        }
+    }
+
+    pub async fn on_discovery(&mut self) {
+        for dev in self.devices.values_mut() {
             dev.publish_config(self.discovery_prefix.clone(), self.ponder_prefix.clone())
                 .await
         }
     }
 
+    /// Last HA-facing values of a device, e.g. `mode`, `temperature`, `availability` and
+    /// `last_seen`, without going through MQTT.
+    #[allow(dead_code)]
+    pub fn device_state(&self, id: &str) -> Option<HashMap<String, String>> {
+        self.devices.get(id).map(|dev| dev.ha_state())
+    }
+
     pub async fn on_set_property(&mut self, id: String, prop: String, value: String) {
         if let Some(dev) = self.devices.get_mut(&id) {
-            dev.set_property(prop, value).await;
+            dev.set_property(self.ponder_prefix.clone(), prop, value)
+                .await;
         }
     }
 }
@@ -199,3 +273,74 @@ fn deploy_response(payload: Payload, timestamp: i64) -> String {
 
     json.to_string()
 }
+
+/// The v5 properties of a request that its response carries along
+///
+/// User properties, content type, payload format and correlation data belong to the message, so
+/// request/response clients can match the answer. The response topic is not copied: the answer
+/// already goes to it when the request names one, and a response must not ask for a reply of its
+/// own. Topic alias, subscription identifiers and expiry belong to one delivery and are left for
+/// the broker to fill in.
+fn forwarded_properties(original: Option<&PublishProperties>) -> PublishProperties {
+    let Some(original) = original else {
+        return PublishProperties::default();
+    };
+
+    PublishProperties {
+        user_properties: original.user_properties.clone(),
+        content_type: original.content_type.clone(),
+        is_utf8_payload: original.is_utf8_payload,
+        correlation_data: original.correlation_data.clone(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, reload::LiveConf};
+    use std::sync::RwLock;
+
+    async fn manager(clock: Arc<MockClock>) -> DeviceManager {
+        let scx = ServerContext::new().build().await;
+        let (client, _) = AsyncClient::new(rumqttc::MqttOptions::new("test", "localhost", 1883), 1);
+        let live_config = Arc::new(RwLock::new(LiveConf {
+            publish_qos: rumqttc::QoS::AtMostOnce,
+            verbose_decode: false,
+        }));
+
+        DeviceManager::new(
+            scx,
+            client,
+            clock,
+            live_config,
+            String::from("homeassistant"),
+            String::from("ponder"),
+            TemperatureUnits::default(),
+        )
+    }
+
+    fn deploy(did: &str) -> Payload {
+        Payload {
+            cmd: String::from("deploy"),
+            did: String::from(did),
+            kind: String::from("RAC_056905_WW"),
+            data: serde_json::Value::Null,
+        }
+    }
+
+    #[tokio::test]
+    async fn deploy_response_carries_the_clock_time() {
+        let clock = Arc::new(MockClock::new(1_700_000_000_000));
+        let manager = manager(clock.clone()).await;
+        clock.set(1_700_000_000_500);
+
+        let publish = manager.deploy_publish(deploy("did1"), None);
+        let message: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+
+        assert_eq!(&*publish.topic, "lime/devices/did1");
+        assert_eq!(message["mid"], 1_700_000_000_500i64);
+        assert_eq!(message["cmd"], "completeProvisioning");
+        assert_eq!(publish.create_time, Some(1_700_000_000_500));
+    }
+}
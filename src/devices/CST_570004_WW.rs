@@ -1,7 +1,8 @@
+use async_trait::async_trait;
 use serde_json::json;
 use std::collections::HashMap;
 
-use crate::device::{Field, HADevice};
+use crate::device::{Field, FieldError, HADevice, PreWrite};
 
 #[allow(non_camel_case_types)]
 #[derive(Clone)]
@@ -43,6 +44,7 @@ impl CST_570004_WW_Fields {
     }
 }
 
+#[async_trait]
 impl Field for CST_570004_WW_Fields {
     fn id(&self) -> u16 {
         match self {
@@ -92,7 +94,7 @@ impl Field for CST_570004_WW_Fields {
         }
     }
 
-    fn read_xform(&self, v: u32, raw_clip_state: &HashMap<u16, u32>) -> Option<String> {
+    async fn read_xform(&self, v: u32, raw_clip_state: &HashMap<u16, u32>) -> Option<String> {
         // eprintln!("{} read_xform v: {}", self.name(), v);
         match self {
             Self::CurrentTemperature => Some((v / 2).to_string()),
@@ -151,73 +153,89 @@ impl Field for CST_570004_WW_Fields {
         }
     }
 
-    fn pre_write_xform_set_property(&self, v: String) -> Option<(String, String)> {
+    fn pre_write_xform_set_property(&self, v: String) -> Option<PreWrite> {
         // eprintln!("{} pre_write_xform_set_property v: {}", self.name(), v);
         match self {
             Self::Mode => {
                 if v == "off" {
-                    Some((String::from("power"), String::from("OFF")))
+                    Some(PreWrite::Instead(
+                        String::from("power"),
+                        String::from("OFF"),
+                    ))
                 } else {
-                    Some((String::from("power"), String::from("ON")))
+                    Some(PreWrite::Before(String::from("power"), String::from("ON")))
                 }
             }
             _ => None,
         }
     }
 
-    fn write_xform(&self, v: String) -> Option<u32> {
+    async fn validate(&self, v: &str) -> Result<(), FieldError> {
+        match self {
+            // Handled by pre_write_xform_set_property, there is no raw mode for it.
+            Self::Mode if v == "off" => Ok(()),
+            _ => self.write_xform(v.to_string()).await.map(|_| ()),
+        }
+    }
+
+    async fn write_xform(&self, v: String) -> Result<u32, FieldError> {
         // eprintln!("{} write_xform v: {}", self.name(), v);
+        let unsupported = || FieldError::Unsupported {
+            field: self.name(),
+            value: v.clone(),
+        };
+
         match self {
-            Self::CurrentTemperature => None,
-            Self::Power => Some(if v == "ON" { 1 } else { 0 }),
+            Self::CurrentTemperature => Err(FieldError::ReadOnly { field: self.name() }),
+            Self::Power => Ok(if v == "ON" { 1 } else { 0 }),
             Self::Mode => match v.as_str() {
-                "cool" => Some(0),
-                "dry" => Some(1),
-                "fan_only" => Some(2),
-                "heat" => Some(4),
-                "auto" => Some(6),
-                _ => None,
+                "cool" => Ok(0),
+                "dry" => Ok(1),
+                "fan_only" => Ok(2),
+                "heat" => Ok(4),
+                "auto" => Ok(6),
+                _ => Err(unsupported()),
             },
             Self::FanMode => match v.as_str() {
-                "very low" => Some(2),
-                "low" => Some(3),
-                "medium" => Some(4),
-                "high" => Some(5),
-                "very high" => Some(6),
-                "auto" => Some(8),
-                _ => None,
+                "very low" => Ok(2),
+                "low" => Ok(3),
+                "medium" => Ok(4),
+                "high" => Ok(5),
+                "very high" => Ok(6),
+                "auto" => Ok(8),
+                _ => Err(unsupported()),
             },
-            Self::Temperature => Some((v.parse::<f32>().unwrap() * 2.0).round() as u32),
+            Self::Temperature => {
+                FieldError::parse_number(&self.name(), &v).map(|value| (value * 2.0).round() as u32)
+            }
             Self::VerticalSwingMode => match v.as_str() {
-                "off" => Some(0),
-                "1" => Some(1),
-                "2" => Some(2),
-                "3" => Some(3),
-                "4" => Some(4),
-                "5" => Some(5),
-                "6" => Some(6),
-                "on" => Some(100),
-                _ => None,
+                "off" => Ok(0),
+                "1" => Ok(1),
+                "2" => Ok(2),
+                "3" => Ok(3),
+                "4" => Ok(4),
+                "5" => Ok(5),
+                "6" => Ok(6),
+                "on" => Ok(100),
+                _ => Err(unsupported()),
             },
             Self::SwingMode => match v.as_str() {
-                "off" => Some(0),
-                "1" => Some(1),
-                "2" => Some(2),
-                "3" => Some(3),
-                "4" => Some(4),
-                "5" => Some(5),
-                "1-3" => Some(13),
-                "3-5" => Some(35),
-                "on" => Some(100),
-                _ => None,
+                "off" => Ok(0),
+                "1" => Ok(1),
+                "2" => Ok(2),
+                "3" => Ok(3),
+                "4" => Ok(4),
+                "5" => Ok(5),
+                "1-3" => Ok(13),
+                "3-5" => Ok(35),
+                "on" => Ok(100),
+                _ => Err(unsupported()),
             },
         }
     }
 
-    fn write_callback(&self, _v: String) -> Option<()> {
-        match self {
-            _ => None,
-        }
+    async fn write_callback(&self, _v: String) -> Option<()> {
+        None
     }
 
     fn write_attach(&self, raw: u32) -> Option<Vec<u16>> {
@@ -232,6 +250,18 @@ impl Field for CST_570004_WW_Fields {
             _ => None,
         }
     }
+
+    fn echo_on_write(&self) -> bool {
+        match self {
+            Self::CurrentTemperature => false,
+            Self::Power => false,
+            Self::Mode => false,
+            Self::FanMode => false,
+            Self::Temperature => false,
+            Self::VerticalSwingMode => true,
+            Self::SwingMode => true,
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
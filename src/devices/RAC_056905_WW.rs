@@ -1,100 +1,116 @@
+use async_trait::async_trait;
 use serde_json::json;
 use std::collections::HashMap;
 
-use crate::device::{Field, HADevice};
+use crate::device::{Field, FieldError, HADevice, NumberConfig, PreWrite, TemperatureUnit};
 
 #[allow(non_camel_case_types)]
 #[derive(Clone)]
 enum RAC_056905_WW_Fields {
-    CurrentTemperature,
+    CurrentTemperature(TemperatureUnit),
     Power,
     Mode,
     FanMode,
-    Temperature,
+    Temperature(TemperatureUnit),
     VerticalSwingMode,
     SwingMode,
+    HvacAction,
 }
 
+// Setpoint range of the unit in raw 0.5 °C steps, 16 to 30 °C.
+const MIN_SETPOINT_RAW: u32 = 32;
+const MAX_SETPOINT_RAW: u32 = 60;
+
+// Not a real TLV tag, tags are only 10 bits wide. HvacAction is derived from power and mode.
+const HVAC_ACTION: u16 = 0x400;
+
 impl RAC_056905_WW_Fields {
-    fn from_id(id: u16) -> Option<Self> {
+    fn from_id(id: u16, unit: TemperatureUnit) -> Option<Self> {
         match id {
-            0x1fd => Some(Self::CurrentTemperature),
+            0x1fd => Some(Self::CurrentTemperature(unit)),
             0x1f7 => Some(Self::Power),
             0x1f9 => Some(Self::Mode),
             0x1fa => Some(Self::FanMode),
-            0x1fe => Some(Self::Temperature),
+            0x1fe => Some(Self::Temperature(unit)),
             0x321 => Some(Self::VerticalSwingMode),
             0x322 => Some(Self::SwingMode),
+            HVAC_ACTION => Some(Self::HvacAction),
             _ => None,
         }
     }
 
-    fn from_name(name: &str) -> Option<Self> {
+    fn from_name(name: &str, unit: TemperatureUnit) -> Option<Self> {
         match name {
-            "current_temperature" => Some(Self::CurrentTemperature),
+            "current_temperature" => Some(Self::CurrentTemperature(unit)),
             "power" => Some(Self::Power),
             "mode" => Some(Self::Mode),
             "fan_mode" => Some(Self::FanMode),
-            "temperature" => Some(Self::Temperature),
+            "temperature" => Some(Self::Temperature(unit)),
             "vertical_swing_mode" => Some(Self::VerticalSwingMode),
             "swing_mode" => Some(Self::SwingMode),
+            "hvac_action" => Some(Self::HvacAction),
             _ => None,
         }
     }
 }
 
+#[async_trait]
 impl Field for RAC_056905_WW_Fields {
     fn id(&self) -> u16 {
         match self {
-            Self::CurrentTemperature => 0x1fd,
+            Self::CurrentTemperature(_) => 0x1fd,
             Self::Power => 0x1f7,
             Self::Mode => 0x1f9,
             Self::FanMode => 0x1fa,
-            Self::Temperature => 0x1fe,
+            Self::Temperature(_) => 0x1fe,
             Self::VerticalSwingMode => 0x321,
             Self::SwingMode => 0x322,
+            Self::HvacAction => HVAC_ACTION,
         }
     }
 
     fn name(&self) -> String {
         String::from(match self {
-            Self::CurrentTemperature => "current_temperature",
+            Self::CurrentTemperature(_) => "current_temperature",
             Self::Power => "power",
             Self::Mode => "mode",
             Self::FanMode => "fan_mode",
-            Self::Temperature => "temperature",
+            Self::Temperature(_) => "temperature",
             Self::VerticalSwingMode => "vertical_swing_mode",
             Self::SwingMode => "swing_mode",
+            Self::HvacAction => "hvac_action",
         })
     }
 
     fn readable(&self) -> bool {
         match self {
-            Self::CurrentTemperature => true,
+            Self::CurrentTemperature(_) => true,
             Self::Power => false,
             Self::Mode => true,
             Self::FanMode => true,
-            Self::Temperature => true,
+            Self::Temperature(_) => true,
             Self::VerticalSwingMode => true,
             Self::SwingMode => true,
+            Self::HvacAction => true,
         }
     }
 
     fn writable(&self) -> bool {
         match self {
-            Self::CurrentTemperature => false,
+            Self::CurrentTemperature(_) => false,
             Self::Power => true,
             Self::Mode => true,
             Self::FanMode => true,
-            Self::Temperature => true,
+            Self::Temperature(_) => true,
             Self::VerticalSwingMode => true,
             Self::SwingMode => true,
+            Self::HvacAction => false,
         }
     }
 
-    fn read_xform(&self, v: u32, raw_clip_state: &HashMap<u16, u32>) -> Option<String> {
+    async fn read_xform(&self, v: u32, raw_clip_state: &HashMap<u16, u32>) -> Option<String> {
         match self {
-            Self::CurrentTemperature => Some((v / 2).to_string()),
+            Self::CurrentTemperature(unit) => Some(unit.decode(v).to_string()),
             Self::Power => Some(String::from(if v == 0 { "OFF" } else { "ON" })),
 
             Self::Mode => {
@@ -122,7 +138,7 @@ impl Field for RAC_056905_WW_Fields {
                 _ => None,
             },
 
-            Self::Temperature => Some((v / 2).to_string()),
+            Self::Temperature(unit) => Some(unit.decode(v).to_string()),
 
             Self::VerticalSwingMode => match v {
                 0 => Some(String::from("off")),
@@ -139,21 +155,41 @@ impl Field for RAC_056905_WW_Fields {
                 100 => Some(String::from("on")),
                 _ => None,
             },
+
+            Self::HvacAction => {
+                if raw_clip_state.get(&0x1f7) == Some(&0) {
+                    Some(String::from("off"))
+                } else {
+                    // No running/idle TLV is known for this unit, so assume it is doing what the
+                    // mode asks for. Auto can't be resolved to heating or cooling.
+                    match raw_clip_state.get(&0x1f9) {
+                        Some(0) => Some(String::from("cooling")),
+                        Some(1) => Some(String::from("drying")),
+                        Some(2) => Some(String::from("fan")),
+                        Some(4) => Some(String::from("heating")),
+                        _ => Some(String::from("idle")),
+                    }
+                }
+            }
         }
     }
 
     fn read_callback(&self, _v: String) -> Option<u16> {
         match self {
             Self::Power => Some(0x1f9),
+            Self::Mode => Some(HVAC_ACTION),
             _ => None,
         }
     }
 
-    fn pre_write_xform_set_property(&self, v: String) -> Option<(String, String)> {
+    fn pre_write_xform_set_property(&self, v: String) -> Option<PreWrite> {
         match self {
             Self::Mode => {
                 if v == "off" {
-                    Some((String::from("power"), String::from("OFF")))
+                    Some(PreWrite::Instead(
+                        String::from("power"),
+                        String::from("OFF"),
+                    ))
                 } else {
                     None
                 }
@@ -162,58 +198,82 @@ impl Field for RAC_056905_WW_Fields {
         }
     }
 
-    fn write_xform(&self, v: String) -> Option<u32> {
+    async fn validate(&self, v: &str) -> Result<(), FieldError> {
         match self {
-            Self::CurrentTemperature => None,
-            Self::Power => Some(if v == "ON" { 1 } else { 0 }),
+            Self::Temperature(unit) => {
+                let value = FieldError::parse_number(&self.name(), v)?;
+                FieldError::check_range(
+                    &self.name(),
+                    value,
+                    unit.decode(MIN_SETPOINT_RAW),
+                    unit.decode(MAX_SETPOINT_RAW),
+                )
+            }
+            // Handled by pre_write_xform_set_property, there is no raw mode for it.
+            Self::Mode if v == "off" => Ok(()),
+            _ => self.write_xform(v.to_string()).await.map(|_| ()),
+        }
+    }
+
+    async fn write_xform(&self, v: String) -> Result<u32, FieldError> {
+        let unsupported = || FieldError::Unsupported {
+            field: self.name(),
+            value: v.clone(),
+        };
+
+        match self {
+            Self::CurrentTemperature(_) => Err(FieldError::ReadOnly { field: self.name() }),
+            Self::Power => Ok(if v == "ON" { 1 } else { 0 }),
             Self::Mode => match v.as_str() {
-                "cool" => Some(0),
-                "dry" => Some(1),
-                "fan_only" => Some(2),
-                "heat" => Some(4),
-                "auto" => Some(6),
-                _ => None,
+                "cool" => Ok(0),
+                "dry" => Ok(1),
+                "fan_only" => Ok(2),
+                "heat" => Ok(4),
+                "auto" => Ok(6),
+                _ => Err(unsupported()),
             },
             Self::FanMode => match v.as_str() {
-                "very low" => Some(2),
-                "low" => Some(3),
-                "medium" => Some(4),
-                "high" => Some(5),
-                "very high" => Some(6),
-                "auto" => Some(8),
-                _ => None,
+                "very low" => Ok(2),
+                "low" => Ok(3),
+                "medium" => Ok(4),
+                "high" => Ok(5),
+                "very high" => Ok(6),
+                "auto" => Ok(8),
+                _ => Err(unsupported()),
             },
-            Self::Temperature => Some((v.parse::<f32>().unwrap() * 2.0).round() as u32),
+            Self::Temperature(unit) => FieldError::parse_number(&self.name(), &v).map(|value| {
+                unit.encode(value)
+                    .clamp(MIN_SETPOINT_RAW as f32, MAX_SETPOINT_RAW as f32) as u32
+            }),
             Self::VerticalSwingMode => match v.as_str() {
-                "off" => Some(0),
-                "1" => Some(1),
-                "2" => Some(2),
-                "3" => Some(3),
-                "4" => Some(4),
-                "5" => Some(5),
-                "6" => Some(6),
-                "on" => Some(100),
-                _ => None,
+                "off" => Ok(0),
+                "1" => Ok(1),
+                "2" => Ok(2),
+                "3" => Ok(3),
+                "4" => Ok(4),
+                "5" => Ok(5),
+                "6" => Ok(6),
+                "on" => Ok(100),
+                _ => Err(unsupported()),
             },
             Self::SwingMode => match v.as_str() {
-                "off" => Some(0),
-                "1" => Some(1),
-                "2" => Some(2),
-                "3" => Some(3),
-                "4" => Some(4),
-                "5" => Some(5),
-                "1-3" => Some(13),
-                "3-5" => Some(35),
-                "on" => Some(100),
-                _ => None,
+                "off" => Ok(0),
+                "1" => Ok(1),
+                "2" => Ok(2),
+                "3" => Ok(3),
+                "4" => Ok(4),
+                "5" => Ok(5),
+                "1-3" => Ok(13),
+                "3-5" => Ok(35),
+                "on" => Ok(100),
+                _ => Err(unsupported()),
             },
+            Self::HvacAction => Err(FieldError::ReadOnly { field: self.name() }),
         }
     }
 
-    fn write_callback(&self, _v: String) -> Option<()> {
-        match self {
-            _ => None,
-        }
+    async fn write_callback(&self, _v: String) -> Option<()> {
+        None
     }
 
     fn write_attach(&self, raw: u32) -> Option<Vec<u16>> {
@@ -221,17 +281,51 @@ impl Field for RAC_056905_WW_Fields {
             Self::Power => Some(if raw == 0 { vec![] } else { vec![0x1f9, 0x1fa] }),
             Self::Mode => Some(vec![0x1fa, 0x1fe]),
             Self::FanMode => Some(vec![0x1f9, 0x1fe]),
-            Self::Temperature => Some(vec![0x1f9, 0x1fa]),
+            Self::Temperature(_) => Some(vec![0x1f9, 0x1fa]),
             Self::VerticalSwingMode => Some(vec![0x1f9, 0x1fa]),
             Self::SwingMode => Some(vec![0x1f9, 0x1fa]),
             _ => None,
         }
     }
+
+    fn echo_on_write(&self) -> bool {
+        match self {
+            Self::CurrentTemperature(_) => false,
+            Self::Power => false,
+            Self::Mode => false,
+            Self::FanMode => false,
+            Self::Temperature(_) => false,
+            Self::VerticalSwingMode => true,
+            Self::SwingMode => true,
+            Self::HvacAction => false,
+        }
+    }
+
+    fn number(&self) -> Option<NumberConfig> {
+        match self {
+            Self::Temperature(unit) => Some(NumberConfig {
+                name: String::from("Target temperature"),
+                min: unit.decode(MIN_SETPOINT_RAW),
+                max: unit.decode(MAX_SETPOINT_RAW),
+                step: unit.step(),
+                suggested_display_precision: Some(if unit.step() < 1.0 { 1 } else { 0 }),
+            }),
+            _ => None,
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
 #[derive(Clone)]
-pub struct RAC_056905_WW;
+pub struct RAC_056905_WW {
+    temperature_unit: TemperatureUnit,
+}
+
+impl RAC_056905_WW {
+    pub fn new(temperature_unit: TemperatureUnit) -> Self {
+        Self { temperature_unit }
+    }
+}
 
 impl HADevice for RAC_056905_WW {
     fn get_ha_class(&self) -> String {
@@ -247,11 +341,15 @@ impl HADevice for RAC_056905_WW {
         id: String,
         ponder_prefix: String,
     ) -> serde_json::Map<String, serde_json::Value> {
+        let unit = self.temperature_unit;
+
         json!({
             "name": "LG Air Conditioner",
-            "temperature_unit": "C",
-            "temp_step": 0.5,
-            "precision": 0.5,
+            "temperature_unit": unit.symbol(),
+            "temp_step": unit.step(),
+            "precision": unit.step(),
+            "min_temp": unit.decode(MIN_SETPOINT_RAW),
+            "max_temp": unit.decode(MAX_SETPOINT_RAW),
             "fan_modes": [ "auto", "very low", "low", "medium", "high", "very high" ],
             "swing_modes": [ "1", "2", "3", "4", "5", "1-3", "3-5", "on", "off" ],
             "vertical_swing_modes": [ "1", "2", "3", "4", "5", "6", "on", "off" ],
@@ -265,6 +363,7 @@ impl HADevice for RAC_056905_WW {
             "temperature_command_topic": format!("{}/{}/temperature/set", ponder_prefix, id),
             "swing_mode_state_topic": format!("{}/{}/swing_mode", ponder_prefix, id),
             "swing_mode_command_topic": format!("{}/{}/swing_mode/set", ponder_prefix, id),
+            "action_topic": format!("{}/{}/hvac_action", ponder_prefix, id),
         })
         .as_object()
         .unwrap()
@@ -272,10 +371,20 @@ impl HADevice for RAC_056905_WW {
     }
 
     fn get_field_by_id(&self, t: u16) -> Option<Box<dyn Field>> {
-        RAC_056905_WW_Fields::from_id(t).map(|f| Box::new(f) as Box<dyn Field>)
+        RAC_056905_WW_Fields::from_id(t, self.temperature_unit)
+            .map(|f| Box::new(f) as Box<dyn Field>)
     }
 
     fn get_field_by_ha(&self, prop: String) -> Option<Box<dyn Field>> {
-        RAC_056905_WW_Fields::from_name(&prop).map(|f| Box::new(f) as Box<dyn Field>)
+        RAC_056905_WW_Fields::from_name(&prop, self.temperature_unit)
+            .map(|f| Box::new(f) as Box<dyn Field>)
+    }
+
+    // The setpoint as a standalone slider, for dashboards and automations that want a plain
+    // number instead of the climate card.
+    fn get_number_fields(&self) -> Vec<Box<dyn Field>> {
+        vec![Box::new(RAC_056905_WW_Fields::Temperature(
+            self.temperature_unit,
+        ))]
     }
 }
@@ -1,6 +1,10 @@
 use async_trait::async_trait;
+use clock::SystemClock;
+use device::{TemperatureUnit, TemperatureUnits};
 use device_manager::DeviceManager;
+use reload::LiveConf;
 use rmqtt::{
+    codec::v5::PublishProperties,
     context::ServerContext,
     hook::{Handler, HookResult, Parameter, Priority, Register, ReturnType, Type},
     macros::Plugin,
@@ -11,25 +15,31 @@ use rmqtt::{
 };
 use rumqttc::{AsyncClient, MqttOptions};
 use serde::Deserialize;
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use tokio::sync::{
     mpsc::{self, Sender},
     Mutex,
 };
 use tokio_util::sync::CancellationToken;
 
+mod clock;
 mod crc16;
 mod device;
 mod device_manager;
 mod devices;
+mod reload;
 mod tlv;
 
 struct PublishHandler {
-    tx: Sender<(String, String)>,
+    tx: Sender<(String, String, Option<PublishProperties>)>,
 }
 
 impl PublishHandler {
-    fn new(tx: &Sender<(String, String)>) -> Self {
+    fn new(tx: &Sender<(String, String, Option<PublishProperties>)>) -> Self {
         Self { tx: tx.clone() }
     }
 }
@@ -42,7 +52,11 @@ impl Handler for PublishHandler {
             let payload = std::str::from_utf8(&publish.payload).unwrap_or("<binary>");
 
             self.tx
-                .send((topic.to_string(), payload.to_string()))
+                .send((
+                    topic.to_string(),
+                    payload.to_string(),
+                    publish.properties.clone(),
+                ))
                 .await
                 .unwrap();
         }
@@ -65,7 +79,7 @@ impl Handler for PublishHandler {
 #[inline]
 pub async fn register_named(
     scx: &rmqtt::context::ServerContext,
-    tx: Sender<(String, String)>,
+    tx: Sender<(String, String, Option<PublishProperties>)>,
     name: &'static str,
     default_startup: bool,
     immutable: bool,
@@ -95,7 +109,7 @@ pub async fn register_named(
 #[inline]
 pub async fn register(
     scx: &rmqtt::context::ServerContext,
-    tx: Sender<(String, String)>,
+    tx: Sender<(String, String, Option<PublishProperties>)>,
     default_startup: bool,
     immutable: bool,
 ) -> rmqtt::Result<()> {
@@ -104,7 +118,7 @@ pub async fn register(
 
 #[derive(Plugin)]
 struct PublishHookPlugin {
-    tx: Sender<(String, String)>,
+    tx: Sender<(String, String, Option<PublishProperties>)>,
     register: Box<dyn Register>,
 }
 
@@ -112,7 +126,7 @@ impl PublishHookPlugin {
     #[inline]
     async fn new<S: Into<String>>(
         scx: ServerContext,
-        tx: Sender<(String, String)>,
+        tx: Sender<(String, String, Option<PublishProperties>)>,
         _name: S,
     ) -> Result<Self> {
         let register = scx.extends.hook_mgr().register();
@@ -152,7 +166,60 @@ impl Plugin for PublishHookPlugin {
     }
 }
 
-#[derive(Debug, Deserialize)]
+fn subscribe_ha(client: &AsyncClient, discovery_prefix: &str, ponder_prefix: &str) {
+    // `try_subscribe` doesn't wait on the request channel, which is only drained by the same
+    // event loop that is calling us.
+    for topic in [
+        format!("{}/status", discovery_prefix),
+        format!("{}/+/+/set", ponder_prefix),
+    ] {
+        if let Err(e) = client.try_subscribe(topic.clone(), rumqttc::QoS::AtMostOnce) {
+            eprintln!("Failed to subscribe to {}: {e:?}", topic);
+        }
+    }
+}
+
+async fn inflight_count(scx: &ServerContext) -> usize {
+    let sessions: Vec<_> = scx
+        .extends
+        .shared()
+        .await
+        .iter()
+        .filter_map(|entry| entry.session())
+        .collect();
+
+    let mut count = 0;
+    for session in sessions {
+        count += session.out_inflight().read().await.len();
+    }
+
+    count
+}
+
+// Listeners must already be stopped, otherwise new sessions could keep the inflight windows busy.
+async fn drain(scx: &ServerContext, timeout: Duration) {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let inflight = inflight_count(scx).await;
+        if inflight == 0 {
+            eprintln!("All inflight messages delivered");
+            return;
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            eprintln!(
+                "Drain timeout elapsed with {} inflight messages left",
+                inflight
+            );
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct HAConf {
     address: String,
     port: u16,
@@ -160,34 +227,80 @@ pub struct HAConf {
     password: String,
     ponder_prefix: String,
     discovery_prefix: String,
+    #[serde(default)]
+    publish_qos: u8,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Conf {
     home_assistant: HAConf,
     ca_cert_file: String,
     ca_key_file: String,
-    #[allow(dead_code)]
     https_port: u16,
     mqtts_port: u16,
     mqtt_port: u16,
-    #[allow(dead_code)]
     hostname: String,
+    #[serde(default = "default_drain_timeout")]
+    drain_timeout: u64,
+    #[serde(default)]
+    verbose_decode: bool,
+    #[serde(default)]
+    temperature_unit: TemperatureUnit,
+    #[serde(default)]
+    temperature_units: HashMap<String, TemperatureUnit>,
+}
+
+fn default_drain_timeout() -> u64 {
+    10
+}
+
+const CONFIG_PATH: &str = "./config.toml";
+
+fn load_config(path: &str) -> std::result::Result<Conf, config::ConfigError> {
+    config::Config::builder()
+        .add_source(config::File::with_name(path))
+        .build()?
+        .try_deserialize()
+}
+
+fn print_version() {
+    println!(
+        "ponder {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("PONDER_GIT_COMMIT")
+    );
+    // rmqtt-net is patched to default to openssl, see the README for why.
+    println!("tls: {}", rmqtt::net::TLS_BACKEND);
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config: Conf = config::Config::builder()
-        .add_source(config::File::with_name("./config.toml"))
-        .build()?
-        .try_deserialize()?;
+    if std::env::args()
+        .skip(1)
+        .any(|arg| arg == "--version" || arg == "-V")
+    {
+        print_version();
+        return Ok(());
+    }
+
+    let config = load_config(CONFIG_PATH)?;
+
+    let live_config = Arc::new(RwLock::new(LiveConf::from_conf(&config)));
 
     let token = CancellationToken::new();
     let broker_token = token.clone();
     let ha_token = token.clone();
     let receiver_token = token.clone();
+    let reload_token = token.clone();
+
+    let reload_handler = tokio::spawn(reload::watch(
+        String::from(CONFIG_PATH),
+        config.clone(),
+        live_config.clone(),
+        reload_token,
+    ));
 
-    let (tx, mut rx) = mpsc::channel::<(String, String)>(100);
+    let (tx, mut rx) = mpsc::channel::<(String, String, Option<PublishProperties>)>(100);
 
     // TODO: Implement provisioning.
     // tokio::spawn(async move {
@@ -205,6 +318,8 @@ async fn main() -> Result<()> {
 
     let scx = ServerContext::new().build().await;
     let scx_clone = scx.clone();
+    let drain_scx = scx.clone();
+    let drain_timeout = Duration::from_secs(config.drain_timeout);
 
     register(&scx, tx, true, false).await.unwrap();
 
@@ -217,6 +332,8 @@ async fn main() -> Result<()> {
                     // TODO: Generate certs if they don't exist.
                     .tls_cert(Some(config.ca_cert_file))
                     .tls_key(Some(config.ca_key_file))
+                    // The ThinQ modems only speak this legacy CBC suite, see the README.
+                    .tls_ciphers(Some("ECDHE-RSA-AES256-SHA"))
                     .bind()
                     .unwrap()
                     .tls()
@@ -235,12 +352,16 @@ async fn main() -> Result<()> {
 
         tokio::select! {
             _ = broker_token.cancelled() => {
-                eprintln!("broker_handler cancelled, shutting down");
+                eprintln!("broker_handler cancelled, draining sessions");
             }
             b = broker.run() => {
                 b.unwrap();
             }
         }
+
+        // Dropping `run` closed the listeners, but the spawned sessions are still alive and can
+        // finish their QoS 1/2 handshakes.
+        drain(&drain_scx, drain_timeout).await;
     });
 
     let mut mqttoptions = MqttOptions::new(
@@ -261,27 +382,19 @@ async fn main() -> Result<()> {
     });
 
     let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-
-    client
-        .subscribe(
-            format!("{}/status", config.home_assistant.discovery_prefix),
-            rumqttc::QoS::AtMostOnce,
-        )
-        .await
-        .unwrap();
-    client
-        .subscribe(
-            format!("{}/+/+/set", config.home_assistant.ponder_prefix),
-            rumqttc::QoS::AtMostOnce,
-        )
-        .await
-        .unwrap();
+    let ha_client = client.clone();
 
     let device_manager = DeviceManager::new(
         scx,
         client.clone(),
+        Arc::new(SystemClock),
+        live_config,
         config.home_assistant.discovery_prefix.clone(),
         config.home_assistant.ponder_prefix.clone(),
+        TemperatureUnits {
+            default: config.temperature_unit,
+            devices: config.temperature_units.clone(),
+        },
     );
 
     let device_manager_1 = Arc::new(Mutex::new(device_manager));
@@ -294,47 +407,53 @@ async fn main() -> Result<()> {
                     eprintln!("ha_handler cancelled, shutting down");
                     break;
                 }
-                event = eventloop.poll() => {
-                    if let Ok(notification) = event {
-                        if let rumqttc::Event::Incoming(rumqttc::Incoming::Publish(rumqttc::Publish {
-                            topic,
-                            payload,
-                            ..
-                        })) = notification
+                event = eventloop.poll() => match event {
+                    // The HA broker may not persist our session, so the subscriptions are
+                    // restored on every ConnAck rather than once at startup.
+                    Ok(rumqttc::Event::Incoming(rumqttc::Incoming::ConnAck(_))) => {
+                        subscribe_ha(
+                            &ha_client,
+                            &config.home_assistant.discovery_prefix,
+                            &config.home_assistant.ponder_prefix,
+                        );
+                    }
+                    Ok(rumqttc::Event::Incoming(rumqttc::Incoming::Publish(rumqttc::Publish {
+                        topic,
+                        payload,
+                        ..
+                    }))) => {
+                        if topic == format!("{}/status", config.home_assistant.discovery_prefix)
+                            && payload == "online"
                         {
-                            if topic
-                                == String::from(format!("{}/status", config.home_assistant.discovery_prefix))
-                                && payload == String::from("online")
-                            {
-                                println!("HA online, starting discovery process");
+                            println!("HA online, starting discovery process");
 
-                                device_manager_1.clone().lock().await.on_discovery().await;
-                            }
+                            device_manager_1.clone().lock().await.on_discovery().await;
+                        }
 
-                            if topic.starts_with(format!("{}/", config.home_assistant.ponder_prefix).as_str()) {
-                                let path_elements: Vec<&str> = topic
-                                    [(config.home_assistant.ponder_prefix.len() + 1)..]
-                                    .split("/")
-                                    .collect();
-
-                                if path_elements.len() == 3 && path_elements[2] == "set" {
-                                    let id = path_elements[0];
-                                    let prop = path_elements[1];
-
-                                    device_manager_1
-                                        .clone()
-                                        .lock()
-                                        .await
-                                        .on_set_property(
-                                            id.to_string(),
-                                            prop.to_string(),
-                                            String::from_utf8(payload.to_vec()).unwrap(),
-                                        )
-                                        .await;
-                                }
+                        if topic.starts_with(format!("{}/", config.home_assistant.ponder_prefix).as_str()) {
+                            let path_elements: Vec<&str> = topic
+                                [(config.home_assistant.ponder_prefix.len() + 1)..]
+                                .split("/")
+                                .collect();
+
+                            if path_elements.len() == 3 && path_elements[2] == "set" {
+                                let id = path_elements[0];
+                                let prop = path_elements[1];
+
+                                device_manager_1
+                                    .clone()
+                                    .lock()
+                                    .await
+                                    .on_set_property(
+                                        id.to_string(),
+                                        prop.to_string(),
+                                        String::from_utf8(payload.to_vec()).unwrap(),
+                                    )
+                                    .await;
                             }
                         }
                     }
+                    _ => {}
                 }
             }
         }
@@ -348,11 +467,11 @@ async fn main() -> Result<()> {
                     break;
                 }
                 maybe_received = rx.recv() => {
-                    if let Some((topic, payload)) = maybe_received {
+                    if let Some((topic, payload, properties)) = maybe_received {
                         device_manager_2
                             .lock()
                             .await
-                            .on_publish(topic, payload)
+                            .on_publish(topic, payload, properties)
                             .await;
                     }
                 }
@@ -367,12 +486,16 @@ async fn main() -> Result<()> {
         },
     }
 
-    let (broker_result, ha_result, receiver_result) =
-        tokio::join!(broker_handler, ha_handler, receiver_handler);
+    let (broker_result, ha_result, receiver_result, reload_result) =
+        tokio::join!(broker_handler, ha_handler, receiver_handler, reload_handler);
 
     broker_result?;
     ha_result?;
     receiver_result?;
 
+    if let Err(e) = reload_result? {
+        eprintln!("Config watcher failed: {e}");
+    }
+
     Ok(())
 }
@@ -0,0 +1,223 @@
+use notify::{
+    event::{EventKind, ModifyKind},
+    RecursiveMode, Watcher,
+};
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::{load_config, Conf};
+
+// Settings that can change while the bridge is running, everything else in `Conf` is only read
+// at startup.
+#[derive(Debug, Clone)]
+pub struct LiveConf {
+    pub publish_qos: rumqttc::QoS,
+    pub verbose_decode: bool,
+}
+
+impl LiveConf {
+    pub fn from_conf(conf: &Conf) -> Self {
+        Self::with_fallback(conf, rumqttc::QoS::AtMostOnce)
+    }
+
+    // An invalid `publish_qos` is reported and `fallback` used in its place, so a typo in the
+    // file doesn't silently change the QoS.
+    fn with_fallback(conf: &Conf, fallback: rumqttc::QoS) -> Self {
+        let publish_qos = match rumqttc::qos(conf.home_assistant.publish_qos) {
+            Ok(qos) => qos,
+            Err(e) => {
+                eprintln!(
+                    "Invalid home_assistant.publish_qos {}: {e}, keeping {:?}",
+                    conf.home_assistant.publish_qos, fallback
+                );
+                fallback
+            }
+        };
+
+        Self {
+            publish_qos,
+            verbose_decode: conf.verbose_decode,
+        }
+    }
+}
+
+pub type SharedLiveConf = Arc<RwLock<LiveConf>>;
+
+// Editors save in several steps (truncate, write, rename), wait for them to settle before reading.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+// Reading the file reports `Access` events, reloading on those would trigger the next reload.
+fn triggers_reload(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_))
+            | EventKind::Create(_)
+            | EventKind::Remove(_)
+    )
+}
+
+pub async fn watch(
+    path: String,
+    mut current: Conf,
+    live: SharedLiveConf,
+    token: CancellationToken,
+) -> notify::Result<()> {
+    let (tx, mut rx) = mpsc::channel::<()>(1);
+
+    let file_name = Path::new(&path).file_name().map(|name| name.to_os_string());
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if triggers_reload(&event.kind)
+                && event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name().map(|name| name.to_os_string()) == file_name)
+            {
+                // A reload is already queued if this fails, no need to stack them up.
+                let _ = tx.try_send(());
+            }
+        }
+    })?;
+
+    // Editors usually replace the file instead of writing it in place, so watch the directory.
+    let dir = Path::new(&path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => break,
+            Some(()) = rx.recv() => {
+                tokio::time::sleep(DEBOUNCE).await;
+                // Events from the rest of the save are covered by this reload.
+                while rx.try_recv().is_ok() {}
+
+                let new = match load_config(&path) {
+                    Ok(new) => new,
+                    Err(e) => {
+                        eprintln!("Failed to reload {}: {e}", path);
+                        continue;
+                    }
+                };
+
+                apply(&current, &new, &live);
+                current = new;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn apply(old: &Conf, new: &Conf, live: &SharedLiveConf) {
+    if old.home_assistant.publish_qos != new.home_assistant.publish_qos {
+        println!(
+            "Config reload: home_assistant.publish_qos changed from {} to {}",
+            old.home_assistant.publish_qos, new.home_assistant.publish_qos
+        );
+    }
+
+    if old.verbose_decode != new.verbose_decode {
+        println!(
+            "Config reload: verbose_decode changed from {} to {}",
+            old.verbose_decode, new.verbose_decode
+        );
+    }
+
+    let previous_qos = live.read().unwrap().publish_qos;
+    *live.write().unwrap() = LiveConf::with_fallback(new, previous_qos);
+
+    for setting in restart_required(old, new) {
+        println!(
+            "Config reload: {} changed, restart required to apply",
+            setting
+        );
+    }
+}
+
+fn restart_required(old: &Conf, new: &Conf) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+
+    if old.ca_cert_file != new.ca_cert_file {
+        changed.push("ca_cert_file");
+    }
+    if old.ca_key_file != new.ca_key_file {
+        changed.push("ca_key_file");
+    }
+    if old.https_port != new.https_port {
+        changed.push("https_port");
+    }
+    if old.mqtts_port != new.mqtts_port {
+        changed.push("mqtts_port");
+    }
+    if old.mqtt_port != new.mqtt_port {
+        changed.push("mqtt_port");
+    }
+    if old.hostname != new.hostname {
+        changed.push("hostname");
+    }
+    if old.drain_timeout != new.drain_timeout {
+        changed.push("drain_timeout");
+    }
+    if old.temperature_unit != new.temperature_unit {
+        changed.push("temperature_unit");
+    }
+    if old.temperature_units != new.temperature_units {
+        changed.push("temperature_units");
+    }
+    if old.home_assistant.address != new.home_assistant.address {
+        changed.push("home_assistant.address");
+    }
+    if old.home_assistant.port != new.home_assistant.port {
+        changed.push("home_assistant.port");
+    }
+    if old.home_assistant.username != new.home_assistant.username {
+        changed.push("home_assistant.username");
+    }
+    if old.home_assistant.password != new.home_assistant.password {
+        changed.push("home_assistant.password");
+    }
+    if old.home_assistant.ponder_prefix != new.home_assistant.ponder_prefix {
+        changed.push("home_assistant.ponder_prefix");
+    }
+    if old.home_assistant.discovery_prefix != new.home_assistant.discovery_prefix {
+        changed.push("home_assistant.discovery_prefix");
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, AccessMode, CreateKind, DataChange, RenameMode};
+
+    #[test]
+    fn reads_do_not_trigger_a_reload() {
+        assert!(!triggers_reload(&EventKind::Access(AccessKind::Open(
+            AccessMode::Any
+        ))));
+        assert!(!triggers_reload(&EventKind::Access(AccessKind::Close(
+            AccessMode::Read
+        ))));
+    }
+
+    #[test]
+    fn writes_trigger_a_reload() {
+        assert!(triggers_reload(&EventKind::Modify(ModifyKind::Data(
+            DataChange::Any
+        ))));
+        assert!(triggers_reload(&EventKind::Modify(ModifyKind::Name(
+            RenameMode::To
+        ))));
+        assert!(triggers_reload(&EventKind::Create(CreateKind::File)));
+    }
+}
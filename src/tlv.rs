@@ -1,76 +1,808 @@
-#[derive(Debug)]
+//! Tag-length-value codec for the device packet payload
+//!
+//! Each element starts with a 2-byte header: the 8 high bits of the tag, then a byte holding the
+//! 2 low tag bits, a 2-bit length field and a 4-bit nibble. Tags are therefore limited to 10 bits
+//! (0x3FF). A length field of 0 carries the value in the nibble, 1 to 3 give the number of value
+//! bytes that follow, big endian.
+//!
+//! `build_tlv_ext`/`parse_tlv_ext` are an opt-in variant for registers wider than 3 bytes. They
+//! read a length field of 3 as an escape: the value takes 4 + nibble bytes, up to 8, so a 3-byte
+//! value is sent as 4 bytes there. The RAC and CST devices only speak the default encoding.
+//!
+//! What no device needs yet, the extended encoding, signed and fixed-width values, strict parsing
+//! and the collecting `parse_tlv*`/`build_tlv` helpers, is only built for tests until a device
+//! definition calls it. `parse_tlv_spans` backs the log of a dropped frame.
+
+use std::{collections::HashMap, fmt, ops::Range};
+
+#[cfg(test)]
+use indexmap::IndexMap;
+
+/// Largest tag the 10 header bits can carry.
+pub const MAX_TAG: u16 = 0x3FF;
+
+/// Largest value an element can carry, the 2-bit length field allows at most 3 value bytes.
+pub const MAX_VALUE: u32 = 0xFF_FFFF;
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Tlv {
     pub t: u16,
     pub v: u32,
+    /// Value bytes on the wire, 0 for the header nibble. `build_tlv` widens it when `v` needs more.
+    width: u8,
 }
 
-pub fn parse_tlv(buf: &[u8]) -> Vec<Tlv> {
+impl Tlv {
+    /// Encodes `v` in the narrowest width that holds it, as `parse_tlv` reads it back.
+    pub fn new(t: u16, v: u32) -> Self {
+        Self {
+            t,
+            v,
+            width: value_width(v) as u8,
+        }
+    }
+
+    /// Encodes `v` in exactly `bytes` value bytes, 0 meaning the header nibble.
+    ///
+    /// For firmwares that expect a register in a fixed width even when the value would fit in
+    /// the nibble. The RAC and CST devices accept the narrowest encoding, so neither needs it.
+    /// Fails with `ValueTooLarge` when `v` does not fit or `bytes` is above 3.
+    #[cfg(test)]
+    pub fn with_width(t: u16, v: u32, bytes: usize) -> Result<Self, TlvError> {
+        if bytes > 3 || v > mask(bytes) {
+            return Err(TlvError::ValueTooLarge(v));
+        }
+
+        Ok(Self {
+            t,
+            v,
+            width: bytes as u8,
+        })
+    }
+
+    /// Encodes `v` as two's complement in the narrowest width that holds it.
+    #[cfg(test)]
+    pub fn from_i32(t: u16, v: i32) -> Result<Self, TlvError> {
+        let width = (0..=3)
+            .find(|&w| signed(v as u32 & mask(w), w) == v)
+            .ok_or(TlvError::ValueTooLarge(v as u32))?;
+
+        Ok(Self {
+            t,
+            v: v as u32 & mask(width),
+            width: width as u8,
+        })
+    }
+
+    /// Value bytes this element is encoded with, 0 when it fits in the header nibble.
+    pub fn width(&self) -> usize {
+        value_width(self.v).max(self.width as usize)
+    }
+
+    /// Reads `v` as a two's-complement integer of the encoded width.
+    #[cfg(test)]
+    pub fn as_i32(&self) -> i32 {
+        signed(self.v, self.width())
+    }
+}
+
+impl fmt::Display for Tlv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tlv(t={:#x}, v={} [{:#x}])", self.t, self.v, self.v)
+    }
+}
+
+/// Formats `tlvs` on one line for packet logs.
+pub fn dump(tlvs: &[Tlv]) -> String {
+    tlvs.iter()
+        .map(Tlv::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Sign-extends `v` from `width` value bytes, 0 meaning the 4-bit header nibble.
+///
+/// `read_xform` only sees the raw register value, so signed fields pass the width the device
+/// uses for that register.
+#[cfg(test)]
+pub fn signed(v: u32, width: usize) -> i32 {
+    let bits = if width == 0 { 4 } else { 8 * width as u32 };
+    let shift = 32 - bits.min(32);
+
+    ((v << shift) as i32) >> shift
+}
+
+#[cfg(test)]
+fn mask(width: usize) -> u32 {
+    match width {
+        0 => 0x0F,
+        w => MAX_VALUE >> (8 * (3 - w.min(3))),
+    }
+}
+
+/// Value bytes `build_tlv` needs for `v`, 0 when it fits in the header nibble.
+fn value_width(v: u32) -> usize {
+    match v {
+        0..=0x0F => 0,
+        0x10..=0xFF => 1,
+        0x100..=0xFFFF => 2,
+        _ => 3,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TlvError {
+    /// The element starting at byte `at` needs `needed` bytes, more than the buffer has left.
+    Truncated { at: usize, needed: usize },
+    /// The value does not fit in the 3 bytes the length field can describe.
+    ValueTooLarge(u32),
+    /// The tag does not fit in the 10 bits of the header.
+    TagTooLarge(u16),
+    /// The element starting at byte `at` escapes to a width above the 8 bytes of a `u64`.
+    #[cfg(test)]
+    UnsupportedWidth { at: usize, width: usize },
+    /// In strict mode, the element at byte `at` has value bytes but a non-zero header nibble.
+    StrayNibble { at: usize, nibble: u8 },
+    /// `TlvBuilder::add_from_state` found no value for the tag.
+    MissingState(u16),
+}
+
+impl fmt::Display for TlvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlvError::Truncated { at, needed } => write!(
+                f,
+                "truncated TLV at byte {at}, element needs {needed} bytes"
+            ),
+            TlvError::ValueTooLarge(v) => {
+                write!(f, "TLV value {v:#X} is larger than {MAX_VALUE:#X}")
+            }
+            TlvError::TagTooLarge(t) => write!(f, "TLV tag {t:#X} is larger than {MAX_TAG:#X}"),
+            #[cfg(test)]
+            TlvError::UnsupportedWidth { at, width } => {
+                write!(f, "TLV at byte {at} has an unsupported {width}-byte value")
+            }
+            TlvError::StrayNibble { at, nibble } => write!(
+                f,
+                "TLV at byte {at} has value bytes and a stray nibble {nibble:#X}"
+            ),
+            TlvError::MissingState(t) => write!(f, "no known value for TLV tag {t:#X}"),
+        }
+    }
+}
+
+impl std::error::Error for TlvError {}
+
+/// Collects the elements of a command, checking them on `build`.
+#[derive(Debug, Default)]
+pub struct TlvBuilder {
+    elements: Vec<Tlv>,
+    error: Option<TlvError>,
+}
+
+impl TlvBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(mut self, t: u16, v: u32) -> Self {
+        self.elements.push(Tlv::new(t, v));
+        self
+    }
+
+    /// Adds `t` with its last known value, `build` fails if there is none.
+    pub fn add_from_state(self, t: u16, raw_clip_state: &HashMap<u16, u32>) -> Self {
+        match raw_clip_state.get(&t) {
+            Some(&v) => self.add(t, v),
+            None => self.fail(TlvError::MissingState(t)),
+        }
+    }
+
+    /// Returns the elements, or the first error found while adding them.
+    pub fn build(self) -> Result<Vec<Tlv>, TlvError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.elements.iter().try_for_each(check_element)?;
+
+        Ok(self.elements)
+    }
+
+    fn fail(mut self, e: TlvError) -> Self {
+        self.error.get_or_insert(e);
+        self
+    }
+}
+
+/// Parses the elements of `buf` one at a time without allocating.
+///
+/// Ends after the first error, a truncated element leaves nothing to resynchronise on.
+#[derive(Debug, Clone)]
+pub struct TlvIter<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    strict: bool,
+}
+
+impl<'a> TlvIter<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            offset: 0,
+            strict: false,
+        }
+    }
+
+    /// Also rejects elements with value bytes whose header nibble is not zero.
+    ///
+    /// Only the nibble form uses those bits, so a set nibble next to a length field usually means
+    /// the parser is no longer aligned with the element boundaries.
+    #[cfg(test)]
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Checks every element without keeping any, so a bad frame can be dropped as a whole.
+    pub fn validate(&self) -> Result<(), TlvError> {
+        self.clone().try_for_each(|tlv| tlv.map(drop))
+    }
+}
+
+impl Iterator for TlvIter<'_> {
+    type Item = Result<Tlv, TlvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.buf.len() {
+            return None;
+        }
+
+        match parse_element(self.buf, self.offset, self.strict) {
+            Ok((tlv, len)) => {
+                self.offset += len;
+                Some(Ok(tlv))
+            }
+            Err(e) => {
+                self.offset = self.buf.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn parse_tlv(buf: &[u8]) -> Result<Vec<Tlv>, TlvError> {
+    TlvIter::new(buf).collect()
+}
+
+/// Which value `parse_tlv_map` keeps when a frame repeats a tag.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicates {
+    FirstWins,
+    LastWins,
+}
+
+/// Parses `buf` into one value per tag.
+///
+/// Tags are ordered by their first occurrence in the frame whichever value is kept, so with
+/// `LastWins` a repeated tag keeps its original position but carries its final value.
+#[cfg(test)]
+pub fn parse_tlv_map(buf: &[u8], duplicates: Duplicates) -> Result<IndexMap<u16, u32>, TlvError> {
+    let mut result = IndexMap::new();
+
+    for tlv in TlvIter::new(buf) {
+        let Tlv { t, v, .. } = tlv?;
+
+        match duplicates {
+            Duplicates::FirstWins => {
+                result.entry(t).or_insert(v);
+            }
+            Duplicates::LastWins => {
+                result.insert(t, v);
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Like `parse_tlv`, but stops at a truncated element and returns what was parsed before it.
+#[cfg(test)]
+pub fn parse_tlv_lossy(buf: &[u8]) -> Vec<Tlv> {
+    TlvIter::new(buf).map_while(Result::ok).collect()
+}
+
+/// Pairs each element with the bytes of `buf` it came from, for frame diagnostics.
+///
+/// Stops at the first malformed element, like `parse_tlv_lossy`.
+pub fn parse_tlv_spans(buf: &[u8]) -> Vec<(Tlv, Range<usize>)> {
+    let mut result = Vec::new();
+    let mut iter = TlvIter::new(buf);
+
+    loop {
+        let start = iter.offset;
+        let Some(Ok(tlv)) = iter.next() else {
+            break;
+        };
+        result.push((tlv, start..iter.offset));
+    }
+
+    result
+}
+
+/// Parses the element at `i`, returning it with its encoded length.
+fn parse_element(buf: &[u8], i: usize, strict: bool) -> Result<(Tlv, usize), TlvError> {
+    // Check if header (2 bytes) is available
+    if i + 2 > buf.len() {
+        return Err(TlvError::Truncated { at: i, needed: 2 });
+    }
+
+    let b0 = buf[i];
+    let b1 = buf[i + 1];
+
+    // Extract tag (10 bits: 8 from b0 and 2 from b1)
+    let tag = (u16::from(b0) << 2) | (u16::from(b1) >> 6);
+    // Extract length indicator (2 bits)
+    let length_field = (b1 >> 4) & 0x03;
+    let value_bytes = length_field as usize;
+
+    if strict && value_bytes > 0 && b1 & 0x0F != 0 {
+        return Err(TlvError::StrayNibble {
+            at: i,
+            nibble: b1 & 0x0F,
+        });
+    }
+
+    // Check if value bytes are available
+    if i + 2 + value_bytes > buf.len() {
+        return Err(TlvError::Truncated {
+            at: i,
+            needed: 2 + value_bytes,
+        });
+    }
+
+    // Extract value (4 bits from header or additional bytes)
+    let value = if value_bytes == 0 {
+        u32::from(b1 & 0x0F)
+    } else {
+        let mut v = 0;
+        for j in 0..value_bytes {
+            v = (v << 8) | u32::from(buf[i + 2 + j]);
+        }
+        v
+    };
+
+    Ok((
+        Tlv {
+            t: tag,
+            v: value,
+            width: length_field,
+        },
+        2 + value_bytes,
+    ))
+}
+
+/// Encodes `elements` back to back.
+///
+/// Any tag up to `MAX_TAG` with a value up to `MAX_VALUE` comes back unchanged from `parse_tlv`,
+/// width included, when built with `Tlv::new`, `Tlv::with_width` or `Tlv::from_i32`.
+#[cfg(test)]
+pub fn build_tlv(elements: &[Tlv]) -> Result<Vec<u8>, TlvError> {
+    let mut out = Vec::new();
+    build_tlv_into(elements, &mut out)?;
+
+    Ok(out)
+}
+
+/// Like `build_tlv`, but appends to `out`. On error `out` is left as it was.
+pub fn build_tlv_into(elements: &[Tlv], out: &mut Vec<u8>) -> Result<(), TlvError> {
+    let start = out.len();
+
+    for el in elements {
+        if let Err(e) = check_element(el) {
+            out.truncate(start);
+            return Err(e);
+        }
+
+        let t0 = ((el.t >> 2) & 0xFF) as u8;
+        out.push(t0);
+
+        let tl = ((el.t & 3) << 6) as u8;
+
+        let width = el.width();
+
+        if width == 0 {
+            out.push(tl | el.v as u8);
+        } else {
+            out.push(tl | ((width as u8) << 4));
+            for j in (0..width).rev() {
+                out.push((el.v >> (8 * j)) as u8);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_element(el: &Tlv) -> Result<(), TlvError> {
+    if el.t > MAX_TAG {
+        return Err(TlvError::TagTooLarge(el.t));
+    }
+    if el.v > MAX_VALUE {
+        return Err(TlvError::ValueTooLarge(el.v));
+    }
+
+    Ok(())
+}
+
+/// Element of the extended encoding, see the module docs.
+#[cfg(test)]
+#[derive(Debug, PartialEq, Eq)]
+pub struct TlvExt {
+    pub t: u16,
+    pub v: u64,
+}
+
+#[cfg(test)]
+pub fn parse_tlv_ext(buf: &[u8]) -> Result<Vec<TlvExt>, TlvError> {
     let mut result = Vec::new();
     let mut i = 0;
 
     while i < buf.len() {
-        // Check if header (2 bytes) is available
         if i + 2 > buf.len() {
-            break;
+            return Err(TlvError::Truncated { at: i, needed: 2 });
         }
 
         let b0 = buf[i];
         let b1 = buf[i + 1];
-
-        // Extract tag (10 bits: 8 from b0 and 2 from b1)
         let tag = (u16::from(b0) << 2) | (u16::from(b1) >> 6);
-        // Extract length indicator (2 bits)
-        let length_field = (b1 >> 4) & 0x03;
-        let value_bytes = length_field as usize;
+        let nibble = b1 & 0x0F;
 
-        // Check if value bytes are available
+        let value_bytes = match (b1 >> 4) & 0x03 {
+            3 => 4 + nibble as usize,
+            length_field => length_field as usize,
+        };
+        if value_bytes > 8 {
+            return Err(TlvError::UnsupportedWidth {
+                at: i,
+                width: value_bytes,
+            });
+        }
         if i + 2 + value_bytes > buf.len() {
-            break;
+            return Err(TlvError::Truncated {
+                at: i,
+                needed: 2 + value_bytes,
+            });
         }
 
-        // Extract value (4 bits from header or additional bytes)
         let value = if value_bytes == 0 {
-            u32::from(b1 & 0x0F)
+            u64::from(nibble)
         } else {
-            let mut v = 0;
-            for j in 0..value_bytes {
-                v = (v << 8) | u32::from(buf[i + 2 + j]);
-            }
-            v
+            buf[i + 2..i + 2 + value_bytes]
+                .iter()
+                .fold(0, |v, b| (v << 8) | u64::from(*b))
         };
 
-        result.push(Tlv { t: tag, v: value });
+        result.push(TlvExt { t: tag, v: value });
         i += 2 + value_bytes;
     }
 
-    result
+    Ok(result)
 }
 
-pub fn build_tlv(elements: &[Tlv]) -> Vec<u8> {
+#[cfg(test)]
+pub fn build_tlv_ext(elements: &[TlvExt]) -> Result<Vec<u8>, TlvError> {
     let mut out = Vec::new();
 
     for el in elements {
-        let t0 = ((el.t >> 2) & 0xFF) as u8;
-        out.push(t0);
+        if el.t > MAX_TAG {
+            return Err(TlvError::TagTooLarge(el.t));
+        }
 
+        out.push(((el.t >> 2) & 0xFF) as u8);
         let tl = ((el.t & 3) << 6) as u8;
 
-        if el.v < 0x10 {
-            out.push(tl | el.v as u8);
-        } else if el.v < 0x100 {
-            out.push(tl | 0x10);
-            out.push(el.v as u8);
-        } else if el.v < 0x10000 {
-            out.push(tl | 0x20);
-            out.push((el.v >> 8) as u8);
-            out.push((el.v & 0xFF) as u8);
-        } else {
-            out.push(tl | 0x30);
-            out.push((el.v >> 16) as u8);
-            out.push((el.v >> 8) as u8);
-            out.push((el.v & 0xFF) as u8);
+        let value_bytes = 8 - el.v.leading_zeros() as usize / 8;
+        let (header, value_bytes) = match el.v {
+            0..=0x0F => (tl | el.v as u8, 0),
+            0x10..=0xFFFF => (tl | ((value_bytes as u8) << 4), value_bytes),
+            _ => {
+                let value_bytes = value_bytes.max(4);
+                (tl | 0x30 | (value_bytes - 4) as u8, value_bytes)
+            }
+        };
+
+        out.push(header);
+        out.extend_from_slice(&el.v.to_be_bytes()[8 - value_bytes..]);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    fn round_trip(elements: &[Tlv]) -> Vec<Tlv> {
+        parse_tlv(&build_tlv(elements).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_at_every_width_limit() {
+        // The last value of each width, then the first of the next one
+        let cases = [
+            (0x0, 0),
+            (0xF, 0),
+            (0x10, 1),
+            (0xFF, 1),
+            (0x100, 2),
+            (0xFFFF, 2),
+            (0x1_0000, 3),
+            (0xFF_FFFF, 3),
+        ];
+
+        for (v, width) in cases {
+            let parsed = round_trip(&[Tlv::new(0x1fe, v)]);
+
+            assert_eq!(parsed, [Tlv::new(0x1fe, v)]);
+            assert_eq!(parsed[0].width(), width);
+        }
+    }
+
+    #[test]
+    fn ext_round_trips_at_every_width_limit() {
+        for v in [
+            0x0,
+            0xF,
+            0x10,
+            0xFFFF,
+            0x1_0000,
+            0xFFFF_FFFF,
+            0x1_0000_0000,
+            u64::MAX,
+        ] {
+            let elements = [TlvExt { t: MAX_TAG, v }];
+            assert_eq!(
+                parse_tlv_ext(&build_tlv_ext(&elements).unwrap()).unwrap(),
+                elements
+            );
+        }
+    }
+
+    #[test]
+    fn values_past_three_bytes_are_rejected() {
+        assert!(build_tlv(&[Tlv::new(0x1fe, 0xFF_FFFF)]).is_ok());
+        assert_eq!(
+            build_tlv(&[Tlv::new(0x1fe, 0x100_0000)]),
+            Err(TlvError::ValueTooLarge(0x100_0000))
+        );
+        assert_eq!(
+            build_tlv(&[Tlv::new(0x1fe, u32::MAX)]),
+            Err(TlvError::ValueTooLarge(u32::MAX))
+        );
+    }
+
+    #[test]
+    fn rejected_elements_leave_the_buffer_untouched() {
+        let mut out = vec![0xAA];
+        let elements = [Tlv::new(0x1f7, 1), Tlv::new(0x1fe, 0x100_0000)];
+
+        assert!(build_tlv_into(&elements, &mut out).is_err());
+        assert_eq!(out, [0xAA]);
+    }
+
+    #[test]
+    fn ext_carries_values_past_three_bytes() {
+        // 0xFFFFFF goes out as 4 bytes in the extended encoding, see the module docs
+        let cases: [(u64, &[u8]); 3] = [
+            (0xFF_FFFF, &[0x7F, 0xB0, 0x00, 0xFF, 0xFF, 0xFF]),
+            (0x100_0000, &[0x7F, 0xB0, 0x01, 0x00, 0x00, 0x00]),
+            (u32::MAX as u64, &[0x7F, 0xB0, 0xFF, 0xFF, 0xFF, 0xFF]),
+        ];
+
+        for (v, bytes) in cases {
+            let elements = [TlvExt { t: 0x1fe, v }];
+
+            assert_eq!(build_tlv_ext(&elements).unwrap(), bytes);
+            assert_eq!(parse_tlv_ext(bytes).unwrap(), elements);
+        }
+    }
+
+    // 0x1fd = 48 in one byte, 0x1f9 = 0 in the nibble, 0x1fe = 300 in two bytes
+    const FRAME: [u8; 9] = [0x7F, 0x50, 0x30, 0x7E, 0x40, 0x7F, 0xA0, 0x01, 0x2C];
+
+    #[test]
+    fn iter_yields_what_parse_tlv_collects() {
+        let items: Vec<Tlv> = TlvIter::new(&FRAME).map(Result::unwrap).collect();
+
+        assert_eq!(items, parse_tlv(&FRAME).unwrap());
+        assert_eq!(
+            items.iter().map(|tlv| (tlv.t, tlv.v)).collect::<Vec<_>>(),
+            [(0x1fd, 48), (0x1f9, 0), (0x1fe, 300)]
+        );
+        assert_eq!(TlvIter::new(&FRAME).validate(), Ok(()));
+    }
+
+    #[test]
+    fn iter_reports_where_a_truncated_element_starts() {
+        // A 2-byte value with only one byte left
+        let mut buf = FRAME.to_vec();
+        buf.extend_from_slice(&[0x7E, 0x60, 0x00]);
+
+        let mut iter = TlvIter::new(&buf);
+        assert_eq!(iter.by_ref().take(3).filter(Result::is_ok).count(), 3);
+        assert_eq!(
+            iter.next(),
+            Some(Err(TlvError::Truncated { at: 9, needed: 4 }))
+        );
+        // Fused after the error
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(
+            parse_tlv(&buf),
+            Err(TlvError::Truncated { at: 9, needed: 4 })
+        );
+        assert_eq!(
+            TlvIter::new(&buf[..10]).validate(),
+            Err(TlvError::Truncated { at: 9, needed: 2 })
+        );
+    }
+
+    #[test]
+    fn strict_iter_rejects_a_stray_nibble() {
+        // One value byte, but the nibble is 1
+        let buf = [0x7F, 0x51, 0x30];
+
+        assert!(TlvIter::new(&buf).validate().is_ok());
+        assert_eq!(
+            TlvIter::new(&buf).strict().next(),
+            Some(Err(TlvError::StrayNibble { at: 0, nibble: 1 }))
+        );
+        assert_eq!(TlvIter::new(&FRAME).strict().validate(), Ok(()));
+    }
+
+    #[test]
+    fn lossy_and_span_parsers_stop_at_a_truncated_element() {
+        let mut buf = FRAME.to_vec();
+        buf.extend_from_slice(&[0x7E, 0x60, 0x00]);
+
+        assert_eq!(parse_tlv_lossy(&buf), parse_tlv(&FRAME).unwrap());
+        let spans: Vec<_> = parse_tlv_spans(&buf)
+            .into_iter()
+            .map(|(tlv, span)| (tlv.t, span))
+            .collect();
+        assert_eq!(spans, [(0x1fd, 0..3), (0x1f9, 3..5), (0x1fe, 5..9)]);
+    }
+
+    #[test]
+    fn map_keeps_the_chosen_duplicate_in_first_position() {
+        let buf = build_tlv(&[Tlv::new(0x1f7, 1), Tlv::new(0x1fa, 4), Tlv::new(0x1f7, 3)]).unwrap();
+
+        let first = parse_tlv_map(&buf, Duplicates::FirstWins).unwrap();
+        assert_eq!(
+            first.into_iter().collect::<Vec<_>>(),
+            [(0x1f7, 1), (0x1fa, 4)]
+        );
+        let last = parse_tlv_map(&buf, Duplicates::LastWins).unwrap();
+        assert_eq!(
+            last.into_iter().collect::<Vec<_>>(),
+            [(0x1f7, 3), (0x1fa, 4)]
+        );
+    }
+
+    #[test]
+    fn signed_values_round_trip_in_the_narrowest_width() {
+        let cases = [
+            (0, 0),
+            (7, 0),
+            (-8, 0),
+            (-9, 1),
+            (127, 1),
+            (-128, 1),
+            (300, 2),
+            (-0x8000, 2),
+            (0x7F_FFFF, 3),
+            (-0x80_0000, 3),
+        ];
+
+        for (v, width) in cases {
+            let tlv = Tlv::from_i32(0x1fe, v).unwrap();
+            assert_eq!(tlv.width(), width, "{v}");
+
+            let parsed = round_trip(&[tlv]);
+            assert_eq!(parsed[0].as_i32(), v);
         }
+        assert_eq!(
+            Tlv::from_i32(0x1fe, 0x80_0000),
+            Err(TlvError::ValueTooLarge(0x80_0000))
+        );
+    }
+
+    #[test]
+    fn tags_past_ten_bits_are_rejected() {
+        assert_eq!(build_tlv(&[Tlv::new(MAX_TAG, 1)]).unwrap(), [0xFF, 0xC1]);
+        assert_eq!(
+            build_tlv(&[Tlv::new(0x400, 1)]),
+            Err(TlvError::TagTooLarge(0x400))
+        );
+        assert_eq!(
+            build_tlv_ext(&[TlvExt { t: 0x400, v: 1 }]),
+            Err(TlvError::TagTooLarge(0x400))
+        );
+    }
+
+    #[test]
+    fn builder_output_matches_build_tlv() {
+        let state = HashMap::from([(0x1fa, 4), (0x1fe, 48)]);
+
+        let built = TlvBuilder::new()
+            .add(0x1f7, 1)
+            .add_from_state(0x1fa, &state)
+            .add_from_state(0x1fe, &state)
+            .build()
+            .unwrap();
+        let expected = [Tlv::new(0x1f7, 1), Tlv::new(0x1fa, 4), Tlv::new(0x1fe, 48)];
+
+        assert_eq!(built, expected);
+        assert_eq!(build_tlv(&built).unwrap(), build_tlv(&expected).unwrap());
+    }
+
+    #[test]
+    fn builder_keeps_the_first_error() {
+        let state = HashMap::new();
+
+        let result = TlvBuilder::new()
+            .add(0x1f7, 1)
+            .add_from_state(0x1fa, &state)
+            .add(0x400, 1)
+            .build();
+        assert_eq!(result, Err(TlvError::MissingState(0x1fa)));
+
+        let result = TlvBuilder::new().add(0x1fe, 0x100_0000).build();
+        assert_eq!(result, Err(TlvError::ValueTooLarge(0x100_0000)));
+    }
+
+    /// A tag with a value of 0 to 3 significant bytes, 0 meaning the header nibble.
+    fn element() -> impl Strategy<Value = Tlv> {
+        (0..=MAX_TAG, 0..=3usize, any::<u32>())
+            .prop_map(|(t, width, v)| Tlv::new(t, v & mask(width)))
+    }
+
+    /// Like `element`, but padded to `width` bytes whatever the value needs.
+    fn wide_element() -> impl Strategy<Value = Tlv> {
+        (0..=MAX_TAG, 0..=3usize, any::<u32>()).prop_flat_map(|(t, width, v)| {
+            (width..=3).prop_map(move |bytes| Tlv::with_width(t, v & mask(width), bytes).unwrap())
+        })
+    }
+
+    /// A value of 0 to 8 significant bytes, 0 meaning the header nibble.
+    fn ext_element() -> impl Strategy<Value = TlvExt> {
+        (0..=MAX_TAG, 0..=8u32, any::<u64>()).prop_map(|(t, bytes, v)| TlvExt {
+            t,
+            v: match bytes {
+                0 => v & 0xF,
+                bytes => v >> (64 - 8 * bytes),
+            },
+        })
     }
 
-    out
+    proptest! {
+        #[test]
+        fn build_then_parse_round_trips(elements in vec(element(), 0..16)) {
+            prop_assert_eq!(round_trip(&elements), elements);
+        }
+
+        #[test]
+        fn padded_build_then_parse_round_trips(elements in vec(wide_element(), 0..16)) {
+            prop_assert_eq!(round_trip(&elements), elements);
+        }
+
+        #[test]
+        fn ext_build_then_parse_round_trips(elements in vec(ext_element(), 0..16)) {
+            let parsed = parse_tlv_ext(&build_tlv_ext(&elements).unwrap()).unwrap();
+            prop_assert_eq!(parsed, elements);
+        }
+    }
 }